@@ -0,0 +1,223 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{OwnedMutexGuard, oneshot};
+use crate::server::{ConnReader, StreamReader};
+
+/// Size of the pieces a lazily read body is pulled from the connection in.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Request body with a known length.
+/// Bodies received by the server are read lazily, bytes are only pulled from the connection when
+/// the body is awaited, so handlers that reject a request early never buffer its upload.
+/// Chunked bodies are exposed through `Request.stream` instead, a Body only holds one after
+/// [`Request::into_parts`](crate::Request::into_parts).
+pub struct Body {
+    kind: Kind,
+}
+
+enum Kind {
+    Empty,
+    Full(Vec<u8>),
+    Remote {
+        reader: OwnedMutexGuard<ConnReader>,
+        remaining: usize,
+        unread: Unread,
+        /// Fired on the first read, so the server can send `100 Continue` to a client waiting for it.
+        on_read: Option<oneshot::Sender<()>>,
+        /// Copy of the bytes read so far, sent once the body has been read to the end, see `tee`.
+        tee: Option<(Vec<u8>, oneshot::Sender<Vec<u8>>)>,
+    },
+    /// A chunked body taken out of `Request.stream`, its length isn't known up front.
+    Chunked(Box<StreamReader>),
+}
+
+impl Body {
+    /// Returns an empty body.
+    pub fn empty() -> Self {
+        Body { kind: Kind::Empty }
+    }
+
+    pub(crate) fn remote(reader: OwnedMutexGuard<ConnReader>, len: usize, unread: Unread) -> Self {
+        unread.set(len);
+        if len == 0 {
+            return Body::empty();
+        }
+        Body { kind: Kind::Remote { reader, remaining: len, unread, on_read: None, tee: None } }
+    }
+
+    pub(crate) fn chunked(stream: StreamReader) -> Self {
+        Body { kind: Kind::Chunked(Box::new(stream)) }
+    }
+
+    /// Returns the chunked stream this body holds, or the body itself if it isn't chunked.
+    pub(crate) fn into_stream(self) -> Result<StreamReader, Body> {
+        match self.kind {
+            Kind::Chunked(stream) => Ok(*stream),
+            kind => Err(Body { kind }),
+        }
+    }
+
+    /// Notifies `on_read` when the body is first read from the connection.
+    /// Returns false if the body isn't read from the connection.
+    pub(crate) fn notify_on_read(&mut self, notify: oneshot::Sender<()>) -> bool {
+        match &mut self.kind {
+            Kind::Remote { on_read, .. } => {
+                *on_read = Some(notify);
+                true
+            }
+            Kind::Chunked(stream) => {
+                stream.notify_on_read(notify);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keeps a copy of the body as it's read from the connection, the receiver gets it once the
+    /// body has been read to the end, or fails if the body is dropped before that.
+    /// Returns None if the body isn't read from the connection.
+    pub(crate) fn tee(&mut self) -> Option<oneshot::Receiver<Vec<u8>>> {
+        let Kind::Remote { remaining, tee, .. } = &mut self.kind else { return None };
+        let (tx, rx) = oneshot::channel();
+        *tee = Some((Vec::with_capacity(*remaining), tx));
+        Some(rx)
+    }
+
+    /// Returns the number of bytes not yet read from the body, 0 for a chunked body whose length isn't known.
+    pub fn len(&self) -> usize {
+        match &self.kind {
+            Kind::Empty | Kind::Chunked(_) => 0,
+            Kind::Full(data) => data.len(),
+            Kind::Remote { remaining, .. } => *remaining,
+        }
+    }
+
+    /// Returns true if there is nothing left to read.
+    pub fn is_empty(&self) -> bool {
+        !matches!(self.kind, Kind::Chunked(_)) && self.len() == 0
+    }
+
+    /// Returns the next piece of the body, or `None` once the body is exhausted.
+    pub async fn chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        self.chunk_within(usize::MAX).await
+    }
+
+    /// Like `chunk`, but fails with `InvalidData` before reading a chunk of a chunked body whose
+    /// declared size is over `budget`.
+    async fn chunk_within(&mut self, budget: usize) -> std::io::Result<Option<Vec<u8>>> {
+        match &mut self.kind {
+            Kind::Empty => Ok(None),
+            Kind::Full(data) => {
+                let data = std::mem::take(data);
+                self.kind = Kind::Empty;
+                Ok(Some(data))
+            }
+            Kind::Remote { reader, remaining, unread, on_read, tee } => {
+                if let Some(notify) = on_read.take() {
+                    let _ = notify.send(());
+                }
+                let mut buf = vec![0u8; (*remaining).min(CHUNK_SIZE)];
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Body truncated"));
+                }
+                buf.truncate(n);
+                *remaining -= n;
+                unread.set(*remaining);
+                if let Some((copy, _)) = tee {
+                    copy.extend_from_slice(&buf);
+                }
+                if *remaining == 0 {
+                    if let Some((copy, done)) = tee.take() {
+                        let _ = done.send(copy);
+                    }
+                    self.kind = Kind::Empty;
+                }
+                Ok(Some(buf))
+            }
+            Kind::Chunked(stream) => {
+                let chunk = stream.next_chunk_within(budget).await?;
+                if chunk.is_none() {
+                    self.kind = Kind::Empty;
+                }
+                Ok(chunk)
+            }
+        }
+    }
+
+    /// Reads the whole body into memory.
+    /// Returns an `InvalidData` error without reading anything if the body is larger than `limit` bytes,
+    /// or as soon as a chunk of a chunked body would take it over, before reading that chunk.
+    pub async fn bytes(&mut self, limit: usize) -> std::io::Result<Vec<u8>> {
+        if self.len() > limit {
+            return Err(Error::new(ErrorKind::InvalidData, "Body too large"));
+        }
+        let mut body = Vec::with_capacity(self.len());
+        while let Some(chunk) = self.chunk_within(limit - body.len()).await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Returns a copy of the body if it is already in memory, otherwise an empty body.
+    pub(crate) fn clone_buffered(&self) -> Body {
+        match &self.kind {
+            Kind::Full(data) => Body { kind: Kind::Full(data.clone()) },
+            _ => Body::empty(),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::empty()
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(data: Vec<u8>) -> Self {
+        Body { kind: Kind::Full(data) }
+    }
+}
+
+impl From<&str> for Body {
+    fn from(data: &str) -> Self {
+        Body::from(data.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(data: String) -> Self {
+        Body::from(data.into_bytes())
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            Kind::Full(data) => f.debug_tuple("Body").field(data).finish(),
+            Kind::Chunked(_) => f.debug_struct("Body").field("chunked", &true).finish(),
+            _ => f.debug_struct("Body").field("remaining", &self.len()).finish(),
+        }
+    }
+}
+
+/// How much of a request body is still on the connection, kept up to date by the body as it's
+/// read so the server can tell what's left once the handler is done with it.
+#[derive(Clone, Default)]
+pub(crate) struct Unread(Arc<AtomicUsize>);
+
+impl Unread {
+    /// Left of a chunked body that hasn't reached its last chunk.
+    pub(crate) const UNKNOWN: usize = usize::MAX;
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set(&self, n: usize) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+}
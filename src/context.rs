@@ -0,0 +1,117 @@
+//! Structured per-request logging, see [`Server::on_request_log`](crate::Server::on_request_log).
+
+use crate::{Method, Request, StatusCode};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Key-value pairs describing a request, collected by middleware and handlers and logged once
+/// with its response. Carried in `Request.extensions`, clones share the same pairs.
+///
+/// # Example:
+/// ```
+/// use zep::{Handler, Request, RequestContext, Response};
+///
+/// async fn auth(mut req: Request, next: Handler) -> Response {
+///     RequestContext::of(&mut req).insert("user", 42);
+///     next(req).await
+/// }
+///
+/// async fn search(mut req: Request) -> Response {
+///     RequestContext::of(&mut req).insert("results", 7);
+///     Response::ok("...")
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct RequestContext {
+    fields: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl RequestContext {
+    /// Returns an empty context.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the context of `req`, adding an empty one if it has none yet.
+    pub fn of(req: &mut Request) -> RequestContext {
+        if let Some(context) = req.extensions.get::<RequestContext>() {
+            return context.clone();
+        }
+        let context = RequestContext::new();
+        req.extensions.insert(context.clone());
+        context
+    }
+
+    /// Sets `key` to `value`, replacing its previous value but keeping its position.
+    pub fn insert(&self, key: &str, value: impl fmt::Display) {
+        let Ok(mut fields) = self.fields.lock() else { return };
+        let value = value.to_string();
+        match fields.iter_mut().find(|(k, _)| k == key) {
+            Some((_, existing)) => *existing = value,
+            None => fields.push((key.to_string(), value)),
+        }
+    }
+
+    /// Returns the value of `key`.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let fields = self.fields.lock().ok()?;
+        fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    }
+
+    /// Returns a copy of the pairs, in the order they were first set.
+    pub fn fields(&self) -> Vec<(String, String)> {
+        self.fields.lock().map(|fields| fields.clone()).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for RequestContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.fields()).finish()
+    }
+}
+
+/// A finished request, passed to the callback set with [`Server::on_request_log`](crate::Server::on_request_log).
+/// Displays as a logfmt line: `id=1 method=GET path=/ status=200 duration_ms=3 user=42`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RequestLog {
+    pub method: Method,
+    pub path: String,
+    pub status: StatusCode,
+    /// How long the handler took, including its middleware.
+    pub duration: Duration,
+    pub remote_addr: SocketAddr,
+    /// See [`Request.id`](Request::id).
+    pub id: u64,
+    /// What the request's [`RequestContext`] held when the response was ready.
+    pub fields: Vec<(String, String)>,
+}
+
+impl fmt::Display for RequestLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} method={} path={} status={} duration_ms={} remote_addr={}",
+            self.id,
+            self.method,
+            logfmt_value(&self.path),
+            self.status.as_u16(),
+            self.duration.as_millis(),
+            self.remote_addr,
+        )?;
+        for (key, value) in &self.fields {
+            write!(f, " {}={}", key, logfmt_value(value))?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes `value` if it's empty or contains spaces, quotes or `=`.
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty() && !value.contains([' ', '"', '=']) {
+        return value.to_string();
+    }
+    format!("{:?}", value)
+}
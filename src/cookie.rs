@@ -0,0 +1,240 @@
+//! Cookies, and a keyed jar to sign or encrypt them so clients can't tamper with their values.
+//! The jar needs the `signed-cookies` feature.
+
+#[cfg(feature = "signed-cookies")]
+use crate::crypto::{base64url_decode, base64url_encode, constant_time_eq, hmac_sha256, open, random_bytes, seal};
+#[cfg(feature = "signed-cookies")]
+use crate::Request;
+use std::fmt;
+
+/// Value of the `SameSite` cookie attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to send with [`Response::cookie`](crate::Response::cookie).
+/// Displays as the value of a `Set-Cookie` header.
+///
+/// # Example:
+/// ```
+/// use zep::{Response, cookie::{Cookie, SameSite}};
+///
+/// let resp = Response::ok("welcome")
+///     .cookie(Cookie::new("theme", "dark").path("/").max_age(86400).same_site(SameSite::Lax));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Returns a session cookie with no attributes set.
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Returns a cookie that makes the client delete the cookie called `name`.
+    /// The path and domain must match the ones the cookie was set with.
+    pub fn removal(name: &str) -> Self {
+        Cookie::new(name, "").max_age(0)
+    }
+
+    /// Sets the `Path` attribute.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Sets the `Domain` attribute.
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Sets the `Max-Age` attribute in seconds.
+    pub fn max_age(mut self, secs: i64) -> Self {
+        self.max_age = Some(secs);
+        self
+    }
+
+    /// Sets the `Secure` attribute.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Sets the `HttpOnly` attribute.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Sets the `SameSite` attribute. `SameSite::None` also requires `Secure` in browsers.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Control characters and separators would let a value inject attributes or headers.
+        let clean = |s: &str| -> String {
+            s.chars().filter(|c| !c.is_ascii_control() && *c != ';').collect()
+        };
+        write!(f, "{}={}", clean(&self.name).replace('=', ""), clean(&self.value))?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", clean(path))?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", clean(domain))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax"),
+            Some(SameSite::None) => f.write_str("; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Splits a `Cookie` request header into name/value pairs.
+pub(crate) fn parse_cookies(header: &str) -> impl Iterator<Item = (&str, &str)> {
+    header.split(';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        Some((name.trim(), value))
+    })
+}
+
+#[cfg(feature = "signed-cookies")]
+#[derive(Clone)]
+struct Keys {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+#[cfg(feature = "signed-cookies")]
+impl Keys {
+    fn derive(master: &[u8]) -> Self {
+        Keys {
+            signing: hmac_sha256(master, b"zep cookie signing"),
+            encryption: hmac_sha256(master, b"zep cookie encryption"),
+        }
+    }
+
+    fn mac(&self, name: &str, value: &str) -> String {
+        base64url_encode(&hmac_sha256(&self.signing, format!("{}={}", name, value).as_bytes()))
+    }
+}
+
+/// Signs or encrypts cookie values with a secret key.
+/// Signed cookies can be read by the client but not changed, private cookies can neither be
+/// read nor changed. Keys can be rotated: cookies are always written with the current key,
+/// while cookies written with an older key registered with [`CookieJar::old_key`] are still accepted.
+///
+/// # Example:
+/// ```
+/// use zep::{Request, Response, cookie::{Cookie, CookieJar}};
+///
+/// let jar = CookieJar::new(b"a long random secret, at least 32 bytes");
+///
+/// fn login(jar: &CookieJar) -> Response {
+///     Response::ok("logged in").cookie(jar.signed(Cookie::new("user", "42").http_only(true)))
+/// }
+///
+/// fn whoami(jar: &CookieJar, req: &Request) -> Response {
+///     match jar.get_signed(req, "user") {
+///         Some(user) => Response::ok(user),
+///         None => Response::bad_request(),
+///     }
+/// }
+/// ```
+#[cfg(feature = "signed-cookies")]
+#[derive(Clone)]
+pub struct CookieJar {
+    keys: Vec<Keys>,
+}
+
+#[cfg(feature = "signed-cookies")]
+impl CookieJar {
+    /// Returns a jar using `key` for writing and reading cookies.
+    /// The key should be at least 32 random bytes and kept secret.
+    pub fn new(key: &[u8]) -> Self {
+        CookieJar { keys: vec![Keys::derive(key)] }
+    }
+
+    /// Also accepts cookies written with `key`, for rotating keys without logging everyone out.
+    pub fn old_key(mut self, key: &[u8]) -> Self {
+        self.keys.push(Keys::derive(key));
+        self
+    }
+
+    /// Returns `cookie` with its value signed.
+    pub fn signed(&self, mut cookie: Cookie) -> Cookie {
+        let mac = self.keys[0].mac(&cookie.name, &cookie.value);
+        cookie.value = format!("{}.{}", mac, cookie.value);
+        cookie
+    }
+
+    /// Returns the value of the signed cookie `name`, or `None` if it's missing or was tampered with.
+    pub fn get_signed(&self, req: &Request, name: &str) -> Option<String> {
+        let (mac, value) = req.cookie(name)?.split_once('.')?;
+        self.keys
+            .iter()
+            .any(|keys| constant_time_eq(keys.mac(name, value).as_bytes(), mac.as_bytes()))
+            .then(|| value.to_string())
+    }
+
+    /// Returns `cookie` with its value encrypted and authenticated.
+    /// Fails only if the operating system's random number generator is unavailable.
+    pub fn private(&self, mut cookie: Cookie) -> std::io::Result<Cookie> {
+        let mut nonce = [0u8; 12];
+        random_bytes(&mut nonce)?;
+        let sealed = seal(&self.keys[0].encryption, &nonce, cookie.name.as_bytes(), cookie.value.as_bytes())?;
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&sealed);
+        cookie.value = base64url_encode(&data);
+        Ok(cookie)
+    }
+
+    /// Returns the decrypted value of the private cookie `name`, or `None` if it's missing or was tampered with.
+    pub fn get_private(&self, req: &Request, name: &str) -> Option<String> {
+        let data = base64url_decode(req.cookie(name)?)?;
+        let (nonce, sealed) = data.split_at_checked(12)?;
+        let nonce: [u8; 12] = nonce.try_into().ok()?;
+        self.keys
+            .iter()
+            .find_map(|keys| open(&keys.encryption, &nonce, name.as_bytes(), sealed))
+            .and_then(|value| String::from_utf8(value).ok())
+    }
+}
@@ -0,0 +1,118 @@
+//! Small cryptographic building blocks (HMAC-SHA256, ChaCha20-Poly1305, base64url and RSA
+//! signature checks) for signed tokens and cookies. The primitives come from ring, which is only
+//! built with the `jwt` or `signed-cookies` feature. Nothing here is meant to produce long-term key material.
+
+#[cfg(feature = "signed-cookies")]
+use ring::aead;
+#[cfg(any(feature = "jwt", feature = "signed-cookies"))]
+use ring::hmac;
+
+/// Returns the HMAC-SHA256 (RFC 2104) of `data` under `key`.
+#[cfg(any(feature = "jwt", feature = "signed-cookies"))]
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data).as_ref());
+    out
+}
+
+/// Compares two byte strings in time that only depends on their length.
+#[cfg(any(feature = "jwt", feature = "signed-cookies"))]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encodes `data` as unpadded base64url (RFC 4648 section 5).
+pub(crate) fn base64url_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64URL[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Decodes unpadded base64url, trailing `=` padding is tolerated.
+#[cfg(any(feature = "jwt", feature = "signed-cookies", feature = "lambda"))]
+pub(crate) fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let v = BASE64URL.iter().position(|&b| b == c)? as u32;
+            n |= v << (18 - 6 * i);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+/// Encodes `data` as padded standard base64 (RFC 4648 section 4).
+#[cfg(feature = "lambda")]
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut out = base64url_encode(data).replace('-', "+").replace('_', "/");
+    while !out.len().is_multiple_of(4) {
+        out.push('=');
+    }
+    out
+}
+
+/// Decodes standard base64, with or without padding.
+#[cfg(feature = "lambda")]
+pub(crate) fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    if text.contains(['-', '_']) {
+        return None;
+    }
+    base64url_decode(&text.replace('+', "-").replace('/', "_"))
+}
+
+/// Fills `buf` from the operating system's random number generator.
+pub(crate) fn random_bytes(buf: &mut [u8]) -> std::io::Result<()> {
+    getrandom::getrandom(buf).map_err(std::io::Error::from)
+}
+
+#[cfg(feature = "signed-cookies")]
+fn aead_key(key: &[u8; 32]) -> aead::LessSafeKey {
+    let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).expect("ChaCha20-Poly1305 takes 32 byte keys");
+    aead::LessSafeKey::new(key)
+}
+
+/// Encrypts `plaintext` with ChaCha20-Poly1305 (RFC 8439), returns the ciphertext followed by the tag.
+#[cfg(feature = "signed-cookies")]
+pub(crate) fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut out = plaintext.to_vec();
+    aead_key(key)
+        .seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(*nonce), aead::Aad::from(aad), &mut out)
+        .map_err(|_| std::io::Error::other("Plaintext too large"))?;
+    Ok(out)
+}
+
+/// Decrypts the output of [`seal`], returns `None` if it was tampered with.
+#[cfg(feature = "signed-cookies")]
+pub(crate) fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    let mut out = sealed.to_vec();
+    let len = aead_key(key)
+        .open_in_place(aead::Nonce::assume_unique_for_key(*nonce), aead::Aad::from(aad), &mut out)
+        .ok()?
+        .len();
+    out.truncate(len);
+    Some(out)
+}
+
+/// Verifies an RSASSA-PKCS1-v1_5 SHA-256 signature (RS256) of `message`.
+/// `modulus` and `exponent` are the big-endian public key components, as found in a JWK.
+/// Keys shorter than 2048 bits are rejected, as RFC 7518 section 3.3 requires.
+#[cfg(feature = "jwt")]
+pub(crate) fn rsa_sha256_verify(modulus: &[u8], exponent: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    use ring::signature::{RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents};
+
+    let key = RsaPublicKeyComponents { n: modulus, e: exponent };
+    key.verify(&RSA_PKCS1_2048_8192_SHA256, message, signature).is_ok()
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// Errors reported by a [`Server`](crate::Server).
+/// `Bind`, `Accept` and `Startup` are fatal and returned from `run()`, the other variants are scoped to
+/// a single connection and only ever logged.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The listener could not be bound to the configured address.
+    Bind(std::io::Error),
+    /// Accepting a new connection failed.
+    Accept(std::io::Error),
+    /// The request could not be parsed.
+    Parse(std::io::Error),
+    /// Reading from or writing to an established connection failed.
+    Io(std::io::Error),
+    /// The TLS handshake with a client failed or timed out, for HTTPS and HTTP/3 connections.
+    #[cfg(feature = "rustls")]
+    Tls(std::io::Error),
+    /// A handler panicked, contains the panic message. The client received a 500 response.
+    HandlerPanic(String),
+    /// The hook set with [`Server::on_start`](crate::Server::on_start) failed, so the server didn't start.
+    Startup(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind(e) => write!(f, "failed to bind listener: {}", e),
+            Error::Accept(e) => write!(f, "failed to accept connection: {}", e),
+            Error::Parse(e) => write!(f, "failed to parse request: {}", e),
+            Error::Io(e) => write!(f, "connection error: {}", e),
+            #[cfg(feature = "rustls")]
+            Error::Tls(e) => write!(f, "TLS handshake failed: {}", e),
+            Error::HandlerPanic(msg) => write!(f, "handler panicked: {}", msg),
+            Error::Startup(e) => write!(f, "startup hook failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind(e) | Error::Accept(e) | Error::Parse(e) | Error::Io(e) => Some(e),
+            #[cfg(feature = "rustls")]
+            Error::Tls(e) => Some(e),
+            Error::HandlerPanic(_) => None,
+            Error::Startup(e) => Some(&**e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
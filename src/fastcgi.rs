@@ -0,0 +1,378 @@
+//! Serving a [`Router`] over FastCGI, for deployments behind nginx or Apache where the app
+//! can't bind a port of its own. Enabled with the `fastcgi` feature.
+//!
+//! The adapter plays the FastCGI responder role and handles one request per connection at a
+//! time, as nginx and Apache send them. Request bodies and streamed responses are buffered.
+//! Bodies over the largest limit set with [`Router::max_body`] or [`Router::default_max_body`],
+//! 16 MiB for routes without one, are answered with 413 Payload Too Large, and CGI variables
+//! over 64 KiB with 431 Request Header Fields Too Large.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use zep::{tokio, Router, Request, Response, Method};
+//!
+//! async fn root(_req: Request) -> Response {
+//!     Response::ok("Hello world!")
+//! }
+//!
+//! // nginx: `fastcgi_pass 127.0.0.1:9000;` and `include fastcgi_params;`
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut router = Router::new();
+//!     router.route(Method::GET, "/", root);
+//!     zep::fastcgi::serve("127.0.0.1:9000", router).await.unwrap();
+//! }
+//! ```
+
+use crate::{Body, Error, HeaderMap, Method, Request, Response, ResponseBody, Router, StatusCode, Version};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+const UNKNOWN_TYPE: u8 = 11;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+
+/// Protocol status of an END_REQUEST record.
+const REQUEST_COMPLETE: u8 = 0;
+const CANT_MPX_CONN: u8 = 1;
+const UNKNOWN_ROLE: u8 = 3;
+
+/// Largest content a single record can carry.
+const MAX_CONTENT: usize = 65_535;
+/// Largest CGI variables of a request, all names and values together.
+const MAX_PARAMS: usize = 64 * 1024;
+/// Largest buffered body for routes without a body size limit.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+/// A FastCGI record, without its padding.
+struct Record {
+    kind: u8,
+    id: u16,
+    content: Vec<u8>,
+}
+
+/// The request being received on a connection.
+struct Pending {
+    id: u16,
+    keep_conn: bool,
+    params: Vec<u8>,
+    stdin: Vec<u8>,
+    /// Set once the request was answered for going over a limit, its remaining records are dropped.
+    rejected: bool,
+}
+
+/// Accepts FastCGI connections from the web server on `addr` and serves them with `router`.
+/// Only returns on a bind or accept error.
+pub async fn serve(addr: &str, router: Router) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await.map_err(Error::Bind)?;
+    let router = Arc::new(router);
+    loop {
+        let (stream, _) = listener.accept().await.map_err(Error::Accept)?;
+        tokio::spawn(log_errors(serve_connection(stream, router.clone())));
+    }
+}
+
+/// Serves FastCGI on the listening socket the web server passed as file descriptor 0, as
+/// Apache's mod_fcgid and other process managers do (`FCGI_LISTENSOCK_FILENO`).
+/// Both TCP and Unix sockets are accepted. Only returns on an accept error, which happens
+/// right away if descriptor 0 isn't a listening socket.
+#[cfg(unix)]
+pub async fn run(router: Router) -> Result<(), Error> {
+    use std::os::fd::FromRawFd;
+
+    let router = Arc::new(router);
+    // SAFETY: a FastCGI application owns the listening socket it's given as descriptor 0.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(0) };
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true).map_err(Error::Bind)?;
+        let listener = TcpListener::from_std(tcp).map_err(Error::Bind)?;
+        loop {
+            let (stream, _) = listener.accept().await.map_err(Error::Accept)?;
+            tokio::spawn(log_errors(serve_connection(stream, router.clone())));
+        }
+    }
+    let unix = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(tcp));
+    unix.set_nonblocking(true).map_err(Error::Bind)?;
+    let listener = tokio::net::UnixListener::from_std(unix).map_err(Error::Bind)?;
+    loop {
+        let (stream, _) = listener.accept().await.map_err(Error::Accept)?;
+        tokio::spawn(log_errors(serve_connection(stream, router.clone())));
+    }
+}
+
+async fn log_errors(conn: impl Future<Output = io::Result<()>>) {
+    if let Err(e) = conn.await {
+        eprintln!("error, fastcgi conn, err: {:?}", e);
+    }
+}
+
+/// Serves the FastCGI requests of one connection from the web server, such as an accepted
+/// Unix socket, until the web server closes it or stops asking to keep it open.
+pub async fn serve_connection<S>(io: S, router: Arc<Router>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, mut write) = tokio::io::split(io);
+    let mut read = BufReader::new(read);
+    let mut pending: Option<Pending> = None;
+    let max_body = router.largest_max_body(MAX_BODY);
+
+    while let Some(record) = read_record(&mut read).await? {
+        match record.kind {
+            BEGIN_REQUEST if record.content.len() >= 3 => {
+                let role = u16::from_be_bytes([record.content[0], record.content[1]]);
+                // The web server may start the next request without finishing a rejected one.
+                if pending.as_ref().is_some_and(|p| !p.rejected) {
+                    end_request(&mut write, record.id, CANT_MPX_CONN).await?;
+                } else if role != RESPONDER {
+                    end_request(&mut write, record.id, UNKNOWN_ROLE).await?;
+                } else {
+                    let keep_conn = record.content[2] & KEEP_CONN != 0;
+                    let (params, stdin) = (Vec::new(), Vec::new());
+                    pending = Some(Pending { id: record.id, keep_conn, params, stdin, rejected: false });
+                }
+            }
+            ABORT_REQUEST if pending.as_ref().is_some_and(|p| p.id == record.id && !p.rejected) => {
+                let keep_conn = pending.take().is_some_and(|p| p.keep_conn);
+                end_request(&mut write, record.id, REQUEST_COMPLETE).await?;
+                if !keep_conn {
+                    return Ok(());
+                }
+            }
+            PARAMS => {
+                let Some(p) = pending.as_mut().filter(|p| p.id == record.id && !p.rejected) else { continue };
+                if p.params.len() + record.content.len() > MAX_PARAMS {
+                    reject(&mut write, &router, p, StatusCode::RequestHeaderFieldsTooLarge).await?;
+                    if !p.keep_conn {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                p.params.extend_from_slice(&record.content);
+            }
+            STDIN if pending.as_ref().is_some_and(|p| p.id == record.id) => {
+                if !record.content.is_empty() {
+                    let Some(p) = pending.as_mut().filter(|p| !p.rejected) else { continue };
+                    if p.stdin.len() + record.content.len() > max_body {
+                        reject(&mut write, &router, p, StatusCode::PayloadTooLarge).await?;
+                        if !p.keep_conn {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    p.stdin.extend_from_slice(&record.content);
+                    continue;
+                }
+                // An empty STDIN record ends the request.
+                let Some(p) = pending.take().filter(|p| !p.rejected) else { continue };
+                let req = request_from_params(&p.params, p.stdin)
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Malformed FastCGI params"))?;
+                let resp = respond(&router, req).await;
+                write_response(&mut write, p.id, resp).await?;
+                end_request(&mut write, p.id, REQUEST_COMPLETE).await?;
+                if !p.keep_conn {
+                    return Ok(());
+                }
+            }
+            GET_VALUES if record.id == 0 => {
+                let mut result = Vec::new();
+                for (name, _) in parse_pairs(&record.content).unwrap_or_default() {
+                    // Requests on one connection are handled one at a time.
+                    if name == b"FCGI_MPXS_CONNS" {
+                        encode_pair(&mut result, name, b"0");
+                    }
+                }
+                write_record(&mut write, GET_VALUES_RESULT, 0, &result).await?;
+            }
+            kind if record.id == 0 => {
+                write_record(&mut write, UNKNOWN_TYPE, 0, &[kind, 0, 0, 0, 0, 0, 0, 0]).await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Runs the router on `req`, answering a panicking handler with the router's 500 response.
+async fn respond(router: &Arc<Router>, req: Request) -> Response {
+    let parts = req.clone_parts();
+    let handler_router = router.clone();
+    match tokio::spawn(async move { handler_router.handle_request(req).await }).await {
+        Ok(resp) => resp,
+        Err(_) => router.error_response(StatusCode::InternalServerError, parts).await,
+    }
+}
+
+/// Answers a request that went over a limit with the router's `status` response before its
+/// records are all in, and drops what was buffered of it.
+async fn reject<W: AsyncWrite + Unpin>(write: &mut W, router: &Router, p: &mut Pending, status: StatusCode) -> io::Result<()> {
+    p.rejected = true;
+    p.params = Vec::new();
+    p.stdin = Vec::new();
+    let resp = router.error_response(status, Request::default()).await;
+    write_response(write, p.id, resp).await?;
+    end_request(write, p.id, REQUEST_COMPLETE).await
+}
+
+/// Reads the next record, or returns `None` if the web server closed the connection.
+async fn read_record<R: AsyncRead + Unpin>(read: &mut R) -> io::Result<Option<Record>> {
+    let mut header = [0u8; 8];
+    match read.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if header[0] != 1 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Unsupported FastCGI version"));
+    }
+    let id = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0u8; len + header[6] as usize];
+    read.read_exact(&mut content).await?;
+    content.truncate(len);
+    Ok(Some(Record { kind: header[1], id, content }))
+}
+
+async fn write_record<W: AsyncWrite + Unpin>(write: &mut W, kind: u8, id: u16, content: &[u8]) -> io::Result<()> {
+    let [id_hi, id_lo] = id.to_be_bytes();
+    let [len_hi, len_lo] = (content.len() as u16).to_be_bytes();
+    write.write_all(&[1, kind, id_hi, id_lo, len_hi, len_lo, 0, 0]).await?;
+    write.write_all(content).await
+}
+
+async fn end_request<W: AsyncWrite + Unpin>(write: &mut W, id: u16, status: u8) -> io::Result<()> {
+    write_record(write, END_REQUEST, id, &[0, 0, 0, 0, status, 0, 0, 0]).await?;
+    write.flush().await
+}
+
+/// Writes `resp` as a CGI response on STDOUT, followed by the empty record ending the stream.
+async fn write_response<W: AsyncWrite + Unpin>(write: &mut W, id: u16, resp: Response) -> io::Result<()> {
+    let (head, body) = resp.into_parts();
+    let body = match body {
+        ResponseBody::Empty => Vec::new(),
+        ResponseBody::Bytes(body) => body,
+        ResponseBody::Stream(stream) => stream.read_to_end().await?,
+    };
+    let mut out = format!("Status: {}\r\n", head.status_code).into_bytes();
+    for (key, value) in &head.headers {
+        // The web server frames the body itself.
+        if key.eq_ignore_ascii_case("transfer-encoding") || key.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        // Repeated headers are stored newline separated, see `Response::append_header`.
+        for value in value.split('\n') {
+            out.extend(format!("{}: {}\r\n", key, value.trim_end_matches('\r')).as_bytes());
+        }
+    }
+    out.extend(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+    out.extend(body);
+    for chunk in out.chunks(MAX_CONTENT) {
+        write_record(write, STDOUT, id, chunk).await?;
+    }
+    write_record(write, STDOUT, id, &[]).await
+}
+
+/// Decodes FastCGI name-value pairs, returns `None` if they're truncated.
+fn parse_pairs(mut data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    fn length(data: &mut &[u8]) -> Option<usize> {
+        let first = *data.first()?;
+        if first < 0x80 {
+            *data = &data[1..];
+            return Some(first as usize);
+        }
+        let bytes: [u8; 4] = data.get(..4)?.try_into().ok()?;
+        *data = &data[4..];
+        Some((u32::from_be_bytes(bytes) & 0x7fff_ffff) as usize)
+    }
+    let mut pairs = Vec::new();
+    while !data.is_empty() {
+        let name_len = length(&mut data)?;
+        let value_len = length(&mut data)?;
+        let name = data.get(..name_len)?;
+        let value = data.get(name_len..name_len + value_len)?;
+        data = &data[name_len + value_len..];
+        pairs.push((name, value));
+    }
+    Some(pairs)
+}
+
+fn encode_pair(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    for len in [name.len(), value.len()] {
+        match len {
+            0..0x80 => out.push(len as u8),
+            _ => out.extend((len as u32 | 0x8000_0000).to_be_bytes()),
+        }
+    }
+    out.extend_from_slice(name);
+    out.extend_from_slice(value);
+}
+
+/// Builds a Request from the CGI variables the web server sent and the request body.
+fn request_from_params(params: &[u8], body: Vec<u8>) -> Option<Request> {
+    let mut req = Request { body: Body::from(body), ..Default::default() };
+    let mut query = None;
+    let mut remote_ip = None;
+    let mut remote_port = 0;
+    let mut headers = HeaderMap::new();
+    for (name, value) in parse_pairs(params)? {
+        let (Ok(name), Ok(value)) = (std::str::from_utf8(name), std::str::from_utf8(value)) else {
+            continue;
+        };
+        match name {
+            "REQUEST_METHOD" => req.method = Method::from(value),
+            "REQUEST_URI" => req.path = value.to_string(),
+            "QUERY_STRING" => query = Some(value),
+            "SERVER_PROTOCOL" => req.version = Version::from(value),
+            "REMOTE_ADDR" => remote_ip = value.parse::<IpAddr>().ok(),
+            "REMOTE_PORT" => remote_port = value.parse().unwrap_or(0),
+            "CONTENT_TYPE" if !value.is_empty() => {
+                headers.insert("Content-Type".to_string(), value.to_string());
+            }
+            "CONTENT_LENGTH" if !value.is_empty() => {
+                headers.insert("Content-Length".to_string(), value.to_string());
+            }
+            _ => {
+                if let Some(header) = name.strip_prefix("HTTP_") {
+                    headers.insert(header_name(header), value.to_string());
+                }
+            }
+        }
+    }
+    // Web servers that don't send REQUEST_URI still send the query string on its own.
+    if req.path.is_empty() {
+        req.path = "/".to_string();
+        if let Some(query) = query.filter(|q| !q.is_empty()) {
+            req.path = format!("/?{}", query);
+        }
+    }
+    req.headers = headers;
+    req.remote_addr = SocketAddr::new(remote_ip.unwrap_or(IpAddr::from([0, 0, 0, 0])), remote_port);
+    Some(req)
+}
+
+/// Turns a CGI variable suffix such as `USER_AGENT` back into a header name, `User-Agent`.
+fn header_name(cgi: &str) -> String {
+    cgi.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
@@ -0,0 +1,1189 @@
+//! HTTP/2 (RFC 9113), spoken on TLS connections whose client asks for it with ALPN, see
+//! [`Server::new_tls`](crate::Server::new_tls).
+//!
+//! Each stream is answered like a request on its own HTTP/1.1 connection: its head goes through
+//! the same checks, with header names capitalized the way HTTP/1.1 clients send them, and the
+//! handler runs with the same hooks. Server push and stream priorities aren't supported.
+
+pub(crate) mod hpack;
+
+use crate::body::{Body, Unread};
+use crate::proto::ParseError;
+use crate::route::Router;
+use crate::server::{self, Config, ConnRead, ConnReader, ConnWrite, StreamPart, StreamReader};
+use crate::tls::PeerCertificate;
+use crate::types::{HeaderMap, Method, Request, RequestHead, Response, Version};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::sync::{Mutex, mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// What a client sends before its first frame.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+// Settings.
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Largest frame payload either side may send until told otherwise, the server never allows more.
+const MAX_FRAME_SIZE: usize = 16_384;
+
+/// Flow-control window each side starts with, for the connection and for every stream.
+const DEFAULT_WINDOW: i64 = 65_535;
+
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Streams a client may have open at once.
+const MAX_STREAMS: usize = 100;
+
+/// Most bytes of decoded header fields per request, counted the way SETTINGS_MAX_HEADER_LIST_SIZE
+/// counts them. Matches the limit on HTTP/1.1 heads.
+const MAX_HEADER_LIST_SIZE: usize = 16_384;
+
+/// Most bytes of a header block still split over CONTINUATION frames.
+const MAX_HEADER_BLOCK: usize = 4 * MAX_HEADER_LIST_SIZE;
+
+/// Most items queued for the writer before the client's frames are refused with ENHANCE_YOUR_CALM.
+/// Responses queue a few items per stream, a client that floods the connection with frames that
+/// need an answer, such as PING or SETTINGS, while not reading the answers goes past it.
+const MAX_QUEUED: usize = 1024;
+
+/// Serves an HTTP/2 connection until the client closes it or `closing` turns true, after which the
+/// streams already open are finished and the client is told to go away.
+/// `id` is stamped on the first request, later ones get their own.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve_connection(
+    read: ConnRead,
+    write: ConnWrite,
+    remote_addr: SocketAddr,
+    id: u64,
+    peer_certificate: Option<PeerCertificate>,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    closing: watch::Receiver<bool>,
+) -> Result<(), crate::Error> {
+    let mut read = BufReader::new(read);
+    let mut preface = [0u8; PREFACE.len()];
+    let reading = read.read_exact(&mut preface);
+    match config.read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, reading)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Idle connection timed out"))??,
+        None => reading.await?,
+    };
+    if preface != PREFACE {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid HTTP/2 preface").into());
+    }
+
+    let (out, queue) = mpsc::unbounded_channel();
+    let out = Outbox { out, queued: Arc::new(AtomicUsize::new(0)) };
+    let writer = tokio::spawn(write_frames(write, queue, out.queued.clone()));
+    let (frames, received) = mpsc::channel(16);
+    let reader = tokio::spawn(read_frames(read, frames));
+
+    let mut settings = Vec::new();
+    for (setting, value) in [
+        (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+        (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE as u32),
+    ] {
+        settings.extend(setting.to_be_bytes());
+        settings.extend(value.to_be_bytes());
+    }
+    let _ = out.send(Out::Frame(frame(SETTINGS, 0, 0, &settings)));
+
+    let conn = Conn {
+        remote_addr,
+        first_id: Some(id),
+        peer_certificate,
+        router,
+        config,
+        out,
+        decoder: hpack::Decoder::new(),
+        streams: HashMap::new(),
+        tasks: JoinSet::new(),
+        last_stream: 0,
+        opened: 0,
+        continuation: None,
+        going_away: false,
+    };
+    let result = conn.run(received, writer, closing).await;
+    reader.abort();
+    result
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn window_update(stream: u32, increment: u32) -> Vec<u8> {
+    frame(WINDOW_UPDATE, 0, stream, &increment.to_be_bytes())
+}
+
+/// Returns the payload of a frame without its padding, `None` if the padding doesn't fit in it.
+fn unpadded(frame: &Frame) -> Option<&[u8]> {
+    if frame.flags & PADDED == 0 {
+        return Some(&frame.payload);
+    }
+    let (&padding, rest) = frame.payload.split_first()?;
+    rest.len().checked_sub(padding as usize).map(|len| &rest[..len])
+}
+
+/// Reads the next frame, `None` if the client closed the connection between frames.
+async fn read_frame(read: &mut ConnReader) -> std::io::Result<Option<Frame>> {
+    let mut header = [0u8; 9];
+    match read.read_exact(&mut header[..1]).await {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        read => read?,
+    };
+    read.read_exact(&mut header[1..]).await?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "Frame too large"));
+    }
+    let mut payload = vec![0u8; len];
+    read.read_exact(&mut payload).await?;
+    let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+    Ok(Some(Frame { kind: header[3], flags: header[4], stream, payload }))
+}
+
+/// Reads frames off the connection for its `Conn`, until the client closes it or breaks the framing.
+/// Reading in a task of its own keeps frames whole when the connection has other things to do.
+async fn read_frames(mut read: ConnReader, frames: mpsc::Sender<std::io::Result<Option<Frame>>>) {
+    loop {
+        let frame = read_frame(&mut read).await;
+        let last = !matches!(frame, Ok(Some(_)));
+        if frames.send(frame).await.is_err() || last {
+            return;
+        }
+    }
+}
+
+/// State of the connection, kept by the task reading it.
+struct Conn {
+    remote_addr: SocketAddr,
+    /// Request id for the first stream, which uses the id chosen for the connection.
+    first_id: Option<u64>,
+    peer_certificate: Option<PeerCertificate>,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    out: Outbox,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, StreamState>,
+    /// Tasks answering the open streams, each returns the id of its stream.
+    tasks: JoinSet<u32>,
+    /// Highest stream id the client opened.
+    last_stream: u32,
+    /// Number of streams opened, for `Server::max_requests_per_connection`.
+    opened: usize,
+    /// A header block waiting for the rest of its CONTINUATION frames.
+    continuation: Option<HeaderBlock>,
+    /// Whether GOAWAY was sent, after which new streams are refused.
+    going_away: bool,
+}
+
+/// A stream whose response isn't complete yet.
+struct StreamState {
+    /// Hands the request body over to the handler, `None` once the client finished sending.
+    body: Option<mpsc::UnboundedSender<Incoming>>,
+    /// How much more the client may send before the handler reads some.
+    window: Arc<AtomicI64>,
+    content_length: Option<usize>,
+    received: usize,
+}
+
+struct HeaderBlock {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+impl Conn {
+    async fn run(
+        mut self,
+        mut frames: mpsc::Receiver<std::io::Result<Option<Frame>>>,
+        mut writer: JoinHandle<std::io::Result<()>>,
+        mut closing: watch::Receiver<bool>,
+    ) -> Result<(), crate::Error> {
+        let mut reading = true;
+        let result = loop {
+            if (self.going_away || !reading) && self.tasks.is_empty() {
+                break Ok(());
+            }
+            // The read timeout becomes an idle timeout, streams waiting on their handler aren't idle.
+            let idle = self.config.read_timeout.filter(|_| self.tasks.is_empty());
+            tokio::select! {
+                frame = frames.recv(), if reading => match frame {
+                    Some(Ok(Some(frame))) => {
+                        if let Err(code) = self.handle(frame) {
+                            self.go_away(code);
+                            // The client isn't reading, waiting for the writer would hold the connection open.
+                            if code == ENHANCE_YOUR_CALM {
+                                writer.abort();
+                            }
+                            break Err(Error::new(ErrorKind::InvalidData, format!("HTTP/2 connection error {:#x}", code)).into());
+                        }
+                    }
+                    Some(Ok(None)) | None => {
+                        reading = false;
+                        for stream in self.streams.values_mut() {
+                            stream.body = None;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        if e.kind() == ErrorKind::InvalidData {
+                            self.go_away(FRAME_SIZE_ERROR);
+                        }
+                        break Err(e.into());
+                    }
+                },
+                Some(done) = self.tasks.join_next(), if !self.tasks.is_empty() => {
+                    if let Ok(id) = done {
+                        self.finished(id);
+                    }
+                }
+                Ok(()) = closing.changed(), if !self.going_away => {
+                    if *closing.borrow() {
+                        self.go_away(NO_ERROR);
+                    }
+                }
+                _ = tokio::time::sleep(idle.unwrap_or_default()), if idle.is_some() => {
+                    self.go_away(NO_ERROR);
+                    break Ok(());
+                }
+                written = &mut writer => {
+                    self.tasks.shutdown().await;
+                    return match written {
+                        Ok(written) => written.map_err(crate::Error::Io),
+                        Err(e) => Err(Error::other(e).into()),
+                    };
+                }
+            }
+        };
+        self.tasks.shutdown().await;
+        let _ = self.out.send(Out::Close);
+        match writer.await {
+            Ok(written) => result.and(written.map_err(crate::Error::Io)),
+            Err(e) => result.and(Err(Error::other(e).into())),
+        }
+    }
+
+    fn send(&self, out: Out) {
+        // The writer only stops after the connection failed, which the connection finds out by itself.
+        let _ = self.out.send(out);
+    }
+
+    fn go_away(&mut self, code: u32) {
+        let mut payload = self.last_stream.to_be_bytes().to_vec();
+        payload.extend(code.to_be_bytes());
+        self.send(Out::Frame(frame(GOAWAY, 0, 0, &payload)));
+        self.going_away = true;
+    }
+
+    /// Resets a stream, telling its task that the response is no longer wanted.
+    fn reset(&mut self, id: u32, code: u32) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.body = None;
+        }
+        self.send(Out::Reset { stream: id, code: Some(code) });
+    }
+
+    /// Handles a frame, returning the code of the connection error it causes if any.
+    fn handle(&mut self, frame: Frame) -> Result<(), u32> {
+        if self.out.queued.load(Ordering::Relaxed) > MAX_QUEUED {
+            return Err(ENHANCE_YOUR_CALM);
+        }
+        if let Some(pending) = &mut self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != pending.stream {
+                return Err(PROTOCOL_ERROR);
+            }
+            pending.block.extend_from_slice(&frame.payload);
+            if pending.block.len() > MAX_HEADER_BLOCK {
+                return Err(ENHANCE_YOUR_CALM);
+            }
+            if frame.flags & END_HEADERS != 0 {
+                let block = self.continuation.take().expect("continuation is pending");
+                return self.headers(block);
+            }
+            return Ok(());
+        }
+
+        match frame.kind {
+            DATA => self.data(frame),
+            HEADERS => {
+                if frame.stream == 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                let mut block = unpadded(&frame).ok_or(PROTOCOL_ERROR)?;
+                if frame.flags & PRIORITY_FLAG != 0 {
+                    block = block.get(5..).ok_or(FRAME_SIZE_ERROR)?;
+                }
+                let block = HeaderBlock {
+                    stream: frame.stream,
+                    end_stream: frame.flags & END_STREAM != 0,
+                    block: block.to_vec(),
+                };
+                if frame.flags & END_HEADERS == 0 {
+                    self.continuation = Some(block);
+                    return Ok(());
+                }
+                self.headers(block)
+            }
+            PRIORITY => {
+                if frame.stream == 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if frame.payload.len() != 5 {
+                    self.reset(frame.stream, FRAME_SIZE_ERROR);
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if frame.stream == 0 || frame.stream > self.last_stream {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if frame.payload.len() != 4 {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                if let Some(stream) = self.streams.get_mut(&frame.stream) {
+                    stream.body = None;
+                }
+                self.send(Out::Reset { stream: frame.stream, code: None });
+                Ok(())
+            }
+            SETTINGS => self.settings(frame),
+            PUSH_PROMISE | CONTINUATION => Err(PROTOCOL_ERROR),
+            PING => {
+                if frame.stream != 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if frame.payload.len() != 8 {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                if frame.flags & ACK == 0 {
+                    self.send(Out::Frame(crate::h2::frame(PING, ACK, 0, &frame.payload)));
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if frame.stream != 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                // The client opens no more streams, the ones it has are still answered.
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                let [a, b, c, d] = frame.payload[..] else { return Err(FRAME_SIZE_ERROR) };
+                let increment = u32::from_be_bytes([a, b, c, d]) & 0x7fff_ffff;
+                if increment == 0 {
+                    if frame.stream == 0 {
+                        return Err(PROTOCOL_ERROR);
+                    }
+                    self.reset(frame.stream, PROTOCOL_ERROR);
+                    return Ok(());
+                }
+                self.send(Out::Window { stream: frame.stream, increment });
+                Ok(())
+            }
+            // Unknown frame types must be ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.stream != 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        if frame.flags & ACK != 0 {
+            if !frame.payload.is_empty() {
+                return Err(FRAME_SIZE_ERROR);
+            }
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(FRAME_SIZE_ERROR);
+        }
+        let (mut initial_window, mut max_frame_size) = (None, None);
+        for setting in frame.payload.chunks(6) {
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(PROTOCOL_ERROR),
+                SETTINGS_INITIAL_WINDOW_SIZE if value as i64 > MAX_WINDOW => return Err(FLOW_CONTROL_ERROR),
+                SETTINGS_INITIAL_WINDOW_SIZE => initial_window = Some(value),
+                SETTINGS_MAX_FRAME_SIZE if !(16_384..=16_777_215).contains(&value) => return Err(PROTOCOL_ERROR),
+                SETTINGS_MAX_FRAME_SIZE => max_frame_size = Some(value),
+                // Push is never used and the encoder keeps no dynamic table, the rest is advisory.
+                _ => {}
+            }
+        }
+        // The writer applies the settings before it acknowledges them.
+        self.send(Out::Settings { initial_window, max_frame_size });
+        self.send(Out::Frame(crate::h2::frame(SETTINGS, ACK, 0, &[])));
+        Ok(())
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.stream == 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        if frame.stream > self.last_stream {
+            return Err(PROTOCOL_ERROR);
+        }
+        // The connection window is handed back right away, each stream's as its handler reads.
+        let len = frame.payload.len();
+        if len > 0 {
+            self.send(Out::Frame(window_update(0, len as u32)));
+        }
+        let data = unpadded(&frame).ok_or(PROTOCOL_ERROR)?;
+        let id = frame.stream;
+        // Frames for streams that are already closed may still be on the way, they're dropped.
+        let Some(stream) = self.streams.get_mut(&id) else { return Ok(()) };
+        let Some(body) = &stream.body else {
+            self.reset(id, STREAM_CLOSED);
+            return Ok(());
+        };
+        if stream.window.fetch_sub(len as i64, Ordering::Relaxed) < len as i64 {
+            self.reset(id, FLOW_CONTROL_ERROR);
+            return Ok(());
+        }
+        stream.received += data.len();
+        let end = frame.flags & END_STREAM != 0;
+        let length_ok = match stream.content_length {
+            Some(content_length) if end => stream.received == content_length,
+            Some(content_length) => stream.received <= content_length,
+            None => true,
+        };
+        if !length_ok {
+            self.reset(id, PROTOCOL_ERROR);
+            return Ok(());
+        }
+        // Padding never reaches the handler, nor do bodies it no longer reads.
+        let mut unused = len - data.len();
+        if data.is_empty() || body.send(Incoming::Data(data.to_vec())).is_err() {
+            unused = len;
+        }
+        if unused > 0 {
+            stream.window.fetch_add(unused as i64, Ordering::Relaxed);
+            let _ = self.out.send(Out::Frame(window_update(id, unused as u32)));
+        }
+        if end {
+            let _ = body.send(Incoming::End);
+            stream.body = None;
+        }
+        Ok(())
+    }
+
+    fn headers(&mut self, block: HeaderBlock) -> Result<(), u32> {
+        // Every block changes the decoder's state, including those of streams that are ignored.
+        let fields = self.decoder.decode(&block.block, MAX_HEADER_LIST_SIZE).map_err(|_| COMPRESSION_ERROR)?;
+        let id = block.stream;
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // Trailers, which end the request body. Handlers don't see them, like those of
+            // chunked HTTP/1.1 bodies.
+            let Some(body) = stream.body.take() else {
+                self.reset(id, STREAM_CLOSED);
+                return Ok(());
+            };
+            let complete = stream.content_length.is_none_or(|len| len == stream.received);
+            if !block.end_stream || !complete || !fields.as_deref().is_some_and(valid_trailers) {
+                self.reset(id, PROTOCOL_ERROR);
+                return Ok(());
+            }
+            let _ = body.send(Incoming::End);
+            return Ok(());
+        }
+        if id.is_multiple_of(2) {
+            return Err(PROTOCOL_ERROR);
+        }
+        // Streams are never reused, the client is late for one that was closed.
+        if id <= self.last_stream {
+            return Ok(());
+        }
+        self.last_stream = id;
+        if self.going_away || self.streams.len() >= MAX_STREAMS {
+            self.send(Out::Reset { stream: id, code: Some(REFUSED_STREAM) });
+            return Ok(());
+        }
+        self.open(id, fields, block.end_stream);
+        self.opened += 1;
+        // The client is told to go away right after the last stream it may open, which is still answered.
+        if self.config.max_requests_per_connection.is_some_and(|max| self.opened >= max) {
+            self.go_away(NO_ERROR);
+        }
+        Ok(())
+    }
+
+    /// Starts answering a new stream in a task of its own.
+    /// `fields` is `None` if the header block was over the size limit.
+    fn open(&mut self, id: u32, fields: Option<Vec<(Vec<u8>, Vec<u8>)>>, end_stream: bool) {
+        let window = Arc::new(AtomicI64::new(DEFAULT_WINDOW));
+        let mut stream = StreamState { body: None, window: window.clone(), content_length: None, received: 0 };
+        // A body nobody reads, after a malformed head, is still received so the response can be sent.
+        let mut incoming = None;
+        if !end_stream {
+            let (body, received) = mpsc::unbounded_channel();
+            stream.body = Some(body);
+            incoming = Some(received);
+        }
+        let parsed = fields.ok_or(ParseError::HeadersTooLarge).and_then(|fields| request_head(fields, Version::Http2, &self.config));
+        let parsed = parsed.and_then(|mut head| {
+            if end_stream && head.content_length.is_some_and(|len| len > 0) {
+                return Err(ParseError::Malformed("Body shorter than Content-Length"));
+            }
+            head.remote_addr = self.remote_addr;
+            head.received = std::time::Instant::now();
+            stream.content_length = head.content_length;
+            let Some(incoming) = incoming else { return Ok((head, Body::empty())) };
+            let reader = DataReader {
+                stream: id,
+                incoming,
+                window,
+                out: self.out.clone(),
+                chunked: head.content_length.is_none(),
+                pending: Vec::new(),
+                pos: 0,
+            };
+            let reader: ConnReader = BufReader::new(Box::new(reader));
+            let reader = Arc::new(Mutex::new(reader)).try_lock_owned().expect("a new lock is free");
+            let body = match head.content_length {
+                Some(len) => Body::remote(reader, len, Unread::default()),
+                None => Body::chunked(StreamReader::new(reader, Unread::default())),
+            };
+            Ok((head, body))
+        });
+        self.streams.insert(id, stream);
+        self.send(Out::Open(id));
+
+        let responder = Responder { id, out: self.out.clone() };
+        let req_id = self.first_id.take().unwrap_or_else(|| self.config.next_request_id());
+        let stream = StreamTask {
+            remote_addr: self.remote_addr,
+            req_id,
+            peer_certificate: self.peer_certificate.clone(),
+            router: self.router.clone(),
+            config: self.config.clone(),
+        };
+        self.tasks.spawn(async move {
+            let result = stream.respond(&responder, parsed).await;
+            server::count_error(&stream.config.stats, &result);
+            if let Err(e) = result {
+                eprintln!("error, request: {}, conn: {}, err: {:?}", req_id, stream.remote_addr, e);
+            }
+            responder.id
+        });
+    }
+
+    /// Forgets a stream once its response is complete, and asks the client to stop sending a
+    /// request body the handler no longer reads.
+    fn finished(&mut self, id: u32) {
+        if self.streams.remove(&id).is_some_and(|stream| stream.body.is_some()) {
+            self.send(Out::Reset { stream: id, code: Some(NO_ERROR) });
+        }
+    }
+}
+
+/// Checks the fields of a request's header block and parses them like an HTTP/1.1 head, so the
+/// request goes through the same checks against the server's options. Also used for HTTP/3,
+/// whose requests have the same fields.
+pub(crate) fn request_head(fields: Vec<(Vec<u8>, Vec<u8>)>, version: Version, config: &Config) -> Result<RequestHead, ParseError> {
+    let size: usize = fields.iter().map(|(name, value)| name.len() + value.len() + 32).sum();
+    if size > MAX_HEADER_LIST_SIZE {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers = String::new();
+    let mut cookies = Vec::new();
+    let mut regular = false;
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) else {
+            return Err(ParseError::Malformed("UTF-8 error"));
+        };
+        if !valid_value(&value) {
+            return Err(ParseError::Malformed("Invalid header value"));
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if regular {
+                return Err(ParseError::Malformed("Pseudo-header after regular header"));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err(ParseError::Malformed("Unknown pseudo-header")),
+            };
+            if slot.replace(value).is_some() {
+                return Err(ParseError::Malformed("Repeated pseudo-header"));
+            }
+            continue;
+        }
+        regular = true;
+        if !valid_name(&name) {
+            return Err(ParseError::Malformed("Invalid header name"));
+        }
+        match name.as_str() {
+            "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" => {
+                return Err(ParseError::Malformed("Connection-specific header"));
+            }
+            "te" if value != "trailers" => return Err(ParseError::Malformed("Connection-specific header")),
+            // Split up for better compression, HTTP/1.1 expects them on one line.
+            "cookie" => {
+                cookies.push(value);
+                continue;
+            }
+            // :authority takes its place.
+            "host" if authority.is_some() => continue,
+            _ => {}
+        }
+        headers += &format!("{}: {}\r\n", canonical_name(&name), value);
+    }
+
+    let Some(method) = method else { return Err(ParseError::Malformed("Missing method")) };
+    let target = if method == "CONNECT" {
+        if scheme.is_some() || path.is_some() {
+            return Err(ParseError::Malformed("Unexpected pseudo-header"));
+        }
+        authority.clone()
+    } else {
+        if scheme.is_none() {
+            return Err(ParseError::Malformed("Missing scheme"));
+        }
+        path
+    };
+    let Some(target) = target.filter(|target| !target.is_empty()) else {
+        return Err(ParseError::Malformed("Missing path"));
+    };
+    if method.contains(' ') || target.contains(' ') {
+        return Err(ParseError::Malformed("Invalid request line"));
+    }
+    let mut head = format!("{} {} {}\r\n", method, target, version);
+    if let Some(authority) = &authority {
+        head += &format!("Host: {}\r\n", authority);
+    }
+    if !cookies.is_empty() {
+        head += &format!("Cookie: {}\r\n", cookies.join("; "));
+    }
+    head += &headers;
+    head += "\r\n";
+    server::parse_head(head.as_bytes(), config)
+}
+
+/// Header names must be lowercase tokens in HTTP/2.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| server::is_token_byte(b) && !b.is_ascii_uppercase())
+}
+
+fn valid_value(value: &str) -> bool {
+    !value.contains(['\0', '\r', '\n']) && !value.starts_with([' ', '\t']) && !value.ends_with([' ', '\t'])
+}
+
+fn valid_trailers(fields: &[(Vec<u8>, Vec<u8>)]) -> bool {
+    fields.iter().all(|(name, value)| {
+        std::str::from_utf8(name).is_ok_and(valid_name) && std::str::from_utf8(value).is_ok_and(valid_value)
+    })
+}
+
+/// Capitalizes a header name the way HTTP/1.1 clients send it, `content-type` becomes `Content-Type`.
+fn canonical_name(name: &str) -> String {
+    let mut upper = true;
+    name.chars()
+        .map(|c| {
+            let c = if upper { c.to_ascii_uppercase() } else { c };
+            upper = c == '-';
+            c
+        })
+        .collect()
+}
+
+/// What the connection hands over to the body of a request.
+enum Incoming {
+    Data(Vec<u8>),
+    /// The client finished sending the body.
+    End,
+}
+
+/// Request body of a stream, read from the DATA frames the connection hands over. Reading it
+/// gives the client window to send more. Bodies without a Content-Length get chunked framing,
+/// so they're read with a `StreamReader` like chunked HTTP/1.1 bodies.
+struct DataReader {
+    stream: u32,
+    incoming: mpsc::UnboundedReceiver<Incoming>,
+    window: Arc<AtomicI64>,
+    out: Outbox,
+    chunked: bool,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for DataReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        while this.pos == this.pending.len() {
+            let incoming = match this.incoming.poll_recv(cx) {
+                Poll::Ready(incoming) => incoming,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending.clear();
+            this.pos = 0;
+            match incoming {
+                Some(Incoming::Data(data)) => {
+                    this.window.fetch_add(data.len() as i64, Ordering::Relaxed);
+                    let _ = this.out.send(Out::Frame(window_update(this.stream, data.len() as u32)));
+                    if this.chunked {
+                        this.pending.extend(format!("{:X}\r\n", data.len()).as_bytes());
+                        this.pending.extend(data);
+                        this.pending.extend(b"\r\n");
+                    } else {
+                        this.pending = data;
+                    }
+                }
+                Some(Incoming::End) if this.chunked => this.pending.extend(b"0\r\n\r\n"),
+                // A body cut short by a reset fails to parse, or comes up short of its length.
+                Some(Incoming::End) | None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = buf.remaining().min(this.pending.len() - this.pos);
+        buf.put_slice(&this.pending[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Everything needed to answer a stream, moved into its task.
+struct StreamTask {
+    remote_addr: SocketAddr,
+    req_id: u64,
+    peer_certificate: Option<PeerCertificate>,
+    router: Arc<Router>,
+    config: Arc<Config>,
+}
+
+impl StreamTask {
+    /// Runs the router on the request and sends its response, mirroring `respond` in server.rs.
+    async fn respond(
+        &self,
+        responder: &Responder,
+        parsed: Result<(RequestHead, Body), ParseError>,
+    ) -> Result<(), crate::Error> {
+        let config = &self.config;
+        let parsed = match parsed {
+            Ok((head, body)) => server::apply_unexpected_body_policy(&head.method, body, config)
+                .await
+                .map(|body| (head, body)),
+            Err(e) => Err(e.into()),
+        };
+        let (head, body) = match parsed {
+            Ok(parts) => parts,
+            Err(e) => {
+                match server::rejection_status(&e) {
+                    Some(status) => {
+                        let req = Request { remote_addr: self.remote_addr, version: Version::Http2, ..Default::default() };
+                        let resp = self.router.error_response(status, req).await;
+                        let _ = send_response(responder, resp, false, config).await;
+                    }
+                    None => responder.reset(PROTOCOL_ERROR),
+                }
+                return Err(crate::Error::Parse(e));
+            }
+        };
+        config.stats.request();
+        let mut req = Request::from_parts(head, body);
+        req.id = self.req_id;
+        if let Some(cert) = &self.peer_certificate {
+            req.extensions.insert(cert.clone());
+        }
+
+        let head_only = req.method == Method::HEAD;
+        let send_continue = async { responder.headers(&[(":status".to_string(), "100".to_string())], false) };
+        let (resp, panic, _) = server::dispatch(req, &self.router, config, send_continue).await?;
+        if resp.upgrade.is_some() {
+            // Upgrades, such as WebSocket, take over an HTTP/1.1 connection.
+            responder.reset(HTTP_1_1_REQUIRED);
+        } else {
+            let streaming = resp.stream.is_some() && !head_only;
+            match send_response(responder, resp, head_only, config).await {
+                Ok(sent) => config.stats.streamed(sent),
+                Err(e) => {
+                    server::write_failed(config, self.remote_addr, self.req_id, streaming, &e);
+                    if server::is_client_gone(&e) {
+                        config.stats.client_close();
+                    } else {
+                        responder.reset(INTERNAL_ERROR);
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        if let Some(msg) = panic {
+            return Err(crate::Error::HandlerPanic(msg));
+        }
+        Ok(())
+    }
+}
+
+/// Sends the response as a HEADERS frame followed by its body in DATA frames, and the trailers of
+/// a stream in a last HEADERS frame. Returns the number of streamed bytes.
+async fn send_response(responder: &Responder, mut resp: Response, head_only: bool, config: &Config) -> std::io::Result<u64> {
+    let mut fields = vec![(":status".to_string(), resp.status_code.as_u16().to_string())];
+    fields.extend(response_fields(&resp));
+    // Responses to HEAD keep their Content-Length but never carry a body.
+    let body = resp.body.take().filter(|body| !body.is_empty() && !head_only);
+    let stream = resp.stream.take().filter(|_| !head_only);
+    if body.is_none() && stream.is_none() {
+        responder.headers(&fields, true)?;
+        return Ok(0);
+    }
+    responder.headers(&fields, false)?;
+    if let Some(body) = body {
+        responder.data(body, stream.is_none()).await?;
+    }
+    let Some(mut stream) = stream else { return Ok(0) };
+    if let Some(limit) = config.bandwidth_limit {
+        stream.limit_rate(limit);
+    }
+    let mut sent = 0;
+    loop {
+        match stream.next_part().await? {
+            Some(StreamPart::Data(data)) => {
+                sent += data.len() as u64;
+                responder.data(data, false).await?;
+            }
+            Some(StreamPart::Trailers(trailers)) => {
+                responder.headers(&header_fields(&trailers), true)?;
+                return Ok(sent);
+            }
+            None => {
+                responder.data(Vec::new(), true).await?;
+                return Ok(sent);
+            }
+        }
+    }
+}
+
+/// Header fields of a response, without those only meaningful to HTTP/1.1 connections, and with
+/// a Content-Length for a body of bytes. Also used for HTTP/3.
+pub(crate) fn response_fields(resp: &Response) -> Vec<(String, String)> {
+    let mut fields = resp.headers.as_ref().map(header_fields).unwrap_or_default();
+    let has_length = fields.iter().any(|(name, _)| name == "content-length");
+    if let Some(body) = resp.body.as_ref().filter(|_| !has_length) {
+        fields.push(("content-length".to_string(), body.len().to_string()));
+    }
+    fields
+}
+
+/// Fields of `headers` with lowercase names, as HTTP/2 requires. Repeated headers, stored newline
+/// separated, become one field each.
+pub(crate) fn header_fields(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    for (name, value) in headers {
+        let name = name.to_ascii_lowercase();
+        if matches!(name.as_str(), "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade") {
+            continue;
+        }
+        for value in value.split('\n') {
+            fields.push((name.clone(), value.trim_end_matches('\r').to_string()));
+        }
+    }
+    fields
+}
+
+/// Sending side of a stream, used by the task answering it.
+struct Responder {
+    id: u32,
+    out: Outbox,
+}
+
+impl Responder {
+    fn headers(&self, fields: &[(String, String)], end: bool) -> std::io::Result<()> {
+        let block = hpack::encode(fields.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        self.out.send(Out::Headers { stream: self.id, block, end }).map_err(|_| stream_closed())
+    }
+
+    /// Sends `data`, returning once it's written so a slow client holds up the handler's stream.
+    async fn data(&self, data: Vec<u8>, end: bool) -> std::io::Result<()> {
+        let (sent, written) = oneshot::channel();
+        self.out.send(Out::Data { stream: self.id, data, end, sent }).map_err(|_| stream_closed())?;
+        written.await.map_err(|_| stream_closed())
+    }
+
+    fn reset(&self, code: u32) {
+        let _ = self.out.send(Out::Reset { stream: self.id, code: Some(code) });
+    }
+}
+
+fn stream_closed() -> Error {
+    Error::new(ErrorKind::ConnectionReset, "Stream closed")
+}
+
+/// Sending end of the writer's queue, which counts what's queued and not written yet so a client
+/// that doesn't read can't make it grow without bound, see `MAX_QUEUED`.
+#[derive(Clone)]
+struct Outbox {
+    out: mpsc::UnboundedSender<Out>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    fn send(&self, out: Out) -> Result<(), mpsc::error::SendError<Out>> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.out.send(out)
+    }
+}
+
+/// What the writer is asked to do, in order.
+enum Out {
+    /// A frame written as is, ahead of DATA waiting for flow-control window.
+    Frame(Vec<u8>),
+    /// A stream was opened, its window starts at the client's initial window size.
+    Open(u32),
+    /// A header block, split into HEADERS and CONTINUATION frames as needed.
+    Headers { stream: u32, block: Vec<u8>, end: bool },
+    /// Data for a stream, sent as its window allows. `sent` fires once it's written and is
+    /// dropped if the stream is reset first.
+    Data { stream: u32, data: Vec<u8>, end: bool, sent: oneshot::Sender<()> },
+    /// The client gave more window to a stream, or to the connection for stream 0.
+    Window { stream: u32, increment: u32 },
+    Settings { initial_window: Option<u32>, max_frame_size: Option<u32> },
+    /// The stream was reset, by the server with `code` or by the client: what's queued for it is dropped.
+    Reset { stream: u32, code: Option<u32> },
+    /// Everything was sent, the connection can be closed.
+    Close,
+}
+
+/// Writes frames to the connection until told to close it. `pending` is the count kept by the
+/// `Outbox`, taken down once what was queued has been written.
+async fn write_frames(
+    mut write: ConnWrite,
+    mut queued: mpsc::UnboundedReceiver<Out>,
+    pending: Arc<AtomicUsize>,
+) -> std::io::Result<()> {
+    let mut writer = Writer {
+        buf: Vec::new(),
+        window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        max_frame_size: MAX_FRAME_SIZE,
+        streams: HashMap::new(),
+        sent: Vec::new(),
+    };
+    let mut open = true;
+    while open {
+        let Some(out) = queued.recv().await else { break };
+        open = writer.push(out);
+        let mut taken = 1;
+        while open {
+            let Ok(out) = queued.try_recv() else { break };
+            open = writer.push(out);
+            taken += 1;
+        }
+        writer.flush_data();
+        if !writer.buf.is_empty() {
+            write.write_all(&writer.buf).await?;
+            write.flush().await?;
+            writer.buf.clear();
+        }
+        pending.fetch_sub(taken, Ordering::Relaxed);
+        for sent in writer.sent.drain(..) {
+            let _ = sent.send(());
+        }
+    }
+    write.shutdown().await
+}
+
+/// Flow-control state of the writer, and the frames it's about to write.
+struct Writer {
+    buf: Vec<u8>,
+    /// The connection's send window.
+    window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    streams: HashMap<u32, Outgoing>,
+    /// Senders of DATA written with the next write.
+    sent: Vec<oneshot::Sender<()>>,
+}
+
+struct Outgoing {
+    window: i64,
+    queued: std::collections::VecDeque<Queued>,
+}
+
+struct Queued {
+    data: Vec<u8>,
+    offset: usize,
+    end: bool,
+    sent: oneshot::Sender<()>,
+}
+
+impl Writer {
+    /// Applies `out`, returning false once the connection should be closed.
+    fn push(&mut self, out: Out) -> bool {
+        match out {
+            Out::Frame(frame) => self.buf.extend(frame),
+            Out::Open(stream) => {
+                let outgoing = Outgoing { window: self.initial_window, queued: Default::default() };
+                self.streams.insert(stream, outgoing);
+            }
+            Out::Headers { stream, block, end } => {
+                // Streams that were reset get nothing more.
+                if !self.streams.contains_key(&stream) {
+                    return true;
+                }
+                let mut chunks = block.chunks(self.max_frame_size).peekable();
+                let mut kind = HEADERS;
+                let mut flags = if end { END_STREAM } else { 0 };
+                while let Some(chunk) = chunks.next() {
+                    if chunks.peek().is_none() {
+                        flags |= END_HEADERS;
+                    }
+                    self.buf.extend(frame(kind, flags, stream, chunk));
+                    (kind, flags) = (CONTINUATION, 0);
+                }
+                if end {
+                    self.streams.remove(&stream);
+                }
+            }
+            Out::Data { stream, data, end, sent } => {
+                if let Some(outgoing) = self.streams.get_mut(&stream) {
+                    outgoing.queued.push_back(Queued { data, offset: 0, end, sent });
+                }
+            }
+            Out::Window { stream: 0, increment } => {
+                self.window += increment as i64;
+                if self.window > MAX_WINDOW {
+                    return self.fail(FLOW_CONTROL_ERROR);
+                }
+            }
+            Out::Window { stream, increment } => {
+                let Some(outgoing) = self.streams.get_mut(&stream) else { return true };
+                outgoing.window += increment as i64;
+                if outgoing.window > MAX_WINDOW {
+                    self.streams.remove(&stream);
+                    self.buf.extend(frame(RST_STREAM, 0, stream, &FLOW_CONTROL_ERROR.to_be_bytes()));
+                }
+            }
+            Out::Settings { initial_window, max_frame_size } => {
+                if let Some(initial_window) = initial_window {
+                    // The change applies to the windows of open streams too.
+                    let delta = initial_window as i64 - self.initial_window;
+                    self.initial_window = initial_window as i64;
+                    for outgoing in self.streams.values_mut() {
+                        outgoing.window += delta;
+                        if outgoing.window > MAX_WINDOW {
+                            return self.fail(FLOW_CONTROL_ERROR);
+                        }
+                    }
+                }
+                if let Some(max_frame_size) = max_frame_size {
+                    self.max_frame_size = max_frame_size as usize;
+                }
+            }
+            Out::Reset { stream, code } => {
+                self.streams.remove(&stream);
+                if let Some(code) = code {
+                    self.buf.extend(frame(RST_STREAM, 0, stream, &code.to_be_bytes()));
+                }
+            }
+            Out::Close => return false,
+        }
+        true
+    }
+
+    /// Writes GOAWAY for an error the client made in a frame only the writer understands.
+    fn fail(&mut self, code: u32) -> bool {
+        let mut payload = 0u32.to_be_bytes().to_vec();
+        payload.extend(code.to_be_bytes());
+        self.buf.extend(frame(GOAWAY, 0, 0, &payload));
+        false
+    }
+
+    /// Turns as much queued data into DATA frames as the windows allow, a frame per stream at a time.
+    fn flush_data(&mut self) {
+        loop {
+            let mut progress = false;
+            let mut finished = Vec::new();
+            for (&id, outgoing) in &mut self.streams {
+                let Some(queued) = outgoing.queued.front_mut() else { continue };
+                let left = queued.data.len() - queued.offset;
+                let n = left
+                    .min(self.max_frame_size)
+                    .min(outgoing.window.max(0) as usize)
+                    .min(self.window.max(0) as usize);
+                if n == 0 && left > 0 {
+                    continue;
+                }
+                let last = n == left;
+                let flags = if last && queued.end { END_STREAM } else { 0 };
+                self.buf.extend(frame(DATA, flags, id, &queued.data[queued.offset..queued.offset + n]));
+                queued.offset += n;
+                outgoing.window -= n as i64;
+                self.window -= n as i64;
+                progress = true;
+                if last {
+                    let queued = outgoing.queued.pop_front().expect("queue isn't empty");
+                    self.sent.push(queued.sent);
+                    if queued.end {
+                        finished.push(id);
+                    }
+                }
+            }
+            for id in finished {
+                self.streams.remove(&id);
+            }
+            if !progress {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! HPACK (RFC 7541), the header compression of HTTP/2.
+//! Responses are encoded without the dynamic table or Huffman coding, which keeps the encoder
+//! stateless at the cost of a few bytes per header.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// Size of the dynamic table the decoder keeps, the default of SETTINGS_HEADER_TABLE_SIZE.
+pub(crate) const TABLE_SIZE: usize = 4096;
+
+/// A header block that can't be decoded, which ruins the decoder's state for the whole connection.
+#[derive(Debug)]
+pub(crate) struct CompressionError;
+
+type Field = (Vec<u8>, Vec<u8>);
+
+/// Decoder of the header blocks a client sends on one connection.
+pub(crate) struct Decoder {
+    /// The dynamic table, newest entry first.
+    table: VecDeque<Field>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Self {
+        Decoder { table: VecDeque::new(), size: 0, max_size: TABLE_SIZE }
+    }
+
+    /// Decodes a complete header block into its fields, in order. Returns `None` if the fields
+    /// add up to more than `max_list_size`, counted like SETTINGS_MAX_HEADER_LIST_SIZE. The whole
+    /// block is still decoded, so the table stays in step with the client's, but fields past the
+    /// limit are never copied: a few bytes referencing a large table entry can't grow into megabytes.
+    pub(crate) fn decode(&mut self, mut block: &[u8], max_list_size: usize) -> Result<Option<Vec<Field>>, CompressionError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut size_updates_allowed = true;
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                let (name, value) = self.entry(index)?;
+                list_size += name.len() + value.len() + 32;
+                if list_size <= max_list_size {
+                    fields.push((name.to_vec(), value.to_vec()));
+                }
+            } else if first & 0xc0 == 0x40 {
+                let field = self.literal(&mut block, 6)?;
+                list_size += entry_size(&field);
+                if list_size <= max_list_size {
+                    fields.push(field.clone());
+                }
+                self.insert(field);
+            } else if first & 0xe0 == 0x20 {
+                // Size updates may only start a block.
+                if !size_updates_allowed {
+                    return Err(CompressionError);
+                }
+                let size = integer(&mut block, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(CompressionError);
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // Literals without indexing and never indexed ones differ only for intermediaries.
+                let field = self.literal(&mut block, 4)?;
+                list_size += entry_size(&field);
+                if list_size <= max_list_size {
+                    fields.push(field);
+                }
+            }
+            size_updates_allowed = false;
+        }
+        Ok((list_size <= max_list_size).then_some(fields))
+    }
+
+    fn entry(&self, index: usize) -> Result<(&[u8], &[u8]), CompressionError> {
+        match index {
+            0 => Err(CompressionError),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes(), value.as_bytes()))
+            }
+            _ => self.table.get(index - 62).map(|(name, value)| (&name[..], &value[..])).ok_or(CompressionError),
+        }
+    }
+
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<Field, CompressionError> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0.to_vec(),
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = entry_size(&field);
+        // An entry larger than the table empties it and isn't added.
+        if size > self.max_size {
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+        self.size += size;
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some(field) = self.table.pop_back() else { break };
+            self.size -= entry_size(&field);
+        }
+    }
+}
+
+/// Size of a field in the dynamic table, which counts 32 bytes of overhead per entry.
+fn entry_size((name, value): &Field) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Encodes response fields, whose names must be lowercase.
+pub(crate) fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(i) = STATIC_TABLE.iter().position(|&field| field == (name, value)) {
+            encode_integer(&mut block, 0x80, 7, i + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(known, _)| known == name) {
+            Some(i) => encode_integer(&mut block, 0x00, 4, i + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Reads an integer with an `prefix` bit prefix, see RFC 7541 section 5.1.
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, CompressionError> {
+    let (&first, rest) = block.split_first().ok_or(CompressionError)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(CompressionError)?;
+        *block = rest;
+        // Anything past 2^28 is far beyond the sizes a header block can describe.
+        if shift > 21 {
+            return Err(CompressionError);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// Reads a string literal, Huffman coded or not, see RFC 7541 section 5.2.
+fn string(block: &mut &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let huffman = block.first().ok_or(CompressionError)? & 0x80 != 0;
+    let len = integer(block, 7)?;
+    if block.len() < len {
+        return Err(CompressionError);
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        return huffman_decode(data);
+    }
+    Ok(data.to_vec())
+}
+
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    encode_integer(block, 0x00, 7, data.len());
+    block.extend_from_slice(data);
+}
+
+/// Decodes a Huffman coded string, whose padding must be the start of the EOS code.
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let mut node = 0;
+    let mut depth = 0;
+    let mut all_ones = true;
+    for &byte in data {
+        for shift in (0..8).rev() {
+            let bit = (byte >> shift) & 1;
+            let next = tree[node][bit as usize];
+            if next & LEAF != 0 {
+                let symbol = next & !LEAF;
+                if symbol == EOS {
+                    return Err(CompressionError);
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                depth = 0;
+                all_ones = true;
+            } else {
+                node = next as usize;
+                depth += 1;
+                all_ones &= bit == 1;
+            }
+        }
+    }
+    if depth > 7 || !all_ones {
+        return Err(CompressionError);
+    }
+    Ok(decoded)
+}
+
+/// Marks a child of the Huffman tree as a symbol rather than a node.
+const LEAF: u16 = 0x8000;
+/// The end-of-string symbol, which must never be decoded.
+const EOS: u16 = 256;
+
+/// Returns the decoding tree of the Huffman code: the two children of each node, root first.
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0u16; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0; 2]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+/// The static table, RFC 7541 appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The Huffman code of each byte and EOS, as code and length in bits, RFC 7541 appendix B.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
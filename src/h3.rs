@@ -0,0 +1,366 @@
+//! HTTP/3 (RFC 9114) over QUIC, see [`Server::http3`](crate::Server::http3).
+//!
+//! Requests are answered like those of HTTP/2 streams, see the `h2` module, on a UDP socket bound
+//! to the port of the server's TCP listener.
+
+use crate::body::{Body, Unread};
+use crate::h2;
+use crate::route::Router;
+use crate::server::{self, Config, ConnReader, StreamPart, StreamReader};
+use crate::tls::{PeerCertificate, TlsConfig, rustls::pki_types::CertificateDer};
+use crate::types::{Method, Request, RequestHead, Response, Version};
+use bytes::{Buf, Bytes};
+use h3::error::Code;
+use h3::server::{RequestResolver, RequestStream};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinSet;
+
+type SendStream = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+type RecvStream = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+/// Most bytes of header fields per request, as for HTTP/2.
+const MAX_FIELD_SECTION_SIZE: u64 = 16_384;
+
+/// Binds the UDP socket HTTP/3 is served on. The read timeout becomes QUIC's idle timeout, which
+/// closes connections that carried nothing for that long.
+pub(crate) fn bind(addr: SocketAddr, tls: &TlsConfig, config: &Config) -> std::io::Result<quinn::Endpoint> {
+    let mut server_config = tls.quic_config()?;
+    let mut transport = quinn::TransportConfig::default();
+    if let Some(timeout) = config.read_timeout {
+        transport.max_idle_timeout(quinn::IdleTimeout::try_from(timeout).ok());
+    }
+    server_config.transport_config(Arc::new(transport));
+    quinn::Endpoint::server(server_config, addr)
+}
+
+/// Accepts QUIC connections and serves HTTP/3 on them until `closing` turns true, then waits for
+/// the requests in progress to be answered.
+pub(crate) async fn serve(
+    endpoint: quinn::Endpoint,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else { break };
+                let remote_addr = incoming.remote_address();
+                let id = config.next_request_id();
+                let (router, config, closing) = (router.clone(), config.clone(), closing.clone());
+                connections.spawn(async move {
+                    if let Err(e) = serve_connection(incoming, id, router, config, closing).await {
+                        eprintln!("error, request: {}, conn: {}, err: {:?}", id, remote_addr, e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            Ok(()) = closing.changed() => {
+                if *closing.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+    while connections.join_next().await.is_some() {}
+    endpoint.close(0u32.into(), b"");
+    endpoint.wait_idle().await;
+}
+
+/// Serves the requests of a QUIC connection until the client closes it or `closing` turns true.
+/// `id` is stamped on the first request, later ones get their own.
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    id: u64,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    mut closing: watch::Receiver<bool>,
+) -> Result<(), crate::Error> {
+    let stats = config.stats.clone();
+    let remote_addr = incoming.remote_address();
+    let conn = match incoming.await {
+        Ok(conn) => conn,
+        Err(e) => {
+            stats.handshake_failure();
+            return Err(crate::Error::Tls(e.into()));
+        }
+    };
+    let _active = stats.connection();
+    let peer_certificate = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|chain| chain.first().and_then(|cert| PeerCertificate::from_der(cert)));
+    let mut h3 = h3::server::builder()
+        .max_field_section_size(MAX_FIELD_SECTION_SIZE)
+        .build(h3_quinn::Connection::new(conn.clone()))
+        .await
+        .map_err(Error::other)?;
+
+    let mut first_id = Some(id);
+    let mut requests = JoinSet::new();
+    let mut going_away = false;
+    let result = loop {
+        if going_away && requests.is_empty() {
+            conn.close(Code::H3_NO_ERROR.value().try_into().expect("error codes fit a varint"), b"");
+            break Ok(());
+        }
+        tokio::select! {
+            accepted = h3.accept() => match accepted {
+                Ok(Some(resolver)) => {
+                    let stream = StreamTask {
+                        remote_addr,
+                        req_id: first_id.take().unwrap_or_else(|| config.next_request_id()),
+                        peer_certificate: peer_certificate.clone(),
+                        router: router.clone(),
+                        config: config.clone(),
+                    };
+                    requests.spawn(async move {
+                        let result = stream.respond(resolver).await;
+                        server::count_error(&stream.config.stats, &result);
+                        if let Err(e) = result {
+                            eprintln!("error, request: {}, conn: {}, err: {:?}", stream.req_id, remote_addr, e);
+                        }
+                    });
+                }
+                Ok(None) => break Ok(()),
+                Err(e) if e.is_h3_no_error() => break Ok(()),
+                Err(e) => break Err(Error::new(ErrorKind::ConnectionAborted, e).into()),
+            },
+            Some(_) = requests.join_next(), if !requests.is_empty() => {}
+            Ok(()) = closing.changed(), if !going_away => {
+                if *closing.borrow() {
+                    // GOAWAY, the requests already accepted are still answered.
+                    going_away = true;
+                    let _ = h3.shutdown(0).await;
+                }
+            }
+        }
+    };
+    while requests.join_next().await.is_some() {}
+    result
+}
+
+/// Everything needed to answer a request, moved into its task.
+struct StreamTask {
+    remote_addr: SocketAddr,
+    req_id: u64,
+    peer_certificate: Option<PeerCertificate>,
+    router: Arc<Router>,
+    config: Arc<Config>,
+}
+
+impl StreamTask {
+    /// Runs the router on the request and sends its response, like `StreamTask::respond` in h2.rs.
+    async fn respond(&self, resolver: RequestResolver<h3_quinn::Connection, Bytes>) -> Result<(), crate::Error> {
+        let config = &self.config;
+        let (req, stream) = resolver.resolve_request().await.map_err(Error::other)?;
+        let (mut send, recv) = stream.split();
+        let parsed = match self.request(req, recv).await {
+            Ok((head, body)) => server::apply_unexpected_body_policy(&head.method, body, config)
+                .await
+                .map(|body| (head, body)),
+            Err(e) => Err(e),
+        };
+        let (head, body) = match parsed {
+            Ok(parts) => parts,
+            Err(e) => {
+                match server::rejection_status(&e) {
+                    Some(status) => {
+                        let req = Request { remote_addr: self.remote_addr, version: Version::Http3, ..Default::default() };
+                        let resp = self.router.error_response(status, req).await;
+                        let _ = send_response(&mut send, resp, false, config).await;
+                    }
+                    None => send.stop_stream(Code::H3_MESSAGE_ERROR),
+                }
+                return Err(crate::Error::Parse(e));
+            }
+        };
+        config.stats.request();
+        let mut req = Request::from_parts(head, body);
+        req.id = self.req_id;
+        if let Some(cert) = &self.peer_certificate {
+            req.extensions.insert(cert.clone());
+        }
+
+        let head_only = req.method == Method::HEAD;
+        let send_continue = async {
+            let mut interim = http::Response::new(());
+            *interim.status_mut() = http::StatusCode::CONTINUE;
+            send.send_response(interim).await.map_err(stream_error)
+        };
+        let (resp, panic, _) = server::dispatch(req, &self.router, config, send_continue).await?;
+        if resp.upgrade.is_some() {
+            // Upgrades, such as WebSocket, take over an HTTP/1.1 connection.
+            send.stop_stream(Code::H3_VERSION_FALLBACK);
+        } else {
+            let streaming = resp.stream.is_some() && !head_only;
+            match send_response(&mut send, resp, head_only, config).await {
+                Ok(sent) => config.stats.streamed(sent),
+                Err(e) => {
+                    server::write_failed(config, self.remote_addr, self.req_id, streaming, &e);
+                    if server::is_client_gone(&e) {
+                        config.stats.client_close();
+                    } else {
+                        send.stop_stream(Code::H3_INTERNAL_ERROR);
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        if let Some(msg) = panic {
+            return Err(crate::Error::HandlerPanic(msg));
+        }
+        Ok(())
+    }
+
+    /// Parses the request's head and sets up its body.
+    async fn request(&self, req: http::Request<()>, mut recv: RecvStream) -> std::io::Result<(RequestHead, Body)> {
+        let (parts, ()) = req.into_parts();
+        let mut fields = vec![(b":method".to_vec(), parts.method.as_str().as_bytes().to_vec())];
+        if let Some(scheme) = parts.uri.scheme_str() {
+            fields.push((b":scheme".to_vec(), scheme.as_bytes().to_vec()));
+        }
+        if let Some(authority) = parts.uri.authority() {
+            fields.push((b":authority".to_vec(), authority.as_str().as_bytes().to_vec()));
+        }
+        if let Some(path) = parts.uri.path_and_query().filter(|_| parts.method != http::Method::CONNECT) {
+            fields.push((b":path".to_vec(), path.as_str().as_bytes().to_vec()));
+        }
+        for (name, value) in &parts.headers {
+            fields.push((name.as_str().as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        let mut head = h2::request_head(fields, Version::Http3, &self.config)?;
+        head.remote_addr = self.remote_addr;
+        head.received = std::time::Instant::now();
+
+        // Whether there is a body of unknown length shows once the first piece of it arrives, or
+        // the client ends the stream without one.
+        let mut pending = Vec::new();
+        if head.content_length.is_none() {
+            match recv.recv_data().await.map_err(stream_error)? {
+                Some(mut data) => pending = data.copy_to_bytes(data.remaining()).to_vec(),
+                None => return Ok((head, Body::empty())),
+            }
+        }
+        let mut reader = RecvBody { recv, chunked: head.content_length.is_none(), pending: Vec::new(), pos: 0, done: false };
+        reader.push(pending);
+        let reader: ConnReader = BufReader::new(Box::new(reader));
+        let reader = Arc::new(Mutex::new(reader)).try_lock_owned().expect("a new lock is free");
+        let body = match head.content_length {
+            Some(len) => Body::remote(reader, len, Unread::default()),
+            None => Body::chunked(StreamReader::new(reader, Unread::default())),
+        };
+        Ok((head, body))
+    }
+}
+
+/// Sends the response head, then its body and trailers. Returns the number of streamed bytes.
+async fn send_response(send: &mut SendStream, mut resp: Response, head_only: bool, config: &Config) -> std::io::Result<u64> {
+    let mut head = http::Response::new(());
+    *head.status_mut() = http::StatusCode::from_u16(resp.status_code.as_u16())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    *head.headers_mut() = header_map(h2::response_fields(&resp));
+    send.send_response(head).await.map_err(stream_error)?;
+
+    // Responses to HEAD keep their Content-Length but never carry a body.
+    if let Some(body) = resp.body.take().filter(|body| !body.is_empty() && !head_only) {
+        send.send_data(Bytes::from(body)).await.map_err(stream_error)?;
+    }
+    let mut sent = 0;
+    if let Some(mut stream) = resp.stream.take().filter(|_| !head_only) {
+        if let Some(limit) = config.bandwidth_limit {
+            stream.limit_rate(limit);
+        }
+        while let Some(part) = stream.next_part().await? {
+            match part {
+                StreamPart::Data(data) => {
+                    sent += data.len() as u64;
+                    send.send_data(Bytes::from(data)).await.map_err(stream_error)?;
+                }
+                StreamPart::Trailers(trailers) => {
+                    send.send_trailers(header_map(h2::header_fields(&trailers))).await.map_err(stream_error)?;
+                }
+            }
+        }
+    }
+    send.finish().await.map_err(stream_error)?;
+    Ok(sent)
+}
+
+/// Converts header fields to an `http::HeaderMap`, leaving out those it considers invalid.
+fn header_map(fields: Vec<(String, String)>) -> http::HeaderMap {
+    let mut map = http::HeaderMap::new();
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (http::HeaderName::from_bytes(name.as_bytes()), http::HeaderValue::from_str(&value)) else {
+            continue;
+        };
+        map.append(name, value);
+    }
+    map
+}
+
+/// A stream that can't be written to anymore was reset by the client or lost with its connection.
+fn stream_error(e: h3::error::StreamError) -> Error {
+    Error::new(ErrorKind::ConnectionReset, e)
+}
+
+/// Request body of a stream. Bodies without a Content-Length get chunked framing, so they're read
+/// with a `StreamReader` like chunked HTTP/1.1 bodies.
+struct RecvBody {
+    recv: RecvStream,
+    chunked: bool,
+    pending: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl RecvBody {
+    fn push(&mut self, data: Vec<u8>) {
+        self.pending.clear();
+        self.pos = 0;
+        if !self.chunked {
+            self.pending = data;
+        } else if !data.is_empty() {
+            self.pending.extend(format!("{:X}\r\n", data.len()).as_bytes());
+            self.pending.extend(data);
+            self.pending.extend(b"\r\n");
+        }
+    }
+}
+
+impl AsyncRead for RecvBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        while this.pos == this.pending.len() {
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            match this.recv.poll_recv_data(cx) {
+                Poll::Ready(Ok(Some(mut data))) => {
+                    let data = data.copy_to_bytes(data.remaining()).to_vec();
+                    this.push(data);
+                }
+                Poll::Ready(Ok(None)) => {
+                    this.done = true;
+                    this.push(Vec::new());
+                    if this.chunked {
+                        this.pending.extend(b"0\r\n\r\n");
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(stream_error(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(this.pending.len() - this.pos);
+        buf.put_slice(&this.pending[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,83 @@
+//! Passing the listening socket to a new process, for restarts that don't drop connections.
+//!
+//! The successor is the current executable started again with the same arguments. It finds the
+//! socket's file descriptor in the `ZEP_LISTEN_FD` environment variable and accepts on it, while
+//! the old process stops accepting and finishes the connections it has. The variable is read and
+//! removed once, and the socket goes to the first server whose address it's bound to. The socket is never closed
+//! in between, so clients connecting during the restart wait in its backlog instead of being refused.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::{Mutex, OnceLock};
+
+/// Environment variable holding the file descriptor of an inherited listener.
+pub(crate) const LISTEN_FD_VAR: &str = "ZEP_LISTEN_FD";
+
+/// The listener passed in by a predecessor until a server takes it, see [`take_inherited`].
+static INHERITED: OnceLock<Mutex<Option<Result<TcpListener>>>> = OnceLock::new();
+
+/// Takes the listener passed in by a predecessor if it's bound to one of `addrs`, the configured
+/// address of the server asking. A port of 0 matches any port, as the predecessor was given one.
+/// [`LISTEN_FD_VAR`] is read and removed from the environment on the first call, so the
+/// descriptor is adopted at most once, however many servers the process binds.
+pub(crate) fn take_inherited(addrs: &[SocketAddr]) -> Option<Result<TcpListener>> {
+    let slot = INHERITED.get_or_init(|| {
+        let value = std::env::var(LISTEN_FD_VAR).ok();
+        if value.is_some() {
+            // SAFETY: this runs once, while the first server binds, and zep reads no other
+            // environment variables concurrently.
+            unsafe { std::env::remove_var(LISTEN_FD_VAR) };
+        }
+        Mutex::new(inherited_listener(value))
+    });
+    let mut slot = slot.lock().ok()?;
+    match slot.take()? {
+        Ok(listener) => match listener.local_addr() {
+            Ok(local) if addr_matches(local, addrs) => Some(Ok(listener)),
+            _ => {
+                *slot = Some(Ok(listener));
+                None
+            }
+        },
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Whether a listener bound to `local` serves one of the configured `addrs`.
+pub(crate) fn addr_matches(local: SocketAddr, addrs: &[SocketAddr]) -> bool {
+    addrs.iter().any(|addr| addr.ip() == local.ip() && (addr.port() == 0 || addr.port() == local.port()))
+}
+
+/// Adopts the listener passed in by a predecessor, if `value` of [`LISTEN_FD_VAR`] names one.
+/// The descriptor is marked close-on-exec again, so it isn't leaked to processes we start.
+pub(crate) fn inherited_listener(value: Option<String>) -> Option<Result<TcpListener>> {
+    let fd: RawFd = match value?.trim().parse() {
+        Ok(fd) if fd > 2 => fd,
+        _ => return Some(Err(Error::new(ErrorKind::InvalidInput, "Invalid ZEP_LISTEN_FD"))),
+    };
+    // SAFETY: the predecessor passes the descriptor of its listening socket for us to own.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // SAFETY: F_SETFD only changes the flags of a descriptor we own.
+    if unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Some(Err(Error::last_os_error()));
+    }
+    Some(listener.set_nonblocking(true).map(|()| listener))
+}
+
+/// Starts the current executable again with the same arguments, passing it the listener `fd`.
+pub(crate) fn spawn_successor(fd: RawFd) -> Result<Child> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.args(std::env::args_os().skip(1)).env(LISTEN_FD_VAR, fd.to_string());
+    // SAFETY: fcntl is async-signal-safe, and only clears close-on-exec in the forked child so
+    // the descriptor survives the exec.
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    command.spawn()
+}
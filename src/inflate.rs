@@ -0,0 +1,42 @@
+//! DEFLATE decompression (RFC 1951) with the gzip (RFC 1952) and zlib (RFC 1950) wrappers,
+//! shared by everything in zep that needs to decode compressed content.
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use std::io::{Error, ErrorKind, Read, Result};
+
+/// Decompresses a gzip stream, checking its CRC and size trailer.
+/// Concatenated gzip members are decoded one after another.
+pub(crate) fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    read_bounded(MultiGzDecoder::new(data), limit)
+}
+
+/// Decompresses a zlib stream. Falls back to raw DEFLATE, which some clients
+/// send for `Content-Encoding: deflate`.
+pub(crate) fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let is_zlib = data.len() >= 2
+        && data[0] & 0x0f == 8
+        && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0;
+    if !is_zlib {
+        return inflate(data, limit);
+    }
+    if data[1] & 0x20 != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "zlib preset dictionaries are not supported"));
+    }
+    read_bounded(ZlibDecoder::new(data), limit)
+}
+
+/// Decompresses raw DEFLATE data, failing if the output would exceed `limit` bytes.
+pub(crate) fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    read_bounded(DeflateDecoder::new(data), limit)
+}
+
+/// Reads `decoder` to the end, stopping with an `InvalidData` error as soon as the output goes
+/// over `limit` bytes, so a small compressed body can't expand without bound.
+fn read_bounded(decoder: impl Read, limit: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decoder.take(limit as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > limit {
+        return Err(Error::new(ErrorKind::InvalidData, "Decompressed body too large"));
+    }
+    Ok(out)
+}
@@ -0,0 +1,345 @@
+//! Running a [`Router`] as an AWS Lambda function behind API Gateway or an Application Load Balancer,
+//! so the same app runs as a long-lived server and as a function. Enabled with the `lambda` feature.
+//! Events and results are [`serde_json`] values.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use zep::{tokio, Router, Request, Response, Method, Server};
+//!
+//! async fn root(_req: Request) -> Response {
+//!     Response::ok("Hello world!")
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut router = Router::new();
+//!     router.route(Method::GET, "/", root);
+//!
+//!     if std::env::var("AWS_LAMBDA_RUNTIME_API").is_ok() {
+//!         zep::lambda::run(router).await.unwrap();
+//!     } else {
+//!         Server::new("0.0.0.0:8080", router).run().await.unwrap();
+//!     }
+//! }
+//! ```
+
+use crate::crypto::{base64_decode, base64_encode};
+use crate::server::find_headers_end;
+use crate::{Body, Error, HeaderMap, Method, Request, Response, ResponseBody, Router, Version};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use serde_json::{Map, Value, json};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Environment variable Lambda sets to the address of its runtime API.
+const RUNTIME_API_VAR: &str = "AWS_LAMBDA_RUNTIME_API";
+
+/// Where an event came from, each expects its own response format.
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    /// API Gateway REST API, payload format 1.0.
+    RestApi,
+    /// API Gateway HTTP API, payload format 2.0.
+    HttpApi,
+    /// Application Load Balancer, with or without multi-value headers.
+    Alb { multi_value: bool },
+}
+
+impl Source {
+    fn of(event: &Value) -> Option<Source> {
+        let context = event.get("requestContext");
+        if context.and_then(|c| c.get("elb")).is_some() {
+            return Some(Source::Alb { multi_value: event.get("multiValueHeaders").is_some_and(Value::is_object) });
+        }
+        if context.and_then(|c| c.get("http")).is_some() {
+            return Some(Source::HttpApi);
+        }
+        event.get("httpMethod").map(|_| Source::RestApi)
+    }
+}
+
+/// Runs `router` as a Lambda function: takes events from the Lambda runtime API one at a time,
+/// handles them with [`Router::handle_request`] and sends back the responses, until the
+/// runtime API can't be reached. Events that aren't API Gateway or ALB requests, and handlers
+/// that panic, are reported to Lambda as invocation errors.
+/// Returns an error right away if `AWS_LAMBDA_RUNTIME_API` isn't set, i.e. outside of Lambda.
+pub async fn run(router: Router) -> Result<(), Error> {
+    let api = std::env::var(RUNTIME_API_VAR)
+        .map_err(|_| Error::Io(io::Error::new(ErrorKind::NotFound, "AWS_LAMBDA_RUNTIME_API is not set")))?;
+    serve_events(&api, Arc::new(router)).await.map_err(Error::Io)
+}
+
+pub(crate) async fn serve_events(api: &str, router: Arc<Router>) -> io::Result<()> {
+    loop {
+        let (headers, event) = runtime_call(api, "GET", "/2018-06-01/runtime/invocation/next", b"").await?;
+        let id = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("lambda-runtime-aws-request-id"))
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Missing Lambda-Runtime-Aws-Request-Id"))?;
+
+        let event = serde_json::from_slice::<Value>(&event).ok();
+        let result = match event.as_ref().and_then(|event| Some((event, request_from_event(event)?))) {
+            Some((event, req)) => {
+                let router = router.clone();
+                match tokio::spawn(async move { router.handle_request(req).await }).await {
+                    Ok(resp) => response_to_event(resp, event).await.map_err(|e| ("Response.Error", e.to_string())),
+                    Err(_) => Err(("Handler.Panic", "Handler panicked".to_string())),
+                }
+            }
+            None => Err(("Event.Unsupported", "Not an API Gateway or ALB event".to_string())),
+        };
+
+        let (outcome, body) = match result {
+            Ok(resp) => ("response", resp),
+            Err((kind, message)) => ("error", json!({"errorMessage": message, "errorType": kind})),
+        };
+        let path = format!("/2018-06-01/runtime/invocation/{}/{}", id, outcome);
+        runtime_call(api, "POST", &path, body.to_string().as_bytes()).await?;
+    }
+}
+
+/// Makes one request to the runtime API, returns the response headers and body.
+async fn runtime_call(api: &str, method: &str, path: &str, body: &[u8]) -> io::Result<(HeaderMap, Vec<u8>)> {
+    let mut conn = TcpStream::connect(api).await?;
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method, path, api, body.len(),
+    );
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(body).await?;
+
+    let mut resp = Vec::new();
+    conn.read_to_end(&mut resp).await?;
+    let end = find_headers_end(&resp)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Malformed runtime API response"))?;
+    let head = String::from_utf8_lossy(&resp[..end]);
+    let mut lines = head.lines();
+    let status = lines.next().and_then(|line| line.split_whitespace().nth(1)).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(io::Error::other(format!("Runtime API answered {} to {} {}", status, method, path)));
+    }
+    let headers: HeaderMap = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let mut body = resp.split_off(end);
+    let len = headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("content-length")).and_then(|(_, v)| v.parse().ok());
+    if let Some(len) = len {
+        body.truncate(len);
+    }
+    Ok((headers, body))
+}
+
+/// Builds a Request from an API Gateway REST API, HTTP API or ALB event.
+/// Returns `None` for other events, or if the body isn't valid base64 while flagged as such.
+/// `Request.remote_addr` is the client's IP address where the event has it, with port 0.
+///
+/// # Example:
+/// ```
+/// use zep::{lambda, serde_json, Method};
+///
+/// let event = serde_json::from_str(r#"{
+///     "version": "2.0",
+///     "rawPath": "/users",
+///     "rawQueryString": "page=2",
+///     "headers": {"accept": "application/json"},
+///     "requestContext": {"http": {"method": "GET", "sourceIp": "203.0.113.7"}},
+///     "isBase64Encoded": false
+/// }"#).unwrap();
+/// let req = lambda::request_from_event(&event).unwrap();
+/// assert_eq!(req.method, Method::GET);
+/// assert_eq!(req.path, "/users?page=2");
+/// ```
+pub fn request_from_event(event: &Value) -> Option<Request> {
+    let source = Source::of(event)?;
+    let context = event.get("requestContext");
+    let http = context.and_then(|c| c.get("http"));
+
+    let (method, mut path, query) = match source {
+        Source::HttpApi => (
+            http?.get("method")?.as_str()?,
+            event.get("rawPath")?.as_str()?.to_string(),
+            event.get("rawQueryString").and_then(Value::as_str).unwrap_or_default().to_string(),
+        ),
+        // ALB passes the query parameters as they were sent, API Gateway decodes them.
+        _ => (
+            event.get("httpMethod")?.as_str()?,
+            event.get("path")?.as_str()?.to_string(),
+            query_from_params(event, source == Source::RestApi),
+        ),
+    };
+    if !query.is_empty() {
+        path.push('?');
+        path.push_str(&query);
+    }
+
+    let mut headers = HeaderMap::new();
+    // REST APIs send both forms, the multi-value one keeps repeated headers apart. It's null when
+    // there are no headers.
+    if let Some(Value::Object(fields)) = event.get("multiValueHeaders") {
+        for (key, values) in fields {
+            let values: Vec<&str> = values.as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+            if values.is_empty() {
+                continue;
+            }
+            let separator = if key.eq_ignore_ascii_case("cookie") { "; " } else { ", " };
+            headers.insert(key.clone(), values.join(separator));
+        }
+    } else if let Some(Value::Object(fields)) = event.get("headers") {
+        for (key, value) in fields {
+            if let Some(value) = value.as_str() {
+                headers.insert(key.clone(), value.to_string());
+            }
+        }
+    }
+    // HTTP APIs move the cookies out of the headers.
+    if let Some(cookies) = event.get("cookies").and_then(Value::as_array) {
+        let cookies: Vec<&str> = cookies.iter().filter_map(Value::as_str).collect();
+        if !cookies.is_empty() {
+            headers.insert("cookie".to_string(), cookies.join("; "));
+        }
+    }
+
+    let body = match event.get("body").and_then(Value::as_str) {
+        Some(body) if event.get("isBase64Encoded").and_then(Value::as_bool) == Some(true) => base64_decode(body)?,
+        Some(body) => body.as_bytes().to_vec(),
+        None => Vec::new(),
+    };
+
+    let source_ip = match source {
+        Source::HttpApi => http.and_then(|h| h.get("sourceIp")),
+        _ => context.and_then(|c| c.get("identity")).and_then(|i| i.get("sourceIp")),
+    };
+    let ip = source_ip.and_then(Value::as_str).and_then(|ip| ip.parse::<IpAddr>().ok());
+    let protocol = match source {
+        Source::HttpApi => http.and_then(|h| h.get("protocol")),
+        _ => context.and_then(|c| c.get("protocol")),
+    };
+
+    Some(Request {
+        method: Method::from(method),
+        path,
+        version: protocol.and_then(Value::as_str).map_or(Version::Http11, Version::from),
+        headers,
+        body: Body::from(body),
+        remote_addr: SocketAddr::new(ip.unwrap_or(IpAddr::from([0, 0, 0, 0])), 0),
+        ..Default::default()
+    })
+}
+
+/// Rebuilds the query string of a REST API or ALB event from its parameters.
+fn query_from_params(event: &Value, encode: bool) -> String {
+    let mut pairs = Vec::new();
+    if let Some(Value::Object(params)) = event.get("multiValueQueryStringParameters") {
+        for (key, values) in params {
+            for value in values.as_array().into_iter().flatten().filter_map(Value::as_str) {
+                pairs.push((key.as_str(), value));
+            }
+        }
+    } else if let Some(Value::Object(params)) = event.get("queryStringParameters") {
+        for (key, value) in params {
+            if let Some(value) = value.as_str() {
+                pairs.push((key.as_str(), value));
+            }
+        }
+    }
+    let pairs: Vec<String> = pairs
+        .into_iter()
+        .map(|(key, value)| match encode {
+            true => format!("{}={}", percent_encode(key), percent_encode(value)),
+            false => format!("{}={}", key, value),
+        })
+        .collect();
+    pairs.join("&")
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+fn percent_encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Converts `resp` to the result format expected by the source of `event`, the event the
+/// request was built from. A streamed body is read into memory first, since Lambda can't
+/// stream it. Bodies that aren't valid UTF-8 are sent base64 encoded.
+///
+/// # Example:
+/// ```
+/// use zep::{lambda, serde_json, tokio, Response};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let event = serde_json::json!({"httpMethod": "GET", "path": "/", "headers": {}});
+///     let result = lambda::response_to_event(Response::ok("hi"), &event).await.unwrap();
+///     assert_eq!(result.get("statusCode").and_then(|s| s.as_u64()), Some(200));
+///     assert_eq!(result.get("body").and_then(|b| b.as_str()), Some("hi"));
+/// }
+/// ```
+pub async fn response_to_event(resp: Response, event: &Value) -> io::Result<Value> {
+    let source = Source::of(event).unwrap_or(Source::RestApi);
+    let (head, body) = resp.into_parts();
+    let body = match body {
+        ResponseBody::Empty => Vec::new(),
+        ResponseBody::Bytes(body) => body,
+        ResponseBody::Stream(stream) => stream.read_to_end().await?,
+    };
+
+    let mut single = Map::new();
+    let mut multi = Map::new();
+    let mut cookies = Vec::new();
+    for (key, value) in &head.headers {
+        // The body is sent whole, so its framing no longer applies.
+        if key.eq_ignore_ascii_case("transfer-encoding") {
+            continue;
+        }
+        // Repeated headers are stored newline separated, see `Response::append_header`.
+        let values: Vec<&str> = value.split('\n').map(|v| v.trim_end_matches('\r')).collect();
+        if key.eq_ignore_ascii_case("set-cookie") {
+            cookies.extend(values.iter().map(|&v| Value::from(v)));
+            // Single-value headers can only carry one cookie, API Gateway prefers the multi-value list.
+            single.insert(key.clone(), Value::from(*values.last().unwrap_or(&"")));
+        } else {
+            single.insert(key.clone(), Value::from(values.join(", ")));
+        }
+        multi.insert(key.clone(), Value::Array(values.into_iter().map(Value::from).collect()));
+    }
+
+    let mut result = Map::new();
+    result.insert("statusCode".into(), Value::from(head.status_code.as_u16()));
+    match source {
+        Source::RestApi => {
+            result.insert("headers".into(), Value::Object(single));
+            result.insert("multiValueHeaders".into(), Value::Object(multi));
+        }
+        Source::HttpApi => {
+            single.retain(|key, _| !key.eq_ignore_ascii_case("set-cookie"));
+            result.insert("headers".into(), Value::Object(single));
+            result.insert("cookies".into(), Value::Array(cookies));
+        }
+        Source::Alb { multi_value } => {
+            result.insert("statusDescription".into(), Value::from(head.status_code.to_string()));
+            match multi_value {
+                true => result.insert("multiValueHeaders".into(), Value::Object(multi)),
+                false => result.insert("headers".into(), Value::Object(single)),
+            };
+        }
+    }
+    let (body, base64) = match String::from_utf8(body) {
+        Ok(body) => (body, false),
+        Err(e) => (base64_encode(e.as_bytes()), true),
+    };
+    result.insert("body".into(), Value::from(body));
+    result.insert("isBase64Encoded".into(), Value::from(base64));
+    Ok(Value::Object(result))
+}
@@ -25,15 +25,54 @@
 //!
 //!
 
+mod body;
+mod context;
+pub mod cookie;
+mod crypto;
+mod error;
+mod extensions;
+#[cfg(feature = "rustls")]
+mod h2;
+#[cfg(feature = "http3")]
+mod h3;
+#[cfg(feature = "fastcgi")]
+pub mod fastcgi;
+#[cfg(unix)]
+mod handoff;
+#[cfg(feature = "decompression")]
+mod inflate;
+#[cfg(feature = "lambda")]
+pub mod lambda;
+pub mod middleware;
+mod panic;
+pub mod proto;
 mod route;
 pub mod serve;
 mod server;
+mod stats;
+pub mod table;
 mod tests;
+#[cfg(feature = "rustls")]
+pub mod tls;
 mod types;
+mod upgrade;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod validation;
 
-pub use route::{Handler, Router};
-pub use server::{Server, StreamReader, StreamWriter};
+pub use body::Body;
+pub use context::{RequestContext, RequestLog};
+pub use error::Error;
+pub use extensions::Extensions;
+pub use route::{ErrorFormat, Handler, MergeError, Router};
+pub use server::{Accounting, EncodedPathPolicy, Readiness, Server, ServerHandle, SlowRequest, StreamReader, StreamWriter, UnexpectedBodyPolicy, WriteFailure};
+pub use panic::PanicReport;
+pub use stats::ServerStats;
 /// Re-exporting tokio for user convenience.
 pub use tokio;
-pub use types::{HeaderMap, Method, ParamMap, Request, Response, StatusCode, Version};
+/// Re-exporting serde_json, whose `Value` appears in zep's API.
+pub use serde_json;
+pub use types::{HeaderMap, Method, ParamMap, Request, RequestHead, Response, ResponseBody, ResponseHead, StatusCode, Version};
+pub use upgrade::Upgraded;
+pub use validation::ValidationError;
 //pub use serve;
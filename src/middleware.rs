@@ -0,0 +1,447 @@
+//! Ready-made middleware, attach them to a route with [`Router::middleware`](crate::Router::middleware).
+
+#[cfg(feature = "signed-cookies")]
+mod flash;
+#[cfg(feature = "jwt")]
+mod jwt;
+
+#[cfg(feature = "signed-cookies")]
+pub use flash::{Flash, FlashMessages};
+#[cfg(feature = "jwt")]
+pub use jwt::{Claims, Jwt};
+
+use crate::crypto::{base64url_encode, random_bytes};
+use crate::serve::ReverseProxy;
+use crate::{Body, Handler, Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Applies `middleware` only to requests matching `predicate`, others go straight to the next layer.
+/// Saves splitting routes into groups just so some of them get a middleware, for example to require
+/// authentication on every path under `/api` from one global middleware.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Request, Response, StatusCode, Handler, middleware};
+///
+/// async fn auth(req: Request, next: Handler) -> Response {
+///     if !req.headers.contains_key("Authorization") {
+///         return Response::new(StatusCode::Unauthorized);
+///     }
+///     next(req).await
+/// }
+///
+/// let mut router = Router::new();
+/// router.global_middleware(middleware::when(|req| req.path.starts_with("/api/"), auth));
+/// ```
+pub fn when<P, M, Fut>(
+    predicate: P,
+    middleware: M,
+) -> impl Fn(Request, Handler) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync + 'static
+where
+    P: Fn(&Request) -> bool + Send + Sync + 'static,
+    M: Fn(Request, Handler) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    move |req, next| {
+        if predicate(&req) {
+            Box::pin(middleware(req, next))
+        } else {
+            next(req)
+        }
+    }
+}
+
+/// Overload protection that caps the number of requests handled at the same time.
+/// Requests over the limit are answered right away with 503 Service Unavailable and a
+/// `Retry-After` header instead of queueing up behind the ones in progress.
+/// Clones share the same counter, so one `LoadShed` can guard several routes together.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, middleware::LoadShed};
+///
+/// async fn report(_req: Request) -> Response {
+///     Response::ok("expensive")
+/// }
+///
+/// let shed = LoadShed::new(64).retry_after(5);
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/report", report);
+/// router.middleware(move |req, next| shed.handle(req, next));
+/// ```
+#[derive(Clone)]
+pub struct LoadShed {
+    in_flight: Arc<AtomicUsize>,
+    max: usize,
+    retry_after: u64,
+}
+
+impl LoadShed {
+    /// Returns a LoadShed that lets at most `max` requests in at once, with a `Retry-After` of 1 second.
+    pub fn new(max: usize) -> Self {
+        LoadShed { in_flight: Arc::new(AtomicUsize::new(0)), max, retry_after: 1 }
+    }
+
+    /// Sets the number of seconds sent in `Retry-After` when a request is shed.
+    pub fn retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = secs;
+        self
+    }
+
+    /// Returns the number of requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Runs `next` if there is capacity left, otherwise returns a 503 response.
+    /// The slot is taken when this is called and released when the returned future completes or is dropped.
+    pub fn handle(&self, req: Request, next: Handler) -> impl Future<Output = Response> + Send + use<> {
+        let admitted = self.in_flight.fetch_add(1, Ordering::AcqRel) < self.max;
+        let slot = Slot(self.in_flight.clone());
+        let retry_after = self.retry_after;
+        async move {
+            let _slot = slot;
+            if !admitted {
+                return Response::new(StatusCode::ServiceUnavailable)
+                    .header("Retry-After", &retry_after.to_string());
+            }
+            next(req).await
+        }
+    }
+}
+
+/// Releases an in-flight slot when dropped, also when the handler panics.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Lets POST requests stand in for PUT, PATCH and DELETE, for HTML forms and clients that can
+/// only send GET and POST. The method named in the `X-HTTP-Method-Override` header, or in a form
+/// field if one is configured, replaces POST before the request is routed.
+/// Unlike other middleware it runs before route matching, install it with
+/// [`Router::method_override`](crate::Router::method_override).
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, middleware::MethodOverride};
+///
+/// async fn delete_post(_req: Request) -> Response {
+///     Response::ok("deleted")
+/// }
+///
+/// let mut router = Router::new();
+/// router.route(Method::DELETE, "/posts/:id", delete_post);
+/// // <form method="post" action="/posts/7"><input type="hidden" name="_method" value="DELETE">
+/// router.method_override(MethodOverride::new().form_field("_method"));
+/// ```
+#[derive(Clone)]
+pub struct MethodOverride {
+    header: Arc<str>,
+    form_field: Option<Arc<str>>,
+    max_body: usize,
+}
+
+impl Default for MethodOverride {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MethodOverride {
+    /// Returns a MethodOverride that reads the `X-HTTP-Method-Override` header.
+    pub fn new() -> Self {
+        MethodOverride { header: "X-HTTP-Method-Override".into(), form_field: None, max_body: 64 * 1024 }
+    }
+
+    /// Sets the header naming the method, `X-HTTP-Method-Override` by default.
+    pub fn header(mut self, name: &str) -> Self {
+        self.header = name.into();
+        self
+    }
+
+    /// Also reads the method from the field `name` of `application/x-www-form-urlencoded` bodies.
+    /// The body is buffered for this and handed on to the handler unchanged.
+    pub fn form_field(mut self, name: &str) -> Self {
+        self.form_field = Some(name.into());
+        self
+    }
+
+    /// Sets the largest form body that is searched for the field, 64 KiB by default.
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Replaces the method of a POST request with the one it asks for, if it's PUT, PATCH or DELETE.
+    pub(crate) async fn apply(&self, req: &mut Request) {
+        if req.method != Method::POST {
+            return;
+        }
+        let mut requested = req
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(&self.header))
+            .map(|(_, v)| v.trim().to_ascii_uppercase());
+        if requested.is_none() && let Some(field) = &self.form_field {
+            requested = self.form_value(req, field).await;
+        }
+        if let Some(method @ ("PUT" | "PATCH" | "DELETE")) = requested.as_deref() {
+            req.method = Method::from(method);
+        }
+    }
+
+    async fn form_value(&self, req: &mut Request, field: &str) -> Option<String> {
+        let is_form = req.headers.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case("content-type")
+                && v.split(';').next().is_some_and(|t| t.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"))
+        });
+        if !is_form || req.body.is_empty() || req.body.len() > self.max_body {
+            return None;
+        }
+        let body = req.body.bytes(self.max_body).await.ok()?;
+        let value = String::from_utf8_lossy(&body)
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == field)
+            .map(|(_, value)| value.trim().to_ascii_uppercase());
+        req.body = Body::from(body);
+        value
+    }
+}
+
+/// Shadows a sample of requests to a second upstream, for trying out a new backend with real traffic.
+/// The copy is sent in the background, its response is read and thrown away, and failures are
+/// ignored, so the primary response is never affected. The request body isn't read ahead of the
+/// handler: a copy of it is kept as the handler reads it, and sent once the handler has read all of
+/// it. Requests whose handler doesn't read the whole body are not mirrored.
+/// Requests with chunked bodies or bodies over the size limit are not mirrored either, and neither
+/// are requests arriving while the limit of copies in flight is reached, so a slow or dead shadow
+/// upstream can't pile up background tasks.
+///
+/// Copies are sent with the [`ReverseProxy`] client, which reuses connections to the shadow upstream.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, middleware::Mirror};
+///
+/// async fn search(_req: Request) -> Response {
+///     Response::ok("results")
+/// }
+///
+/// let mirror = Mirror::new("10.0.0.7:8080").sample(5.0);
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/search", search);
+/// router.middleware(move |req, next| mirror.handle(req, next));
+/// ```
+#[derive(Clone)]
+pub struct Mirror {
+    proxy: ReverseProxy,
+    percent: f64,
+    max_body: usize,
+    timeout: Duration,
+    in_flight: Arc<Semaphore>,
+}
+
+/// Longest shadow response read before the copy is abandoned.
+const MIRROR_MAX_RESPONSE: usize = 1024 * 1024;
+
+impl Mirror {
+    /// Returns a Mirror that copies every request to `upstream`, a `host:port` address.
+    pub fn new(upstream: &str) -> Self {
+        let timeout = Duration::from_secs(10);
+        let proxy = ReverseProxy::new([upstream])
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .buffer_responses(MIRROR_MAX_RESPONSE);
+        Mirror { proxy, percent: 100.0, max_body: 64 * 1024, timeout, in_flight: Arc::new(Semaphore::new(64)) }
+    }
+
+    /// Sets the percentage of requests to mirror, from 0 to 100.
+    pub fn sample(mut self, percent: f64) -> Self {
+        self.percent = percent;
+        self
+    }
+
+    /// Sets the largest request body that is copied for mirroring, 64 KiB by default.
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Sets how long a mirrored request may take before it's abandoned, 10 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.proxy = self.proxy.connect_timeout(timeout).read_timeout(timeout);
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many copies may be in flight at once, 64 by default. Requests arriving while
+    /// that many are still waiting on the shadow upstream are not mirrored.
+    pub fn max_in_flight(mut self, copies: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(copies));
+        self
+    }
+
+    /// Runs `next`, and sends a copy of the request to the shadow upstream if it was sampled.
+    pub fn handle(&self, mut req: Request, next: Handler) -> impl Future<Output = Response> + Send + use<> {
+        let mirror = self.clone();
+        async move {
+            let mirrored = req.stream.is_none() && req.body.len() <= mirror.max_body && sampled(mirror.percent);
+            if mirrored && let Ok(permit) = mirror.in_flight.clone().try_acquire_owned() {
+                // A body still on the connection is copied as the handler reads it.
+                let teed = req.body.tee();
+                let mut copy = req.clone_parts();
+                tokio::spawn(async move {
+                    if let Some(teed) = teed {
+                        let Ok(body) = teed.await else { return };
+                        copy.body = Body::from(body);
+                    }
+                    let _ = tokio::time::timeout(mirror.timeout, mirror.proxy.handle(copy)).await;
+                    drop(permit);
+                });
+            }
+            next(req).await
+        }
+    }
+}
+
+/// Returns true for roughly `percent` out of 100 calls, false if no random bytes are available.
+pub(crate) fn sampled(percent: f64) -> bool {
+    let mut roll = [0u8; 4];
+    if random_bytes(&mut roll).is_err() {
+        return false;
+    }
+    let roll = u32::from_le_bytes(roll) % 10_000;
+    (roll as f64) < percent * 100.0
+}
+
+/// Adds security headers to every response that doesn't set them itself: `X-Content-Type-Options:
+/// nosniff`, `X-Frame-Options: DENY` and `Referrer-Policy: strict-origin-when-cross-origin` by
+/// default, plus a `Content-Security-Policy` if one is given.
+///
+/// A policy containing `{nonce}` gets a fresh nonce per request in its place, which handlers find
+/// as a [`CspNonce`] in `Request.extensions` to mark the inline scripts and styles of the page they
+/// render, so those run without allowing `'unsafe-inline'`.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, middleware::{CspNonce, SecurityHeaders}};
+///
+/// async fn page(req: Request) -> Response {
+///     let nonce = req.extensions.get::<CspNonce>().map(|n| n.as_str().to_string()).unwrap_or_default();
+///     Response::ok(format!("<script nonce=\"{}\">start()</script>", nonce)).header("Content-Type", "text/html")
+/// }
+///
+/// let headers = SecurityHeaders::new().content_security_policy("default-src 'self'; script-src 'self' 'nonce-{nonce}'");
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/", page);
+/// router.global_middleware(move |req, next| headers.handle(req, next));
+/// ```
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    headers: Arc<[(String, String)]>,
+    policy: Option<Arc<str>>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecurityHeaders {
+    /// Returns a SecurityHeaders with the default headers and no `Content-Security-Policy`.
+    pub fn new() -> Self {
+        let headers = [
+            ("X-Content-Type-Options", "nosniff"),
+            ("X-Frame-Options", "DENY"),
+            ("Referrer-Policy", "strict-origin-when-cross-origin"),
+        ];
+        let headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        SecurityHeaders { headers, policy: None }
+    }
+
+    /// Sets the `Content-Security-Policy`, `{nonce}` is replaced with the request's [`CspNonce`].
+    pub fn content_security_policy(mut self, policy: &str) -> Self {
+        self.policy = Some(Arc::from(policy));
+        self
+    }
+
+    /// Adds another header, such as `Strict-Transport-Security`, or replaces the value of one of the
+    /// default headers. An empty value leaves the header out.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        let mut headers: Vec<_> = self.headers.iter().filter(|(k, _)| !k.eq_ignore_ascii_case(name)).cloned().collect();
+        if !value.is_empty() {
+            headers.push((name.to_string(), value.to_string()));
+        }
+        self.headers = headers.into();
+        self
+    }
+
+    /// Adds a [`CspNonce`] to the request's extensions if the policy uses one, runs `next`, then adds
+    /// the headers the response doesn't have. Answers with 500 Internal Server Error if no nonce
+    /// could be generated, since a guessable one would defeat the policy.
+    pub fn handle(&self, mut req: Request, next: Handler) -> impl Future<Output = Response> + Send + use<> {
+        let policy = match &self.policy {
+            Some(policy) if policy.contains("{nonce}") => Some(CspNonce::generate().map(|nonce| {
+                let policy = policy.replace("{nonce}", nonce.as_str());
+                req.extensions.insert(nonce);
+                policy
+            })),
+            policy => policy.as_ref().map(|policy| Ok(policy.to_string())),
+        };
+        let headers = self.headers.clone();
+        async move {
+            let (mut resp, policy) = match policy {
+                Some(Err(e)) => {
+                    eprintln!("error, request: {}, err: can't generate CSP nonce: {}", req.id, e);
+                    (Response::new(StatusCode::InternalServerError), None)
+                }
+                Some(Ok(policy)) => (next(req).await, Some(policy)),
+                None => (next(req).await, None),
+            };
+            let policy = policy.map(|policy| ("Content-Security-Policy".to_string(), policy));
+            for (name, value) in headers.iter().chain(&policy) {
+                let set = resp.headers.as_ref().is_some_and(|headers| headers.keys().any(|k| k.eq_ignore_ascii_case(name)));
+                if !set {
+                    resp = resp.header(name, value);
+                }
+            }
+            resp
+        }
+    }
+}
+
+/// The Content-Security-Policy nonce of a request, added to `Request.extensions` by [`SecurityHeaders`].
+/// Goes in the `nonce` attribute of the page's inline `<script>` and `<style>` elements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    /// Returns 128 random bits, base64url encoded.
+    fn generate() -> std::io::Result<CspNonce> {
+        let mut bytes = [0u8; 16];
+        random_bytes(&mut bytes)?;
+        Ok(CspNonce(base64url_encode(&bytes)))
+    }
+
+    /// Returns the nonce, as it appears in the policy.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
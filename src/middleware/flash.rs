@@ -0,0 +1,120 @@
+use crate::cookie::{Cookie, CookieJar, SameSite};
+use crate::crypto::{base64url_decode, base64url_encode};
+use crate::{Handler, Request, Response};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// One-shot messages for the POST-redirect-GET pattern: a message set while handling one request
+/// is read on the next, typically the page the client was redirected to, and then cleared.
+/// The messages travel in a signed cookie, so clients can read but not forge them.
+/// Handlers reach them through the [`FlashMessages`] in `Request.extensions`.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, StatusCode, cookie::CookieJar, middleware::{Flash, FlashMessages}};
+///
+/// async fn save(req: Request) -> Response {
+///     if let Some(flash) = req.extensions.get::<FlashMessages>() {
+///         flash.push("success", "Settings saved.");
+///     }
+///     Response::new(StatusCode::Custom(303)).header("Location", "/settings")
+/// }
+///
+/// async fn settings(req: Request) -> Response {
+///     let mut page = String::new();
+///     for (level, message) in req.extensions.get::<FlashMessages>().map(|f| f.take()).unwrap_or_default() {
+///         page.push_str(&format!("<p class=\"{}\">{}</p>", level, message));
+///     }
+///     Response::ok(page)
+/// }
+///
+/// let flash = Flash::new(CookieJar::new(b"a long random secret, at least 32 bytes"));
+/// let mut router = Router::new();
+/// router.route(Method::POST, "/settings", save);
+/// router.route(Method::GET, "/settings", settings);
+/// router.global_middleware(move |req, next| flash.handle(req, next));
+/// ```
+#[derive(Clone)]
+pub struct Flash {
+    jar: CookieJar,
+    cookie: Arc<str>,
+}
+
+impl Flash {
+    /// Returns a Flash signing its `_flash` cookie with `jar`.
+    pub fn new(jar: CookieJar) -> Self {
+        Flash { jar, cookie: Arc::from("_flash") }
+    }
+
+    /// Sets the name of the cookie carrying the messages.
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie = Arc::from(name);
+        self
+    }
+
+    /// Adds the messages set on the previous response to the request's extensions, runs `next`,
+    /// then sets the cookie if new messages were pushed or removes it once the old ones were taken.
+    pub fn handle(&self, mut req: Request, next: Handler) -> impl Future<Output = Response> + Send + use<> {
+        let incoming = self.jar.get_signed(&req, &self.cookie).map(|value| decode_flash(&value)).unwrap_or_default();
+        let had_messages = !incoming.is_empty();
+        let messages = FlashMessages {
+            state: Arc::new(Mutex::new(FlashState { incoming, outgoing: Vec::new(), taken: false })),
+        };
+        req.extensions.insert(messages.clone());
+        let flash = self.clone();
+        async move {
+            let resp = next(req).await;
+            let Ok(state) = messages.state.lock() else { return resp };
+            let cookie = if !state.outgoing.is_empty() {
+                flash.jar.signed(Cookie::new(&flash.cookie, &encode_flash(&state.outgoing)))
+            } else if had_messages && state.taken {
+                Cookie::removal(&flash.cookie)
+            } else {
+                return resp;
+            };
+            resp.cookie(cookie.path("/").http_only(true).same_site(SameSite::Lax))
+        }
+    }
+}
+
+/// The flash messages of a request, added to `Request.extensions` by [`Flash`].
+/// Messages are `(level, message)` pairs, the level being something like `"success"` or `"error"`.
+/// Clones share the same messages.
+#[derive(Clone)]
+pub struct FlashMessages {
+    state: Arc<Mutex<FlashState>>,
+}
+
+struct FlashState {
+    incoming: Vec<(String, String)>,
+    outgoing: Vec<(String, String)>,
+    taken: bool,
+}
+
+impl FlashMessages {
+    /// Returns the messages set on the previous response. They are cleared once this response
+    /// is sent, so later calls, and later requests, get none.
+    pub fn take(&self) -> Vec<(String, String)> {
+        let Ok(mut state) = self.state.lock() else { return Vec::new() };
+        state.taken = true;
+        std::mem::take(&mut state.incoming)
+    }
+
+    /// Adds a message for the next request.
+    pub fn push(&self, level: &str, message: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.outgoing.push((level.to_string(), message.to_string()));
+        }
+    }
+}
+
+/// Encodes messages as base64url JSON, `[["level","message"],...]`, which is safe in a cookie value.
+fn encode_flash(messages: &[(String, String)]) -> String {
+    base64url_encode(serde_json::to_string(messages).unwrap_or_default().as_bytes())
+}
+
+fn decode_flash(value: &str) -> Vec<(String, String)> {
+    base64url_decode(value)
+        .and_then(|json| serde_json::from_slice::<Vec<(String, String)>>(&json).ok())
+        .unwrap_or_default()
+}
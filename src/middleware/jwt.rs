@@ -0,0 +1,190 @@
+use crate::crypto::{base64url_decode, constant_time_eq, hmac_sha256, rsa_sha256_verify};
+use crate::{Handler, Request, Response, StatusCode};
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Claims of a validated JWT, stored in `Request.extensions` by [`Jwt`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims(pub Value);
+
+impl Claims {
+    /// Returns the claim named `name`.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// Returns the `sub` (subject) claim.
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub").and_then(Value::as_str)
+    }
+}
+
+#[derive(Clone)]
+enum Key {
+    Hmac(Arc<[u8]>),
+    Rsa { modulus: Arc<[u8]>, exponent: Arc<[u8]> },
+}
+
+/// Validates `Authorization: Bearer` JSON Web Tokens signed with HS256 or RS256.
+/// Checks the signature, `exp` and `nbf`, and `aud`/`iss` when configured, then stores the
+/// [`Claims`] in `Request.extensions`. Requests without a valid token get 401 Unauthorized
+/// with a `WWW-Authenticate` header. Only the algorithm of the configured key is accepted.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, middleware::{Claims, Jwt}};
+///
+/// async fn me(req: Request) -> Response {
+///     let sub = req.extensions.get::<Claims>().and_then(|c| c.subject()).unwrap_or_default();
+///     Response::ok(sub.to_string())
+/// }
+///
+/// let jwt = Jwt::hs256(b"secret").audience("api").issuer("https://auth.example.com");
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/me", me);
+/// router.middleware(move |req, next| jwt.handle(req, next));
+/// ```
+#[derive(Clone)]
+pub struct Jwt {
+    key: Key,
+    audience: Option<String>,
+    issuer: Option<String>,
+    leeway: u64,
+}
+
+impl Jwt {
+    /// Returns a validator for tokens signed with HMAC-SHA256 using `secret`.
+    pub fn hs256(secret: &[u8]) -> Self {
+        Jwt::with_key(Key::Hmac(secret.into()))
+    }
+
+    /// Returns a validator for tokens signed with RSA-SHA256.
+    /// `modulus` and `exponent` are the big-endian public key components.
+    /// Tokens are rejected if the modulus is shorter than 2048 bits.
+    pub fn rs256(modulus: &[u8], exponent: &[u8]) -> Self {
+        Jwt::with_key(Key::Rsa { modulus: modulus.into(), exponent: exponent.into() })
+    }
+
+    /// Like [`Jwt::rs256`], but takes the base64url encoded `n` and `e` members of a JWK.
+    /// Returns `None` if either is not valid base64url.
+    pub fn rs256_jwk(n: &str, e: &str) -> Option<Self> {
+        Some(Jwt::rs256(&base64url_decode(n)?, &base64url_decode(e)?))
+    }
+
+    fn with_key(key: Key) -> Self {
+        Jwt { key, audience: None, issuer: None, leeway: 0 }
+    }
+
+    /// Requires the `aud` claim to be, or contain, `audience`.
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Requires the `iss` claim to equal `issuer`.
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Sets the clock skew in seconds tolerated when checking `exp` and `nbf`. Defaults to 0.
+    pub fn leeway(mut self, secs: u64) -> Self {
+        self.leeway = secs;
+        self
+    }
+
+    /// Validates `token` and returns its claims, or a short description of why it was refused.
+    pub fn verify(&self, token: &str) -> Result<Claims, &'static str> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed token");
+        };
+        let signed = &token[..header.len() + 1 + payload.len()];
+        let header = decode_json(header).ok_or("malformed header")?;
+        let signature = base64url_decode(signature).ok_or("malformed signature")?;
+
+        let valid = match (&self.key, header.get("alg").and_then(Value::as_str)) {
+            (Key::Hmac(secret), Some("HS256")) => {
+                constant_time_eq(&hmac_sha256(secret, signed.as_bytes()), &signature)
+            }
+            (Key::Rsa { modulus, exponent }, Some("RS256")) => {
+                rsa_sha256_verify(modulus, exponent, signed.as_bytes(), &signature)
+            }
+            _ => return Err("unsupported algorithm"),
+        };
+        if !valid {
+            return Err("invalid signature");
+        }
+
+        let claims = decode_json(payload).ok_or("malformed claims")?;
+        if !matches!(claims, Value::Object(_)) {
+            return Err("malformed claims");
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as f64;
+        let leeway = self.leeway as f64;
+        if let Some(exp) = claims.get("exp")
+            && exp.as_f64().is_none_or(|exp| now > exp + leeway)
+        {
+            return Err("token expired");
+        }
+        if let Some(nbf) = claims.get("nbf")
+            && nbf.as_f64().is_none_or(|nbf| now + leeway < nbf)
+        {
+            return Err("token not yet valid");
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("invalid audience");
+            }
+        }
+        if let Some(issuer) = &self.issuer
+            && claims.get("iss").and_then(Value::as_str) != Some(issuer)
+        {
+            return Err("invalid issuer");
+        }
+        Ok(Claims(claims))
+    }
+
+    /// Runs `next` with the claims in `req.extensions` if the request carries a valid token,
+    /// otherwise returns a 401 response.
+    pub fn handle(&self, mut req: Request, next: Handler) -> impl Future<Output = Response> + Send + use<> {
+        let token = req
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+            .and_then(|(_, v)| v.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim());
+        let verified = match token {
+            None => Err(None),
+            Some(token) => self.verify(token).map_err(Some),
+        };
+        async move {
+            match verified {
+                Ok(claims) => {
+                    req.extensions.insert(claims);
+                    next(req).await
+                }
+                Err(None) => Response::new(StatusCode::Unauthorized).header("WWW-Authenticate", "Bearer"),
+                Err(Some(reason)) => Response::new(StatusCode::Unauthorized).header(
+                    "WWW-Authenticate",
+                    &format!("Bearer error=\"invalid_token\", error_description=\"{}\"", reason),
+                ),
+            }
+        }
+    }
+}
+
+fn decode_json(part: &str) -> Option<Value> {
+    let bytes = base64url_decode(part)?;
+    serde_json::from_slice(&bytes).ok()
+}
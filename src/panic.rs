@@ -0,0 +1,79 @@
+//! Capturing what's known about a panicking handler, for [`Server::on_panic`](crate::Server::on_panic).
+
+use crate::Request;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Once};
+use std::task::{Context, Poll};
+
+/// Where the backtrace of a panic is stored, shared between a handler task and the connection.
+pub(crate) type BacktraceSlot = Arc<Mutex<Option<Backtrace>>>;
+
+thread_local! {
+    /// Slot of the handler being polled on this thread, if it asked for backtraces.
+    static CURRENT: RefCell<Option<BacktraceSlot>> = const { RefCell::new(None) };
+}
+
+/// Details of a handler panic, passed to the callback set with [`Server::on_panic`](crate::Server::on_panic).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PanicReport {
+    /// The panic message, or "unknown panic payload" if the payload wasn't a string.
+    pub message: String,
+    /// Where the handler panicked. Captured whatever `RUST_BACKTRACE` is set to.
+    pub backtrace: Backtrace,
+    /// The request being handled, with the parts [`Request::clone_parts`] keeps.
+    pub request: Request,
+}
+
+/// Installs the panic hook that records backtraces for handlers wrapped in [`CaptureBacktrace`].
+/// Panics elsewhere are left to the previous hook alone, which also still runs for handlers.
+pub(crate) fn install_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let slot = CURRENT.try_with(|current| current.borrow().clone()).ok().flatten();
+            if let Some(slot) = slot
+                && let Ok(mut backtrace) = slot.lock()
+            {
+                *backtrace = Some(Backtrace::force_capture());
+            }
+            previous(info);
+        }));
+    });
+}
+
+/// Future that makes the panic hook store a backtrace in `slot` if polling `inner` panics.
+pub(crate) struct CaptureBacktrace<F> {
+    inner: Pin<Box<F>>,
+    slot: BacktraceSlot,
+}
+
+impl<F> CaptureBacktrace<F> {
+    pub(crate) fn new(inner: F, slot: BacktraceSlot) -> Self {
+        CaptureBacktrace { inner: Box::pin(inner), slot }
+    }
+}
+
+/// Clears the current slot once a poll returns or unwinds.
+struct ClearCurrent;
+
+impl Drop for ClearCurrent {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| current.borrow_mut().take());
+    }
+}
+
+impl<F: Future> Future for CaptureBacktrace<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let slot = self.slot.clone();
+        CURRENT.with(|current| *current.borrow_mut() = Some(slot));
+        let _clear = ClearCurrent;
+        self.inner.as_mut().poll(cx)
+    }
+}
@@ -0,0 +1,167 @@
+//! zep's HTTP/1.1 request parser and response serializer, for building proxies, test fixtures
+//! and custom transports on top of the same code the [`Server`](crate::Server) uses.
+
+use crate::server::{self, Config, ConnReader};
+use crate::{Request, Response, StatusCode};
+pub use crate::types::RequestHead;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+/// Reads a request head from `reader` and returns the Request.
+/// The body isn't read up front: the returned Request keeps `reader` and reads the body from it
+/// on demand, like requests handled by the Server. `remote_addr` becomes `Request.remote_addr`.
+///
+/// Malformed requests fail with an `InvalidData` error carrying a [`ParseError`].
+///
+/// # Example:
+/// ```
+/// use zep::{tokio, Method, proto};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let raw: &'static [u8] = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+///     let mut req = proto::parse_request(raw, ([127, 0, 0, 1], 0).into()).await.unwrap();
+///     assert_eq!(req.method, Method::POST);
+///     assert_eq!(req.bytes(1024).await.unwrap(), b"hello");
+/// }
+/// ```
+pub async fn parse_request<R>(reader: R, remote_addr: SocketAddr) -> std::io::Result<Request>
+where
+    R: AsyncRead + Unpin + Send + Sync + 'static,
+{
+    let reader: ConnReader = BufReader::new(Box::new(reader));
+    let (req, _) = server::parse_request(remote_addr, Arc::new(Mutex::new(reader)), &Default::default(), &Config::default()).await?;
+    Ok(req)
+}
+
+/// Parses the request head at the start of `buf` without any I/O, applying the same checks as
+/// [`parse_request`]. Empty lines before the request line are skipped, like the Server does.
+/// Heads longer than 16 KiB are rejected, so the work and memory spent on any input is bounded,
+/// which makes this a suitable target for fuzzing and property tests.
+///
+/// # Example:
+/// ```
+/// use zep::{Method, proto};
+///
+/// let buf = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+/// let head = proto::parse_head(buf).unwrap();
+/// assert_eq!(head.method, Method::POST);
+/// assert_eq!(head.content_length, Some(5));
+/// assert_eq!(&buf[head.len..], b"hello");
+///
+/// assert_eq!(proto::parse_head(b"GET / HTTP/1.1\r\nHost: a").err(), Some(proto::ParseError::Incomplete));
+/// ```
+pub fn parse_head(buf: &[u8]) -> Result<RequestHead, ParseError> {
+    let range = server::find_head(buf)?;
+    let mut head = server::parse_head(&buf[range.clone()], &Config::default())?;
+    head.len = range.end;
+    Ok(head)
+}
+
+/// Why a request couldn't be parsed.
+/// Malformed requests read by [`parse_request`] or the Server, and malformed chunked bodies
+/// read through a [`StreamReader`](crate::StreamReader), fail with an `std::io::Error` carrying
+/// this type, which can be recovered with `get_ref` and `downcast_ref`.
+///
+/// # Example:
+/// ```
+/// use zep::{StatusCode, proto::{self, ParseError}};
+///
+/// // A proxy reading from a client decides whether to wait or to answer.
+/// match proto::parse_head(b"GET / HTTP/1.1\r\nHost: exam") {
+///     Err(ParseError::Incomplete) => { /* read more bytes and try again */ }
+///     Err(e) => assert!(e.status().is_some()),
+///     Ok(head) => println!("{} {}", head.method, head.path),
+/// }
+/// assert_eq!(ParseError::HeadersTooLarge.status(), Some(StatusCode::RequestHeaderFieldsTooLarge));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// The input ends before the head does, more bytes are needed.
+    Incomplete,
+    /// The client didn't finish sending the head within the read timeout.
+    TimedOut,
+    /// The request line alone exceeds the head size limit.
+    RequestLineTooLong,
+    /// The request line and headers together exceed the head size limit.
+    HeadersTooLarge,
+    /// The request target is longer, or has more path segments, than the server allows.
+    UriTooLong,
+    /// The Host header isn't one of the server's allowed hosts.
+    HostNotAllowed,
+    /// The request line or a header is malformed, contains the reason.
+    Malformed(&'static str),
+    /// The framing of a chunked body is malformed, contains the reason.
+    InvalidChunk(&'static str),
+}
+
+impl ParseError {
+    /// Returns the status the request should be answered with, or `None` for [`ParseError::Incomplete`].
+    pub fn status(&self) -> Option<StatusCode> {
+        Some(match self {
+            ParseError::Incomplete => return None,
+            ParseError::TimedOut => StatusCode::RequestTimeout,
+            ParseError::RequestLineTooLong | ParseError::UriTooLong => StatusCode::URITooLong,
+            ParseError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::HostNotAllowed => StatusCode::MisdirectedRequest,
+            ParseError::Malformed(_) | ParseError::InvalidChunk(_) => StatusCode::BadRequest,
+        })
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => f.write_str("Incomplete request head"),
+            ParseError::TimedOut => f.write_str("Request head timed out"),
+            ParseError::RequestLineTooLong => f.write_str("Request line too long"),
+            ParseError::HeadersTooLarge => f.write_str("Headers too large"),
+            ParseError::UriTooLong => f.write_str("Request target too long"),
+            ParseError::HostNotAllowed => f.write_str("Host not allowed"),
+            ParseError::Malformed(reason) | ParseError::InvalidChunk(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for std::io::Error {
+    fn from(e: ParseError) -> Self {
+        let kind = match e {
+            ParseError::Incomplete => std::io::ErrorKind::UnexpectedEof,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+/// Writes `resp` to `writer` as an HTTP/1.1 response.
+/// A streamed body (`Response.stream`) is taken out of the response and written to the end.
+/// Write errors are returned as they are, including `BrokenPipe` or `ConnectionReset` from a
+/// client that went away halfway, so the caller decides whether that's worth reporting.
+///
+/// # Example:
+/// ```
+/// use zep::{tokio, Response, proto};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let mut out = Vec::new();
+///     proto::serialize_response(&mut Response::ok("hi"), &mut out).await.unwrap();
+///     assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+/// }
+/// ```
+pub async fn serialize_response<W>(resp: &mut Response, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&server::serialize_response(resp)).await?;
+    if let Some(stream) = resp.stream.take() {
+        server::stream_resp(writer, stream).await?;
+    }
+    writer.flush().await
+}
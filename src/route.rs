@@ -1,4 +1,10 @@
-use crate::types::{Method, ParamMap, Request, Response};
+use crate::middleware::{sampled, MethodOverride};
+use crate::serve::ForwardProxy;
+use crate::proto::ParseError;
+use crate::types::{Method, ParamMap, Request, Response, StatusCode};
+use crate::Body;
+use std::fmt;
+use std::io::ErrorKind;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -8,8 +14,15 @@ use std::sync::Arc;
 /// `async fn handler(Request) -> Response`
 pub type Handler =
     Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
-type Middleware =
+pub(crate) type Middleware =
     Arc<dyn Fn(Request, Handler) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
+pub(crate) type Predicate = Arc<dyn Fn(&Request) -> bool + Send + Sync>;
+
+/// Put in a request's extensions by the server to learn which route pattern it matched.
+#[derive(Clone, Default)]
+pub(crate) struct RouteSlot(pub(crate) Arc<std::sync::Mutex<Option<String>>>);
+type ErrorHandler =
+    Arc<dyn Fn(StatusCode, Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
 //type Logger = Arc<dyn Fn(&Request) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -23,14 +36,189 @@ struct Route {
     method: Method,
     segments: Arc<[RouteSegment]>,
     handler: Handler,
-    middleware: Option<Middleware>,
+    variants: Vec<(Predicate, Handler)>,
+    host: Option<Arc<[RouteSegment]>>,
+    middleware: Vec<Middleware>,
+    body: BodyRules,
+    headers: Vec<(String, String)>,
+}
+
+impl Route {
+    /// Returns true if both routes would match exactly the same requests.
+    fn conflicts_with(&self, other: &Route) -> bool {
+        self.method == other.method
+            && same_shape(&self.segments, &other.segments)
+            && match (&self.host, &other.host) {
+                (Some(a), Some(b)) => same_shape(a, b),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+/// Compares segment lists, parameters match each other whatever their names.
+fn same_shape(a: &[RouteSegment], b: &[RouteSegment]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|pair| match pair {
+            (RouteSegment::Static(a), RouteSegment::Static(b)) => a == b,
+            (RouteSegment::Param(_), RouteSegment::Param(_)) => true,
+            _ => false,
+        })
+}
+
+fn display_segments(segments: &[RouteSegment]) -> String {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            RouteSegment::Static(s) => s.to_string(),
+            RouteSegment::Param(name) => format!(":{}", name),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Body format of the error responses the router and server generate themselves, such as
+/// 404 Not Found for unmatched requests or 400 Bad Request for malformed ones,
+/// see [`Router::error_format`]. Responses returned by handlers are never changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorFormat {
+    /// The status line as plain text, e.g. `404 Not Found`.
+    #[default]
+    Text,
+    /// A JSON object, e.g. `{"error":"Not Found","status":404}`, for API-only services.
+    Json,
+}
+
+/// Why [`Router::try_merge`] refused to merge two routers: part of one could never be reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MergeError {
+    /// Both routers have a route for the same method, path pattern and host, contains the
+    /// route, e.g. `GET /users/:id`.
+    DuplicateRoute(String),
+    /// Both routers have a forward proxy.
+    DuplicateProxy,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::DuplicateRoute(route) => write!(f, "duplicate route {}", route),
+            MergeError::DuplicateProxy => f.write_str("both routers have a forward proxy"),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Which request bodies a route accepts, see [`Router::max_body`] and [`Router::accept`].
+#[derive(Clone, Default)]
+struct BodyRules {
+    max_size: Option<usize>,
+    content_types: Option<Arc<[String]>>,
+}
+
+impl BodyRules {
+    /// Returns these rules, with the ones that aren't set taken from `defaults`.
+    fn or(&self, defaults: &BodyRules) -> BodyRules {
+        BodyRules {
+            max_size: self.max_size.or(defaults.max_size),
+            content_types: self.content_types.clone().or_else(|| defaults.content_types.clone()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.max_size.is_none() && self.content_types.is_none()
+    }
+
+    /// Checks the body of `req`, returns the status to reject it with if it breaks the rules.
+    /// A chunked body is read into memory to learn its size.
+    async fn check(&self, req: &mut Request) -> Option<StatusCode> {
+        let has_body = req.stream.is_some() || !req.body.is_empty();
+        if let Some(types) = &self.content_types
+            && has_body
+        {
+            let content_type = req.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("content-type")).map(|(_, v)| v);
+            let media_type = content_type.and_then(|v| v.split(';').next()).unwrap_or_default().trim();
+            if !types.iter().any(|t| t.eq_ignore_ascii_case(media_type)) {
+                return Some(StatusCode::UnsupportedMediaType);
+            }
+        }
+        if let Some(max) = self.max_size {
+            if req.body.len() > max {
+                return Some(StatusCode::PayloadTooLarge);
+            }
+            if let Some(stream) = req.stream.take() {
+                match stream.collect(max).await {
+                    Ok(data) => req.body = Body::from(data),
+                    Err(e) if e.get_ref().is_some_and(|e| e.is::<ParseError>()) => return Some(StatusCode::BadRequest),
+                    Err(e) if e.kind() == ErrorKind::InvalidData => return Some(StatusCode::PayloadTooLarge),
+                    Err(_) => return Some(StatusCode::BadRequest),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Wraps `handler` so requests whose body breaks `rules` get an error response instead.
+/// A 415 response lists the accepted media types in `Accept-Patch` for PATCH requests
+/// (RFC 5789 section 3.1) and `Accept-Post` for others.
+fn guard_body(rules: BodyRules, errors: Arc<ErrorHandlers>, handler: Handler) -> Handler {
+    Arc::new(move |mut req| {
+        let (rules, errors, handler) = (rules.clone(), errors.clone(), handler.clone());
+        Box::pin(async move {
+            let status = match rules.check(&mut req).await {
+                Some(status) => status,
+                None => return handler(req).await,
+            };
+            let hint = if matches!(&req.method, Method::Other(m) if m == "PATCH") { "Accept-Patch" } else { "Accept-Post" };
+            let resp = errors.respond(status.clone(), req).await;
+            match (status, &rules.content_types) {
+                (StatusCode::UnsupportedMediaType, Some(types)) => resp.header(hint, &types.join(", ")),
+                _ => resp,
+            }
+        })
+    })
+}
+
+/// How the router renders the errors it and the server generate, see [`Router::on_error`].
+#[derive(Clone, Default)]
+struct ErrorHandlers {
+    format: ErrorFormat,
+    by_status: Vec<(StatusCode, ErrorHandler)>,
+    fallback: Option<ErrorHandler>,
+}
+
+impl ErrorHandlers {
+    fn handler(&self, status: &StatusCode) -> Option<&ErrorHandler> {
+        self.by_status
+            .iter()
+            .find(|(s, _)| s == status)
+            .map(|(_, handler)| handler)
+            .or(self.fallback.as_ref())
+    }
+
+    async fn respond(&self, status: StatusCode, req: Request) -> Response {
+        match self.handler(&status) {
+            Some(handler) => handler(status, req).await,
+            None => error_response(self.format, status),
+        }
+    }
 }
 
 /// Router struct, contains routes and the methods needed to route requests to them.
 #[derive(Clone)]
 pub struct Router {
     routes: Vec<Route>,
-    //global_middleware: Option<Middleware>,
+    global_middleware: Vec<Middleware>,
+    capabilities: Vec<(String, String)>,
+    proxy: Option<ForwardProxy>,
+    method_override: Option<MethodOverride>,
+    trace: bool,
+    errors: Arc<ErrorHandlers>,
+    body_defaults: BodyRules,
+    default_headers: Vec<(String, String)>,
 }
 
 impl Default for Router {
@@ -44,7 +232,14 @@ impl Router {
     pub fn new() -> Self {
         Router {
             routes: Vec::new(),
-            //global_middleware: None,
+            global_middleware: Vec::new(),
+            capabilities: Vec::new(),
+            proxy: None,
+            method_override: None,
+            trace: false,
+            errors: Arc::new(ErrorHandlers::default()),
+            body_defaults: BodyRules::default(),
+            default_headers: Vec::new(),
         }
     }
 
@@ -72,33 +267,161 @@ impl Router {
         Fut: Future<Output = Response> + Send + 'static,
     {
         let handler: Handler = Arc::new(move |req| Box::pin(handler(req)));
+        self.route_handler(method, path, handler);
+    }
+
+    /// Like [`Router::route`], but for handlers that block, such as synchronous database clients or
+    /// image processing. The handler runs on tokio's blocking thread pool through
+    /// [`spawn_blocking`](tokio::task::spawn_blocking), so it doesn't hold up other requests.
+    /// A body still on the connection can be read with
+    /// `tokio::runtime::Handle::current().block_on(req.bytes(limit))`.
+    /// A panic is passed on, so it's answered with 500 like in any other handler.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// fn thumbnail(req: Request) -> Response {
+    ///     let id = req.params.get("id").cloned().unwrap_or_default();
+    ///     std::thread::sleep(std::time::Duration::from_millis(10)); // Decoding, resizing...
+    ///     Response::ok(format!("thumbnail of {}", id))
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route_blocking(Method::GET, "/images/:id/thumbnail", thumbnail);
+    /// ```
+    pub fn route_blocking<F>(&mut self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.route(method, path, move |req| {
+            let handler = handler.clone();
+            async move {
+                match tokio::task::spawn_blocking(move || handler(req)).await {
+                    Ok(resp) => resp,
+                    Err(e) => std::panic::resume_unwind(e.into_panic()),
+                }
+            }
+        });
+    }
+
+    pub(crate) fn route_handler(&mut self, method: Method, path: &str, handler: Handler) {
         self.routes.push(Route {
             method,
             //path: Arc::from(path),
             segments: parse_route(path),
             handler,
-            middleware: None,
+            variants: Vec::new(),
+            host: None,
+            middleware: Vec::new(),
+            body: BodyRules::default(),
+            headers: Vec::new(),
         });
     }
 
-    pub(crate) async fn handle_request(&self, mut req: Request) -> Response {
+    /// Routes `req` to its handler through the middleware and returns the response, without a Server.
+    /// Unmatched requests get the router's 404 response. Server options such as host validation
+    /// and `on_panic` don't apply, and a panicking handler panics here.
+    /// Useful for tests and for running the app on other runtimes, such as serverless functions.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{tokio, Router, Method, Request, Response, StatusCode};
+    ///
+    /// async fn hello(_req: Request) -> Response {
+    ///     Response::ok("hello")
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut router = Router::new();
+    ///     router.route(Method::GET, "/", hello);
+    ///
+    ///     let req = Request { path: "/".to_string(), ..Default::default() };
+    ///     assert_eq!(router.handle_request(req).await.body.unwrap(), b"hello");
+    ///     let req = Request { path: "/missing".to_string(), ..Default::default() };
+    ///     assert_eq!(router.handle_request(req).await.status_code, StatusCode::NotFound);
+    /// }
+    /// ```
+    pub async fn handle_request(&self, req: Request) -> Response {
         /*if let Some(logger) = &self.logger {
             logger(&req).await;
         }*/
+        if let Some(proxy) = &self.proxy
+            && ForwardProxy::is_proxy_request(&req)
+        {
+            return proxy.handle(req).await;
+        }
+        let (mut resp, route_headers) = self.route_request(req).await;
+        // Headers the handler or middleware set win, the route's own defaults over the router's.
+        for (name, value) in route_headers.iter().chain(&self.default_headers) {
+            let set = resp.headers.as_ref().is_some_and(|headers| headers.keys().any(|k| k.eq_ignore_ascii_case(name)));
+            if !set {
+                resp = resp.header(name, value);
+            }
+        }
+        resp
+    }
+
+    /// Answers `req` from its route, returning the response with the route's default headers.
+    async fn route_request(&self, mut req: Request) -> (Response, &[(String, String)]) {
+        if let Some(method_override) = &self.method_override {
+            method_override.apply(&mut req).await;
+        }
+        if req.method == Method::OPTIONS && req.path == "*" {
+            return (self.server_options(), &[]);
+        }
+        if self.trace && is_trace(&req.method) {
+            return (trace_echo(&req), &[]);
+        }
         for route in &self.routes {
             if route.method == req.method
-                && let Some(params) = match_route(route.segments.clone(), &req.path)
+                && let Some(mut params) = match_route(route.segments.clone(), &req.path)
             {
+                if let Some(host) = &route.host {
+                    match request_host(&req).and_then(|h| match_host(host, h)) {
+                        Some(host_params) => params.extend(host_params),
+                        None => continue,
+                    }
+                }
                 req.params = params;
-
-                if let Some(middleware) = route.middleware.clone() {
-                    return middleware(req, route.handler.clone()).await;
+                if let Some(RouteSlot(slot)) = req.extensions.get::<RouteSlot>()
+                    && let Ok(mut slot) = slot.lock()
+                {
+                    *slot = Some(route_pattern(&route.segments));
+                }
+                let handler = route
+                    .variants
+                    .iter()
+                    .find(|(predicate, _)| predicate(&req))
+                    .map_or(&route.handler, |(_, handler)| handler);
+                // Body checks run inside the middleware, so e.g. authentication still comes first.
+                let rules = route.body.or(&self.body_defaults);
+                let guarded;
+                let handler = if rules.is_empty() {
+                    handler
                 } else {
-                    return (route.handler)(req).await;
+                    guarded = guard_body(rules, self.errors.clone(), handler.clone());
+                    &guarded
+                };
+
+                if self.global_middleware.is_empty() && route.middleware.is_empty() {
+                    return (handler(req).await, &route.headers);
                 }
+                let layers = self.global_middleware.iter().chain(&route.middleware);
+                return (layered(layers, handler.clone())(req).await, &route.headers);
             }
         }
-        Response::not_found()
+        if self.global_middleware.is_empty() {
+            return (self.errors.respond(StatusCode::NotFound, req).await, &[]);
+        }
+        let errors = self.errors.clone();
+        let not_found: Handler = Arc::new(move |req| {
+            let errors = errors.clone();
+            Box::pin(async move { errors.respond(StatusCode::NotFound, req).await })
+        });
+        (layered(self.global_middleware.iter(), not_found)(req).await, &[])
     }
 
     /*
@@ -111,10 +434,434 @@ impl Router {
         self.logger = Some(logger);
     }*/
 
+    /// Appends the routes of `other`, so an app can be assembled from routers built in separate modules.
+    /// Route order is kept, `self`'s routes are matched first.
+    /// Global middleware of `other` keeps applying to its own routes, inside the global middleware of `self`.
+    ///
+    /// # Panics
+    /// Panics if both routers have a route for the same method, path pattern and host, or both
+    /// have a forward proxy, since one of them could never be reached. Use [`Router::try_merge`]
+    /// to handle that as an error instead.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn list_users(_req: Request) -> Response {
+    ///     Response::ok("[]")
+    /// }
+    ///
+    /// fn users() -> Router {
+    ///     let mut router = Router::new();
+    ///     router.route(Method::GET, "/users", list_users);
+    ///     router
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.merge(users());
+    /// ```
+    pub fn merge(&mut self, other: Router) {
+        if let Err(e) = self.try_merge(other) {
+            panic!("Router::merge: {}", e);
+        }
+    }
+
+    /// Appends the routes of `other` like [`Router::merge`], but returns an error instead of
+    /// panicking if part of either router could never be reached. `self` is unchanged then.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{MergeError, Router, Method, Request, Response};
+    ///
+    /// async fn list_users(_req: Request) -> Response {
+    ///     Response::ok("[]")
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route(Method::GET, "/users", list_users);
+    /// let mut plugin = Router::new();
+    /// plugin.route(Method::GET, "/users", list_users);
+    /// assert_eq!(router.try_merge(plugin), Err(MergeError::DuplicateRoute("GET /users".to_string())));
+    /// ```
+    pub fn try_merge(&mut self, other: Router) -> Result<(), MergeError> {
+        for route in &other.routes {
+            if let Some(existing) = self.routes.iter().find(|r| r.conflicts_with(route)) {
+                return Err(MergeError::DuplicateRoute(format!(
+                    "{} /{}",
+                    existing.method,
+                    display_segments(&existing.segments)
+                )));
+            }
+        }
+        if self.proxy.is_some() && other.proxy.is_some() {
+            return Err(MergeError::DuplicateProxy);
+        }
+        let other_global = other.global_middleware;
+        let other_body = other.body_defaults;
+        let other_headers = other.default_headers;
+        self.routes.extend(other.routes.into_iter().map(|mut route| {
+            route.middleware.splice(0..0, other_global.iter().cloned());
+            route.body = route.body.or(&other_body);
+            for (name, value) in &other_headers {
+                set_header(&mut route.headers, name, value, false);
+            }
+            route
+        }));
+        self.proxy = self.proxy.take().or(other.proxy);
+        self.method_override = self.method_override.take().or(other.method_override);
+        self.trace |= other.trace;
+        let errors = Arc::make_mut(&mut self.errors);
+        for (status, handler) in &other.errors.by_status {
+            if !errors.by_status.iter().any(|(s, _)| s == status) {
+                errors.by_status.push((status.clone(), handler.clone()));
+            }
+        }
+        if errors.fallback.is_none() {
+            errors.fallback = other.errors.fallback.clone();
+        }
+        self.capabilities.extend(other.capabilities);
+        Ok(())
+    }
+
+    /// Adds a header to the response to `OPTIONS *`, to advertise a server-wide capability.
+    ///
+    /// `OPTIONS *` asks about the server as a whole rather than a resource, so it's answered before
+    /// route matching with `200 OK`, an `Allow` header listing every method some route accepts,
+    /// and the headers added here.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::Router;
+    ///
+    /// let mut router = Router::new();
+    /// router.capability("Accept-Patch", "application/merge-patch+json");
+    /// ```
+    pub fn capability(&mut self, key: &str, value: &str) {
+        self.capabilities.push((key.to_string(), value.to_string()));
+    }
+
+    fn server_options(&self) -> Response {
+        let mut methods = vec![Method::OPTIONS];
+        if self.trace {
+            methods.push(Method::Other("TRACE".to_string()));
+        }
+        for route in &self.routes {
+            if !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+            }
+        }
+        let allow = methods.iter().map(Method::to_string).collect::<Vec<_>>().join(", ");
+        let mut resp = Response::new(StatusCode::Ok).header("Allow", &allow).header("Content-Length", "0");
+        for (key, value) in &self.capabilities {
+            resp = resp.append_header(key, value);
+        }
+        resp
+    }
+
+    /// Turns the router into a forward proxy.
+    /// CONNECT requests and requests with an absolute-form target (`GET http://host/path`)
+    /// are handed to the proxy before route matching, every other request is routed as usual.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, serve::ForwardProxy};
+    ///
+    /// let mut router = Router::new();
+    /// router.forward_proxy(ForwardProxy::new().allow(|req| req.path.contains("internal.example")));
+    /// ```
+    pub fn forward_proxy(&mut self, proxy: ForwardProxy) {
+        self.proxy = Some(proxy);
+    }
+
+    /// Sets the body format of the error responses generated by the router and the server.
+    /// Plain text by default.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{ErrorFormat, Router};
+    ///
+    /// let mut router = Router::new();
+    /// router.error_format(ErrorFormat::Json);
+    /// ```
+    pub fn error_format(&mut self, format: ErrorFormat) {
+        Arc::make_mut(&mut self.errors).format = format;
+    }
+
+    /// Renders the errors with `status` that the router and server generate with `handler`
+    /// instead, e.g. a custom 404 page. These are 404 Not Found for unmatched requests,
+    /// 500 Internal Server Error for handlers that panicked, and 400, 408, 414, 421 and 431 for
+    /// requests the server refused to parse. The handler receives the status and the request,
+    /// which after a panic only has the parts [`Request::clone_parts`] keeps, and for refused
+    /// requests is empty apart from `remote_addr`. Its response is sent as is.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Request, Response, StatusCode};
+    ///
+    /// async fn not_found(status: StatusCode, req: Request) -> Response {
+    ///     let mut resp = Response::new(status).header("Content-Type", "text/html");
+    ///     resp.body(format!("<h1>Nothing at {}</h1>", req.path));
+    ///     resp
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.on_error(StatusCode::NotFound, not_found);
+    /// ```
+    pub fn on_error<F, Fut>(&mut self, status: StatusCode, handler: F)
+    where
+        F: Fn(StatusCode, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let handler: ErrorHandler = Arc::new(move |status, req| Box::pin(handler(status, req)));
+        let errors = Arc::make_mut(&mut self.errors);
+        errors.by_status.retain(|(s, _)| *s != status);
+        errors.by_status.push((status, handler));
+    }
+
+    /// Like [`Router::on_error`], but for every status that has no handler of its own.
+    pub fn on_any_error<F, Fut>(&mut self, handler: F)
+    where
+        F: Fn(StatusCode, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        Arc::make_mut(&mut self.errors).fallback = Some(Arc::new(move |status, req| Box::pin(handler(status, req))));
+    }
+
+    /// Returns the error response for `status`, from its error handler or in the configured format.
+    pub(crate) async fn error_response(&self, status: StatusCode, req: Request) -> Response {
+        self.errors.respond(status, req).await
+    }
+
+    /// Returns true if an error handler is registered for `status`.
+    pub(crate) fn has_error_handler(&self, status: &StatusCode) -> bool {
+        self.errors.handler(status).is_some()
+    }
+
+    /// Lets POST requests override their method before route matching, see [`MethodOverride`].
+    /// Disabled unless set, since it lets any client that can POST reach PUT, PATCH and DELETE routes.
+    pub fn method_override(&mut self, method_override: MethodOverride) {
+        self.method_override = Some(method_override);
+    }
+
+    /// Answers TRACE requests, on any path, with the request head the server received as a
+    /// `message/http` body, so clients can see what proxies in between changed. Answered before
+    /// route matching and middleware, with `Authorization`, `Proxy-Authorization`, `Cookie` and
+    /// other credential headers left out. Disabled by default.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::Router;
+    ///
+    /// let mut router = Router::new();
+    /// router.trace(true);
+    /// ```
+    pub fn trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Restricts the latest route to requests whose `Host` matches `pattern`, for virtual hosting.
+    /// Labels starting with `:` capture that part of the host name into `Request.params`,
+    /// like path parameters. Host names are compared case-insensitively and without the port.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn dashboard(req: Request) -> Response {
+    ///     Response::ok(format!("Dashboard of {}", req.params["tenant"]))
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route(Method::GET, "/dashboard", dashboard);
+    /// router.host(":tenant.example.com");
+    /// ```
+    pub fn host(&mut self, pattern: &str) {
+        if let Some(route) = self.routes.last_mut() {
+            route.host = Some(
+                pattern
+                    .trim_end_matches('.')
+                    .split('.')
+                    .map(|label| match label.strip_prefix(':') {
+                        Some(name) => RouteSegment::Param(Arc::from(name)),
+                        None => RouteSegment::Static(Arc::from(label.to_ascii_lowercase())),
+                    })
+                    .collect(),
+            );
+        }
+    }
+
+    /// Sends `percent` (0 to 100) of the latest route's traffic to `handler` instead,
+    /// for gradual rollouts. Variants are tried in the order they were added, before the
+    /// route's own handler, and share the route's middleware.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn search(_req: Request) -> Response {
+    ///     Response::ok("v1")
+    /// }
+    ///
+    /// async fn search_v2(_req: Request) -> Response {
+    ///     Response::ok("v2")
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route(Method::GET, "/search", search);
+    /// router.canary_when(|req| req.headers.get("X-Canary").is_some_and(|v| v == "1"), search_v2);
+    /// router.canary(5.0, search_v2);
+    /// ```
+    pub fn canary<F, Fut>(&mut self, percent: f64, handler: F)
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.canary_when(move |_| sampled(percent), handler);
+    }
+
+    /// Sends requests of the latest route for which `predicate` returns true to `handler` instead,
+    /// e.g. based on a header or cookie. See [`Router::canary`].
+    pub fn canary_when<P, F, Fut>(&mut self, predicate: P, handler: F)
+    where
+        P: Fn(&Request) -> bool + Send + Sync + 'static,
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        if let Some(route) = self.routes.last_mut() {
+            let handler: Handler = Arc::new(move |req| Box::pin(handler(req)));
+            route.variants.push((Arc::new(predicate), handler));
+        }
+    }
+
+    /// Limits the body of the latest route's requests to `bytes`. Larger bodies are answered
+    /// with 413 Content Too Large before the handler runs: a declared length is checked up
+    /// front, a chunked body is read into memory up to the limit.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn upload(mut req: Request) -> Response {
+    ///     // Can't fail for being too large anymore.
+    ///     let data = req.bytes(1024 * 1024).await.unwrap_or_default();
+    ///     Response::ok(format!("{} bytes", data.len()))
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route(Method::POST, "/upload", upload);
+    /// router.max_body(1024 * 1024);
+    /// ```
+    pub fn max_body(&mut self, bytes: usize) {
+        if let Some(route) = self.routes.last_mut() {
+            route.body.max_size = Some(bytes);
+        }
+    }
+
+    /// Restricts the latest route to request bodies of the given media types, such as
+    /// `application/json`. Requests with a body of any other type, or without a `Content-Type`,
+    /// are answered with 415 Unsupported Media Type before the handler runs and before the body
+    /// is read, with the accepted types in an `Accept-Post` header (`Accept-Patch` for PATCH).
+    /// Requests without a body are let through.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn create_user(_req: Request) -> Response {
+    ///     Response::ok("created")
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route(Method::POST, "/users", create_user);
+    /// router.accept(&["application/json"]);
+    /// ```
+    pub fn accept(&mut self, content_types: &[&str]) {
+        if let Some(route) = self.routes.last_mut() {
+            route.body.content_types = Some(content_types.iter().map(|t| t.to_string()).collect());
+        }
+    }
+
+    /// Sets the body size limit of every route of this router that doesn't set its own with
+    /// [`Router::max_body`], including routes added later. Applies to a router's routes after
+    /// it's merged into another, so a group of routes can share it.
+    pub fn default_max_body(&mut self, bytes: usize) {
+        self.body_defaults.max_size = Some(bytes);
+    }
+
+    /// Returns the largest body any route accepts, counting routes without a limit as `unlimited`,
+    /// for adapters that have to buffer a body before it's routed.
+    #[cfg(feature = "fastcgi")]
+    pub(crate) fn largest_max_body(&self, unlimited: usize) -> usize {
+        self.routes
+            .iter()
+            .map(|route| route.body.max_size.or(self.body_defaults.max_size).unwrap_or(unlimited))
+            .max()
+            .unwrap_or(unlimited)
+    }
+
+    /// Sets the accepted body media types of every route of this router that doesn't set its
+    /// own with [`Router::accept`], like [`Router::default_max_body`].
+    pub fn default_accept(&mut self, content_types: &[&str]) {
+        self.body_defaults.content_types = Some(content_types.iter().map(|t| t.to_string()).collect());
+    }
+
+    /// Adds a header to every response of the latest route that doesn't already have one of the
+    /// same name, so a static header doesn't need a middleware. Takes precedence over
+    /// [`Router::default_header`], headers set by the handler or middleware take precedence over both.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn report(_req: Request) -> Response {
+    ///     Response::ok("42")
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.route(Method::GET, "/report", report);
+    /// router.header("Cache-Control", "no-store");
+    /// ```
+    pub fn header(&mut self, name: &str, value: &str) {
+        if let Some(route) = self.routes.last_mut() {
+            set_header(&mut route.headers, name, value, true);
+        }
+    }
+
+    /// Adds a header to every response of this router that doesn't already have one of the same
+    /// name, including responses of routes added later and 404s for unmatched requests. Like
+    /// [`Router::default_max_body`], a router's defaults keep applying to its routes after it's
+    /// merged into another, ahead of the other router's defaults, so a group of routes can share them.
+    /// Responses of the forward proxy are left as they are.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Method, Request, Response};
+    ///
+    /// async fn home(_req: Request) -> Response {
+    ///     Response::ok("home")
+    /// }
+    ///
+    /// async fn embed(_req: Request) -> Response {
+    ///     Response::ok("widget").header("X-Frame-Options", "SAMEORIGIN")
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.default_header("X-Frame-Options", "DENY");
+    /// router.route(Method::GET, "/", home);
+    /// router.route(Method::GET, "/embed", embed);
+    /// ```
+    pub fn default_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.default_headers, name, value, true);
+    }
+
     /// Appends a middleware to the latest route.
     /// Requires a function with the following signature:
     /// `async fn middleware(Request, Handler) -> Response`
     ///
+    /// Middleware runs outer to inner: global middleware first, in the order it was added, then the
+    /// route's middleware in the order it was added, then the handler. Each call adds the new
+    /// middleware as the innermost layer, use [`Router::middleware_first`] to add it as the outermost.
+    ///
     /// # Example:
     ///
     /// ```
@@ -141,11 +888,104 @@ impl Router {
         Fut: Future<Output = Response> + Send + 'static,
     {
         if let Some(route) = self.routes.last_mut() {
-            route.middleware = Some(Arc::new(move |req, next| Box::pin(f(req, next))));
+            route.middleware.push(Arc::new(move |req, next| Box::pin(f(req, next))));
+        }
+    }
+
+    /// Inserts a middleware as the outermost layer of the latest route, so it runs before the
+    /// route's other middleware (but still after global middleware).
+    pub fn middleware_first<F, Fut>(&mut self, f: F)
+    where
+        F: Fn(Request, Handler) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        if let Some(route) = self.routes.last_mut() {
+            route.middleware.insert(0, Arc::new(move |req, next| Box::pin(f(req, next))));
+        }
+    }
+
+    /// Appends a middleware that applies to every route, including routes added later and
+    /// requests that match no route. It becomes the innermost global layer, still running before
+    /// any per-route middleware.
+    ///
+    /// # Example:
+    ///
+    /// ```
+    /// use zep::{Router, Method, Request, Response, Handler};
+    ///
+    /// async fn handler(_req: Request) -> Response {
+    ///     Response::ok("Hello World!")
+    /// }
+    ///
+    /// async fn log(req: Request, next: Handler) -> Response {
+    ///     println!("{} {}", req.method, req.path);
+    ///     next(req).await
+    /// }
+    ///
+    /// async fn auth(req: Request, next: Handler) -> Response {
+    ///     next(req).await
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.global_middleware(log);
+    /// router.route(Method::GET, "/", handler);
+    /// router.middleware(auth);
+    /// // GET / runs log, then auth, then handler.
+    /// ```
+    pub fn global_middleware<F, Fut>(&mut self, f: F)
+    where
+        F: Fn(Request, Handler) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.global_middleware.push(Arc::new(move |req, next| Box::pin(f(req, next))));
+    }
+
+    /// Inserts a middleware as the outermost global layer, so it runs before every other middleware.
+    pub fn global_middleware_first<F, Fut>(&mut self, f: F)
+    where
+        F: Fn(Request, Handler) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.global_middleware.insert(0, Arc::new(move |req, next| Box::pin(f(req, next))));
+    }
+}
+
+/// Sets `name` in a list of default headers, replacing a value of the same name if `replace`.
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str, replace: bool) {
+    match headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+        Some(existing) if replace => *existing = (name.to_string(), value.to_string()),
+        Some(_) => {}
+        None => headers.push((name.to_string(), value.to_string())),
+    }
+}
+
+fn error_response(format: ErrorFormat, status: StatusCode) -> Response {
+    match format {
+        ErrorFormat::Text => {
+            let body = status.to_string();
+            let mut resp = Response::new(status);
+            resp.body(body);
+            resp
+        }
+        ErrorFormat::Json => {
+            let code = status.as_u16();
+            let reason = StatusCode::canonical_reason(code).unwrap_or_default();
+            let body = serde_json::json!({"error": reason, "status": code});
+            let mut resp = Response::new(status).header("Content-Type", "application/json");
+            resp.body(body.to_string());
+            resp
         }
     }
 }
 
+/// Wraps `handler` in `layers`, the first layer ending up outermost.
+pub(crate) fn layered<'a>(layers: impl DoubleEndedIterator<Item = &'a Middleware>, handler: Handler) -> Handler {
+    layers.rev().fold(handler, |next, middleware| {
+        let middleware = middleware.clone();
+        Arc::new(move |req| middleware(req, next.clone()))
+    })
+}
+
 fn match_route(route_segments: Arc<[RouteSegment]>, req_path: &str) -> Option<ParamMap> {
     let req_segments: Vec<&str> = req_path.trim_matches('/').split('/').collect();
 
@@ -171,6 +1011,77 @@ fn match_route(route_segments: Arc<[RouteSegment]>, req_path: &str) -> Option<Pa
     Some(params)
 }
 
+fn is_trace(method: &Method) -> bool {
+    matches!(method, Method::Other(m) if m == "TRACE")
+}
+
+/// Headers that carry credentials, left out of TRACE echoes.
+const TRACE_HIDDEN: [&str; 6] = ["authorization", "proxy-authorization", "cookie", "x-api-key", "x-auth-token", "x-csrf-token"];
+
+/// Returns the head of `req` as a `message/http` response, see [`Router::trace`].
+fn trace_echo(req: &Request) -> Response {
+    let mut head = format!("{} {} {}\r\n", req.method, req.path, req.version);
+    for (key, value) in &req.headers {
+        if !TRACE_HIDDEN.iter().any(|hidden| key.eq_ignore_ascii_case(hidden)) {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+    }
+    head.push_str("\r\n");
+    let mut resp = Response::new(StatusCode::Ok).header("Content-Type", "message/http");
+    resp.body(head);
+    resp
+}
+
+/// Returns the host name the request was sent to, without the port.
+fn request_host(req: &Request) -> Option<&str> {
+    let host = req.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("host"))?.1.as_str();
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next();
+    }
+    Some(host.rsplit_once(':').map_or(host, |(name, _)| name))
+}
+
+fn match_host(host_segments: &[RouteSegment], host: &str) -> Option<ParamMap> {
+    let labels: Vec<&str> = host.trim_end_matches('.').split('.').collect();
+    if host_segments.len() != labels.len() {
+        return None;
+    }
+
+    let mut params = ParamMap::new();
+    for (segment, label) in host_segments.iter().zip(labels) {
+        match segment {
+            RouteSegment::Static(expected) => {
+                if !expected.eq_ignore_ascii_case(label) {
+                    return None;
+                }
+            }
+            RouteSegment::Param(name) => {
+                if label.is_empty() {
+                    return None;
+                }
+                params.insert(name.clone(), label.to_ascii_lowercase());
+            }
+        }
+    }
+    Some(params)
+}
+
+/// Writes `segments` back as the pattern they were parsed from, such as `/users/:id`.
+fn route_pattern(segments: &[RouteSegment]) -> String {
+    let mut pattern = String::new();
+    for segment in segments {
+        pattern.push('/');
+        match segment {
+            RouteSegment::Static(s) => pattern.push_str(s),
+            RouteSegment::Param(name) => {
+                pattern.push(':');
+                pattern.push_str(name);
+            }
+        }
+    }
+    pattern
+}
+
 fn parse_route(path: &str) -> Arc<[RouteSegment]> {
     path.trim_matches('/')
         .split('/')
@@ -1,9 +1,21 @@
 //! This is a helper module that contains useful utilities to serve and receive different kinds of content over HTTP.
 
-use crate::{Response, StreamReader, StreamWriter, StatusCode};
+use crate::{HeaderMap, Method, Request, Response, StreamReader, StreamWriter, StatusCode};
+use crate::route::Predicate;
+use crate::server::{find_headers_end, parse_absolute_form, parse_response_head};
 use tokio::fs;
-use tokio::io::{AsyncWriteExt};
-use std::io::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use std::io::{Cursor, Result, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod archive;
+mod upstream;
+pub mod well_known;
+pub use archive::archive;
+pub use upstream::{ReverseProxy, Strategy};
 
 /// Returns a 200 OK response with the contents of file located at `path`.
 /// Returns a 500 Internal Server Error response if file could not be read or found.
@@ -26,16 +38,766 @@ pub async fn file(path: &str) -> Response {
 /// Serves a file in a Response. If file is bigger than 64 KiB, then it will be streamed.
 pub async fn send_file(path: &str) -> Result<Response> {
     let n = 64 * 1024;
-    match fs::read(path).await {
-        Ok(data) => {
-            if data.len() <= n {
-                Ok(Response::ok(data))
-            } else {
-                Ok(Response::stream(StatusCode::Ok, StreamWriter::new(fs::File::open(path).await?)))
+    let file = fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    if len <= n {
+        Ok(Response::ok(fs::read(path).await?))
+    } else {
+        Ok(Response::stream_with_len(StatusCode::Ok, StreamWriter::new(file), len))
+    }
+}
+
+/// Serves a file as a download, so browsers save it as `filename` instead of displaying it.
+/// Sets `Content-Disposition: attachment` (with an RFC 5987 `filename*` for non-ASCII names),
+/// `Content-Type` based on the file extension and `Content-Length`, and streams the file.
+///
+/// # Example:
+/// ```
+/// use zep::{Request, Response, serve};
+///
+/// async fn report(_req: Request) -> Response {
+///     serve::download("reports/2024.csv", "Jahresbericht 2024 – Übersicht.csv")
+///         .await
+///         .unwrap_or_else(|_| Response::not_found())
+/// }
+/// ```
+pub async fn download(path: &str, filename: &str) -> Result<Response> {
+    let file = fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    Ok(Response::stream_with_len(StatusCode::Ok, StreamWriter::new(file), len)
+        .header("Content-Type", mime_type(path))
+        .header("Content-Disposition", &content_disposition(filename)))
+}
+
+/// Serves a file the way a static file server would, taking the request into account.
+/// Sets `Content-Type`, `ETag` and `Last-Modified`, answers conditional requests with
+/// 304 Not Modified or 412 Precondition Failed and `Range` requests as [`send_file_range`] does.
+/// HEAD requests get the same headers without the file being read.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, serve};
+///
+/// async fn logo(req: Request) -> Response {
+///     serve::send_file_for(&req, "static/logo.png")
+///         .await
+///         .unwrap_or_else(|_| Response::not_found())
+/// }
+///
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/logo.png", logo);
+/// router.route(Method::HEAD, "/logo.png", logo);
+/// ```
+pub async fn send_file_for(req: &Request, path: &str) -> Result<Response> {
+    let meta = fs::metadata(path).await?;
+    let modified = meta.modified().ok().map(truncate_secs);
+    let mtime = modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+    let etag = format!("W/\"{:x}-{:x}\"", meta.len(), mtime);
+
+    if let Some(resp) = not_modified_if_match(req, Some(&etag), modified) {
+        return Ok(resp);
+    }
+    let resp = if req.method == Method::HEAD {
+        Response::new(StatusCode::Ok)
+            .header("Content-Type", mime_type(path))
+            .header("Content-Length", &meta.len().to_string())
+            .header("Accept-Ranges", "bytes")
+    } else {
+        send_file_range(req, path).await?
+    };
+    let resp = resp.header("ETag", &etag);
+    Ok(match modified {
+        Some(time) => resp.header("Last-Modified", &http_date(time)),
+        None => resp,
+    })
+}
+
+/// Serves a file honoring the request's `Range` header, for resumable downloads and media seeking.
+/// A single range is answered with 206 Partial Content and `Content-Range`, several ranges with a
+/// `multipart/byteranges` body. Ranges that lie outside the file are answered with
+/// 416 Range Not Satisfiable. Without a usable `Range` header the whole file is streamed.
+/// Responses advertise `Accept-Ranges: bytes`.
+pub async fn send_file_range(req: &Request, path: &str) -> Result<Response> {
+    let mut file = fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let content_type = mime_type(path);
+    let ranges = match header(req, "range") {
+        Some(range) if req.method == Method::GET => parse_range(range, len),
+        _ => None,
+    };
+    let resp = match ranges.as_deref() {
+        None => Response::stream_with_len(StatusCode::Ok, StreamWriter::new(file), len)
+            .header("Content-Type", content_type),
+        Some([]) => Response::new(StatusCode::RangeNotSatisfiable)
+            .header("Content-Range", &format!("bytes */{}", len)),
+        Some(&[(start, end)]) => {
+            file.seek(SeekFrom::Start(start)).await?;
+            let part = file.take(end - start + 1);
+            let part_len = end - start + 1;
+            Response::stream_with_len(StatusCode::PartialContent, StreamWriter::new(part), part_len)
+                .header("Content-Type", content_type)
+                .header("Content-Range", &format!("bytes {}-{}/{}", start, end, len))
+        }
+        Some(ranges) => {
+            let seed = format!("{:?}{}", SystemTime::now(), path);
+            let boundary = format!("zep-{}", etag_of(seed.as_bytes()).trim_matches('"'));
+            let mut body: Box<dyn AsyncRead + Unpin + Send> = Box::new(tokio::io::empty());
+            let mut total = 0;
+            for &(start, end) in ranges {
+                let head = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end, len
+                );
+                let mut part = fs::File::open(path).await?;
+                part.seek(SeekFrom::Start(start)).await?;
+                total += head.len() as u64 + end - start + 1;
+                body = Box::new(body.chain(Cursor::new(head)).chain(part.take(end - start + 1)));
             }
-        },
-        Err(e) => Err(e),
+            let tail = format!("\r\n--{}--\r\n", boundary);
+            total += tail.len() as u64;
+            body = Box::new(body.chain(Cursor::new(tail)));
+            let content_type = format!("multipart/byteranges; boundary={}", boundary);
+            Response::stream_with_len(StatusCode::PartialContent, StreamWriter::new(body), total)
+                .header("Content-Type", &content_type)
+        }
+    };
+    Ok(resp.header("Accept-Ranges", "bytes"))
+}
+
+/// Maximum number of ranges served in one response, requests asking for more get the whole file.
+const MAX_RANGES: usize = 16;
+
+/// Parses a `Range` header into inclusive byte ranges within a resource of `len` bytes.
+/// Returns `None` if the header should be ignored and an empty list if no range is satisfiable.
+/// Overlapping and adjacent ranges are merged.
+pub(crate) fn parse_range(value: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = value.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        count += 1;
+        let (first, last) = spec.split_once('-')?;
+        let range = if first.is_empty() {
+            let suffix: u64 = last.parse().ok()?;
+            (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+        } else {
+            let start: u64 = first.parse().ok()?;
+            let end = if last.is_empty() { u64::MAX } else { last.parse().ok()? };
+            if end < start {
+                return None;
+            }
+            (start < len).then(|| (start, end.min(len - 1)))
+        };
+        ranges.extend(range);
     }
+    if count == 0 || count > MAX_RANGES {
+        return None;
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(merged)
+}
+
+/// Builds an attachment Content-Disposition value with an ASCII fallback filename.
+pub(crate) fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    if fallback == filename {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// Guesses a Content-Type from a file extension, defaulting to `application/octet-stream`.
+pub fn mime_type(path: &str) -> &'static str {
+    let ext = match path.rsplit_once('.') {
+        Some((_, ext)) if !ext.contains('/') => ext.to_ascii_lowercase(),
+        _ => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "avif" => "image/avif",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "wasm" => "application/wasm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Static files compiled into the binary, for single-binary deployments.
+/// Built from `(path, contents)` pairs, usually written with `include_bytes!`.
+/// Responses get a `Content-Type` from [`mime_type`] and an `ETag`, and `If-None-Match` is answered
+/// with 304 Not Modified. An entry named `path.gz` is used as the precompressed variant of `path`
+/// and served with `Content-Encoding: gzip` to clients that accept it.
+///
+/// # Example:
+/// ```
+/// use std::sync::Arc;
+/// use zep::{Router, Method, Request, serve::EmbeddedAssets};
+///
+/// static FILES: &[(&str, &[u8])] = &[
+///     ("index.html", b"<h1>Hello</h1>"),
+///     ("app.js", b"console.log('hello');"),
+/// ];
+///
+/// let assets = Arc::new(EmbeddedAssets::new(FILES));
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/:file", move |req: Request| {
+///     let assets = assets.clone();
+///     async move {
+///         let file = req.params.get("file").cloned().unwrap_or_default();
+///         assets.respond(&req, &file)
+///     }
+/// });
+/// ```
+pub struct EmbeddedAssets {
+    assets: Vec<Asset>,
+}
+
+struct Asset {
+    path: &'static str,
+    data: &'static [u8],
+    gzip: Option<&'static [u8]>,
+    etag: String,
+}
+
+impl EmbeddedAssets {
+    /// Indexes the given files. Leading slashes in paths are ignored.
+    pub fn new(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        let assets = files
+            .iter()
+            .map(|&(path, data)| {
+                let path = path.trim_start_matches('/');
+                let gzip = files
+                    .iter()
+                    .find(|(p, _)| p.trim_start_matches('/').strip_suffix(".gz") == Some(path))
+                    .map(|&(_, gz)| gz);
+                Asset { path, data, gzip, etag: etag_of(data) }
+            })
+            .collect();
+        EmbeddedAssets { assets }
+    }
+
+    /// Returns the contents of the asset at `path`.
+    pub fn get(&self, path: &str) -> Option<&'static [u8]> {
+        self.find(path).map(|asset| asset.data)
+    }
+
+    fn find(&self, path: &str) -> Option<&Asset> {
+        let path = path.trim_start_matches('/');
+        let path = if path.is_empty() || path.ends_with('/') {
+            format!("{}index.html", path)
+        } else {
+            path.to_string()
+        };
+        self.assets.iter().find(|asset| asset.path == path)
+    }
+
+    /// Serves the asset at `path`, an empty path or one ending in `/` serves `index.html`.
+    /// Returns a 404 Not Found response if there is no such asset.
+    pub fn respond(&self, req: &Request, path: &str) -> Response {
+        let Some(asset) = self.find(path) else {
+            return Response::not_found();
+        };
+        let mut resp = match precondition_status(req, Some(&asset.etag), None) {
+            Some(status) => Response::new(status),
+            None => match asset.gzip {
+                Some(gz) if header(req, "accept-encoding").is_some_and(accepts_gzip) => {
+                    Response::ok(gz).header("Content-Encoding", "gzip")
+                }
+                _ => Response::ok(asset.data),
+            },
+        };
+        if asset.gzip.is_some() {
+            resp = resp.header("Vary", "Accept-Encoding");
+        }
+        resp.header("Content-Type", mime_type(asset.path)).header("ETag", &asset.etag)
+    }
+}
+
+/// Static files with a content hash in their URLs, so they can be cached forever: when a file
+/// changes, so does its URL. Files are read and hashed once, when loaded. Templates link to them
+/// with [`HashedAssets::asset_url`], and hashed URLs are served with
+/// `Cache-Control: public, max-age=31536000, immutable`.
+///
+/// # Example:
+/// ```no_run
+/// use std::sync::Arc;
+/// use zep::{Router, Method, Request, Response, serve::HashedAssets};
+///
+/// # async fn run() -> std::io::Result<()> {
+/// let assets = Arc::new(HashedAssets::load("static", "/assets").await?);
+/// let stylesheet = assets.asset_url("app.css"); // "/assets/app.3f2a9c1be0.css"
+///
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/", move |_req: Request| {
+///     let page = format!("<link rel=\"stylesheet\" href=\"{}\">", stylesheet);
+///     async move { Response::ok(page).header("Content-Type", "text/html") }
+/// });
+/// router.global_middleware(move |req, next| assets.handle(req, next));
+/// # Ok(())
+/// # }
+/// ```
+pub struct HashedAssets {
+    prefix: String,
+    assets: Vec<HashedAsset>,
+}
+
+struct HashedAsset {
+    path: String,
+    hashed: String,
+    data: Vec<u8>,
+    etag: String,
+}
+
+impl HashedAssets {
+    /// Reads and hashes every file under the directory `dir`, to be served under the URL path `prefix`.
+    pub async fn load(dir: &str, prefix: &str) -> Result<Self> {
+        let mut assets = Vec::new();
+        let mut dirs = vec![(std::path::PathBuf::from(dir), String::new())];
+        while let Some((dir, relative)) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = format!("{}{}", relative, name);
+                if entry.file_type().await?.is_dir() {
+                    dirs.push((entry.path(), format!("{}/", path)));
+                    continue;
+                }
+                let data = fs::read(entry.path()).await?;
+                let etag = etag_of(&data);
+                let hashed = hashed_path(&path, &etag[1..11]);
+                assets.push(HashedAsset { path, hashed, data, etag });
+            }
+        }
+        Ok(HashedAssets { prefix: prefix.trim_end_matches('/').to_string(), assets })
+    }
+
+    /// Returns the hashed URL of the file at `path` relative to the loaded directory,
+    /// e.g. `/assets/css/app.3f2a9c1be0.css` for `css/app.css`.
+    /// Unknown files get their unhashed URL, which is served without long-term caching.
+    pub fn asset_url(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+        match self.assets.iter().find(|asset| asset.path == path) {
+            Some(asset) => format!("{}/{}", self.prefix, asset.hashed),
+            None => format!("{}/{}", self.prefix, path),
+        }
+    }
+
+    /// Serves the file at `path`, relative to the prefix. Hashed paths are cached for a year,
+    /// plain ones are revalidated on every use. Returns a 404 Not Found response if there is no such file.
+    pub fn respond(&self, req: &Request, path: &str) -> Response {
+        let path = path.trim_start_matches('/');
+        let (asset, cache_control) = match self.assets.iter().find(|asset| asset.hashed == path) {
+            Some(asset) => (asset, "public, max-age=31536000, immutable"),
+            None => match self.assets.iter().find(|asset| asset.path == path) {
+                Some(asset) => (asset, "no-cache"),
+                None => return Response::not_found(),
+            },
+        };
+        let resp = match precondition_status(req, Some(&asset.etag), None) {
+            Some(status) => Response::new(status),
+            None => Response::ok(asset.data.clone()),
+        };
+        resp.header("Content-Type", mime_type(&asset.path))
+            .header("ETag", &asset.etag)
+            .header("Cache-Control", cache_control)
+    }
+
+    /// Middleware serving GET and HEAD requests under the prefix, other requests go on to `next`.
+    pub fn handle(&self, req: Request, next: crate::Handler) -> impl Future<Output = Response> + Send + use<> {
+        let path = req.path.split('?').next().unwrap_or_default();
+        let served = matches!(req.method, Method::GET | Method::HEAD)
+            .then(|| path.strip_prefix(&self.prefix)?.strip_prefix('/'))
+            .flatten()
+            .map(|path| self.respond(&req, path));
+        async move {
+            match served {
+                Some(resp) => resp,
+                None => next(req).await,
+            }
+        }
+    }
+}
+
+/// Inserts `hash` before the extension of the file name in `path`: `css/app.css` becomes `css/app.<hash>.css`.
+fn hashed_path(path: &str, hash: &str) -> String {
+    let (dir, name) = path.rsplit_once('/').map_or(("", path), |(dir, name)| (dir, name));
+    let name = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}.{}.{}", stem, hash, ext),
+        _ => format!("{}.{}", name, hash),
+    };
+    if dir.is_empty() { name } else { format!("{}/{}", dir, name) }
+}
+
+/// Returns a handler serving `rules` as `/robots.txt`, with `Content-Type: text/plain`, an `ETag`
+/// and `Cache-Control: public, max-age=86400`, as crawlers fetch it about once a day.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, serve};
+///
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/robots.txt", serve::robots("User-agent: *\nDisallow: /admin/\n"));
+/// ```
+pub fn robots(rules: &str) -> impl Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync + 'static {
+    let rules: Arc<[u8]> = Arc::from(rules.as_bytes());
+    let etag: Arc<str> = Arc::from(etag_of(&rules));
+    move |req| {
+        let resp = cached(&req, &rules, &etag, "text/plain; charset=utf-8");
+        Box::pin(async move { resp })
+    }
+}
+
+/// Where [`favicon`] gets the icon from: bytes, usually written with `include_bytes!`, or the
+/// path of a file read on every request, so it can be replaced while the server runs.
+#[derive(Debug, Clone)]
+pub enum Favicon {
+    /// The icon itself.
+    Bytes(&'static [u8]),
+    /// The path of the icon file.
+    Path(String),
+}
+
+impl From<&'static [u8]> for Favicon {
+    fn from(bytes: &'static [u8]) -> Self {
+        Favicon::Bytes(bytes)
+    }
+}
+
+impl<const N: usize> From<&'static [u8; N]> for Favicon {
+    fn from(bytes: &'static [u8; N]) -> Self {
+        Favicon::Bytes(bytes)
+    }
+}
+
+impl From<&str> for Favicon {
+    fn from(path: &str) -> Self {
+        Favicon::Path(path.to_string())
+    }
+}
+
+/// Returns a handler serving an icon as `/favicon.ico`, with `Cache-Control: public, max-age=86400`
+/// and the validators of [`send_file_for`], so browsers don't ask for it on every page.
+/// The `Content-Type` is guessed from the contents for bytes, PNG and SVG icons work as well as
+/// ICO files, and from the extension for a path. A path that can't be read gets a 404 Not Found.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, serve};
+///
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/favicon.ico", serve::favicon(b"\x00\x00\x01\x00"));
+/// router.route(Method::GET, "/apple-touch-icon.png", serve::favicon("static/touch-icon.png"));
+/// ```
+pub fn favicon(icon: impl Into<Favicon>) -> impl Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync + 'static {
+    let icon = icon.into();
+    let etag: Arc<str> = match &icon {
+        Favicon::Bytes(bytes) => Arc::from(etag_of(bytes)),
+        Favicon::Path(_) => Arc::from(""),
+    };
+    move |req| match &icon {
+        Favicon::Bytes(bytes) => {
+            let resp = cached(&req, bytes, &etag, icon_type(bytes));
+            Box::pin(async move { resp })
+        }
+        Favicon::Path(path) => {
+            let path = path.clone();
+            Box::pin(async move {
+                match send_file_for(&req, &path).await {
+                    Ok(resp) => resp.header("Cache-Control", "public, max-age=86400"),
+                    Err(_) => Response::not_found(),
+                }
+            })
+        }
+    }
+}
+
+/// Answers `req` with `data`, or 304 Not Modified if the client's copy is still `etag`.
+fn cached(req: &Request, data: &[u8], etag: &str, content_type: &str) -> Response {
+    let resp = match precondition_status(req, Some(etag), None) {
+        Some(status) => Response::new(status),
+        None => Response::ok(data.to_vec()),
+    };
+    resp.header("Content-Type", content_type)
+        .header("ETag", etag)
+        .header("Cache-Control", "public, max-age=86400")
+}
+
+/// Returns the media type of an icon from its first bytes.
+fn icon_type(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if data.trim_ascii_start().starts_with(b"<") {
+        "image/svg+xml"
+    } else {
+        "image/x-icon"
+    }
+}
+
+/// Returns a strong entity tag for `data`, derived from its FNV-1a hash and length.
+/// The tag is quoted and can be used as the `ETag` header value as is.
+pub fn etag_of(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("\"{:016x}-{:x}\"", hash, data.len())
+}
+
+/// Evaluates the conditional headers of `req` (`If-Match`, `If-Unmodified-Since`, `If-None-Match`
+/// and `If-Modified-Since`) against the current `etag` and `last_modified` time of a resource.
+/// Returns a 304 Not Modified or 412 Precondition Failed response carrying the validators if the
+/// request should not be processed, or `None` if the handler should go on and respond normally.
+///
+/// # Example:
+/// ```
+/// use zep::{Request, Response, serve};
+///
+/// async fn profile(req: Request) -> Response {
+///     let body = r#"{"name": "zep"}"#;
+///     let etag = serve::etag_of(body.as_bytes());
+///     if let Some(resp) = serve::not_modified_if_match(&req, Some(&etag), None) {
+///         return resp;
+///     }
+///     Response::ok(body).header("ETag", &etag)
+/// }
+/// ```
+pub fn not_modified_if_match(
+    req: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Option<Response> {
+    let status = precondition_status(req, etag, last_modified)?;
+    let mut resp = Response::new(status);
+    if let Some(etag) = etag {
+        resp = resp.header("ETag", etag);
+    }
+    if let Some(time) = last_modified {
+        resp = resp.header("Last-Modified", &http_date(time));
+    }
+    Some(resp)
+}
+
+/// Optimistic concurrency for write endpoints: returns a 412 Precondition Failed response carrying
+/// the current `etag` if the `If-Match` header of `req` doesn't match it, meaning the client is
+/// about to overwrite a version of the resource it hasn't seen. Requests without `If-Match` pass,
+/// use [`require_if_match`] to reject them. `etag` is `None` if the resource doesn't exist.
+///
+/// # Example:
+/// ```
+/// use zep::{Request, Response, serve};
+///
+/// async fn update_post(mut req: Request) -> Response {
+///     let current = serve::etag_of(b"the stored post");
+///     if let Some(resp) = serve::check_if_match(&req, Some(&current)) {
+///         return resp;
+///     }
+///     let Ok(body) = req.bytes(64 * 1024).await else {
+///         return Response::bad_request();
+///     };
+///     Response::ok("updated").header("ETag", &serve::etag_of(&body))
+/// }
+/// ```
+pub fn check_if_match(req: &Request, etag: Option<&str>) -> Option<Response> {
+    let tags = header(req, "if-match")?;
+    if etag.is_some_and(|etag| if_match_passes(tags, etag)) {
+        return None;
+    }
+    let resp = Response::new(StatusCode::PreconditionFailed);
+    Some(match etag {
+        Some(etag) => resp.header("ETag", etag),
+        None => resp,
+    })
+}
+
+/// Like [`check_if_match`], but also rejects requests without an `If-Match` header
+/// with 428 Precondition Required, so clients can't skip the check by accident.
+pub fn require_if_match(req: &Request, etag: Option<&str>) -> Option<Response> {
+    if header(req, "if-match").is_none() {
+        return Some(Response::new(StatusCode::PreconditionRequired));
+    }
+    check_if_match(req, etag)
+}
+
+fn precondition_status(
+    req: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Option<StatusCode> {
+    let is_read = matches!(req.method, Method::GET | Method::HEAD);
+    // Dates only have second precision.
+    let modified_after = |since: &str| match (last_modified, parse_http_date(since)) {
+        (Some(time), Some(since)) => Some(truncate_secs(time) > since),
+        _ => None,
+    };
+
+    if let Some(tags) = header(req, "if-match") {
+        if !etag.is_some_and(|etag| if_match_passes(tags, etag)) {
+            return Some(StatusCode::PreconditionFailed);
+        }
+    } else if let Some(since) = header(req, "if-unmodified-since")
+        && modified_after(since) == Some(true)
+    {
+        return Some(StatusCode::PreconditionFailed);
+    }
+
+    if let Some(tags) = header(req, "if-none-match") {
+        if etag.is_some_and(|etag| etag_matches(tags, etag)) || tags.trim() == "*" {
+            return Some(if is_read { StatusCode::NotModified } else { StatusCode::PreconditionFailed });
+        }
+    } else if let Some(since) = header(req, "if-modified-since")
+        && is_read
+        && modified_after(since) == Some(false)
+    {
+        return Some(StatusCode::NotModified);
+    }
+    None
+}
+
+/// Checks an `If-Match` value against `etag` using strong comparison.
+fn if_match_passes(tags: &str, etag: &str) -> bool {
+    !etag.starts_with("W/") && tags.split(',').map(str::trim).any(|tag| tag == "*" || tag == etag)
+}
+
+/// Checks an `If-None-Match` value against `etag` using weak comparison.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn truncate_secs(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] =
+    ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Parses an HTTP date in the preferred `Sun, 06 Nov 1994 08:49:37 GMT` format.
+/// The obsolete RFC 850 and asctime formats are not accepted.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let (_, rest) = date.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut clock = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (h, m, s) = (clock.next()??, clock.next()??, clock.next()??);
+    if parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+    if !(1..=31).contains(&day) || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + h * 3600 + m * 60 + s))
+}
+
+// Conversions between days since the epoch and proleptic Gregorian dates, see
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Returns true if an `Accept-Encoding` value allows gzip.
+fn accepts_gzip(accept: &str) -> bool {
+    accept.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|p| {
+            p.strip_prefix("q=").is_some_and(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0))
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+fn header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
 }
 
 /// Saves an incoming stream to a file.
@@ -57,3 +819,181 @@ pub async fn save_streamed_file(
     file.sync_all().await?;
     Ok(())
 }
+
+/// Opens a tunnel for a CONNECT request.
+/// Connects to the authority in `req.path` and returns a 200 OK response that, once sent,
+/// splices bytes between the client and the upstream until either side closes.
+/// Returns a 502 Bad Gateway response if the upstream could not be reached.
+///
+/// CONNECT targets are a single path segment, so they can be matched with a parameter route.
+/// Approving or refusing the tunnel is up to the handler.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, StatusCode, serve};
+///
+/// async fn connect(req: Request) -> Response {
+///     if !req.path.ends_with(":443") {
+///         return Response::new(StatusCode::Forbidden);
+///     }
+///     serve::tunnel(&req).await
+/// }
+///
+/// let mut router = Router::new();
+/// router.route(Method::CONNECT, "/:target", connect);
+/// ```
+pub async fn tunnel(req: &Request) -> Response {
+    let mut upstream = match TcpStream::connect(&req.path).await {
+        Ok(upstream) => upstream,
+        Err(_) => return Response::new(StatusCode::BadGateway),
+    };
+
+    Response::new(StatusCode::Ok).upgrade(move |mut io| async move {
+        let _ = tokio::io::copy_bidirectional(&mut io, &mut upstream).await;
+    })
+}
+
+/// Headers that only apply to a single connection and must not be forwarded by a proxy.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-authenticate",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+pub(crate) fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+}
+
+/// Ready-made forward proxy, install it with [`Router::forward_proxy`](crate::Router::forward_proxy).
+/// CONNECT requests are tunneled, requests with an absolute-form `http://` target are forwarded
+/// to the origin server and the response is relayed back to the client.
+/// Every request is denied with 403 Forbidden unless the allow hook accepts it.
+#[derive(Clone)]
+pub struct ForwardProxy {
+    allow: Option<Predicate>,
+}
+
+impl Default for ForwardProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ForwardProxy {
+    /// Returns a new ForwardProxy that denies every request until an allow hook is set.
+    pub fn new() -> Self {
+        ForwardProxy { allow: None }
+    }
+
+    /// Sets the hook deciding whether a request may be proxied.
+    /// The hook sees the full request, so it can implement both a target allowlist
+    /// and authentication through the `Proxy-Authorization` header.
+    pub fn allow<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        self.allow = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn is_proxy_request(req: &Request) -> bool {
+        req.method == Method::CONNECT || req.path.starts_with("http://")
+    }
+
+    /// Proxies a request, tunneling CONNECT requests and forwarding absolute-form requests.
+    pub async fn handle(&self, req: Request) -> Response {
+        if !self.allow.as_ref().is_some_and(|allow| allow(&req)) {
+            return Response::new(StatusCode::Forbidden);
+        }
+        if req.method == Method::CONNECT {
+            return tunnel(&req).await;
+        }
+        match forward(req).await {
+            Ok(resp) => resp,
+            Err(_) => Response::new(StatusCode::BadGateway),
+        }
+    }
+}
+
+/// Forwards an absolute-form request to its origin server and relays the response.
+async fn forward(mut req: Request) -> Result<Response> {
+    let (authority, path) = match parse_absolute_form(&req.path) {
+        Some(target) => target,
+        None => return Ok(Response::bad_request()),
+    };
+    let mut upstream = TcpStream::connect(&authority).await?;
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, path);
+    if !req.headers.keys().any(|k| k.eq_ignore_ascii_case("host")) {
+        head.push_str(&format!("Host: {}\r\n", authority));
+    }
+    for (key, value) in &req.headers {
+        if is_hop_by_hop(key)
+            || key.eq_ignore_ascii_case("content-length")
+            || key.eq_ignore_ascii_case("transfer-encoding")
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str("Connection: close\r\n");
+
+    if !req.body.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", req.body.len()));
+        upstream.write_all(head.as_bytes()).await?;
+        while let Some(chunk) = req.body.chunk().await? {
+            upstream.write_all(&chunk).await?;
+        }
+    } else if let Some(stream) = &mut req.stream {
+        head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+        upstream.write_all(head.as_bytes()).await?;
+        while let Some(chunk) = stream.next_chunk().await? {
+            upstream.write_all(format!("{:X}\r\n", chunk.len()).as_bytes()).await?;
+            upstream.write_all(&chunk).await?;
+            upstream.write_all(b"\r\n").await?;
+        }
+        upstream.write_all(b"0\r\n\r\n").await?;
+    } else {
+        head.push_str("\r\n");
+        upstream.write_all(head.as_bytes()).await?;
+    }
+
+    let mut buffer = Vec::with_capacity(16_384);
+    let headers_end = loop {
+        if let Some(end) = find_headers_end(&buffer) {
+            break end;
+        }
+        if upstream.read_buf(&mut buffer).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+    };
+    let (code, upstream_headers) = match parse_response_head(&buffer[..headers_end]) {
+        Some(head) => head,
+        None => return Err(std::io::ErrorKind::InvalidData.into()),
+    };
+
+    let mut headers = HeaderMap::new();
+    for (key, value) in upstream_headers {
+        if !is_hop_by_hop(&key) {
+            headers.insert(key, value);
+        }
+    }
+    headers.insert("Connection".to_string(), "close".to_string());
+
+    // The upstream closes the connection after the response, so the body, however it's framed,
+    // is relayed verbatim until EOF.
+    let leftover = buffer.split_off(headers_end);
+    Ok(Response::new(StatusCode::from(code))
+        .headermap(headers)
+        .upgrade(move |mut io| async move {
+            if io.write_all(&leftover).await.is_ok() {
+                let _ = tokio::io::copy(&mut upstream, &mut io).await;
+            }
+            let _ = io.shutdown().await;
+        }))
+}
@@ -0,0 +1,103 @@
+//! Streaming several files as one tar archive, built on the fly without touching the disk.
+
+use crate::{Response, StatusCode, StreamWriter};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+/// Tar archives are made of 512 byte blocks.
+const BLOCK: u64 = 512;
+
+/// Largest entry a ustar header can describe, its size field holds 11 octal digits.
+const MAX_ENTRY_SIZE: u64 = 0o77777777777;
+
+/// A file to be archived, opened up front so missing files are reported before streaming starts.
+struct Entry {
+    name: String,
+    file: File,
+    size: u64,
+    mtime: u64,
+}
+
+/// Returns a 200 OK response streaming a tar archive (`application/x-tar`) of the files at `paths`,
+/// for "download all" endpoints. Each file is stored under its file name, in the given order.
+/// The archive is written while it's sent, so memory use doesn't depend on the file sizes,
+/// and its `Content-Length` is known up front.
+/// Returns an error if a file can't be opened or its name doesn't fit in a tar header
+/// (100 bytes). If a file shrinks while it's being sent, the connection is closed.
+///
+/// # Example:
+/// ```
+/// use zep::{Request, Response, serve};
+///
+/// async fn download_all(_req: Request) -> Response {
+///     match serve::archive(&["reports/2023.csv", "reports/2024.csv"]).await {
+///         Ok(resp) => resp.header("Content-Disposition", "attachment; filename=\"reports.tar\""),
+///         Err(_) => Response::not_found(),
+///     }
+/// }
+/// ```
+pub async fn archive(paths: &[&str]) -> Result<Response> {
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = match Path::new(path).file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.len() <= 100 => name.to_string(),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "File name unsuitable for tar")),
+        };
+        let file = File::open(path).await?;
+        let meta = file.metadata().await?;
+        if !meta.is_file() || meta.len() > MAX_ENTRY_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "Only regular files below 8 GiB can be archived"));
+        }
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+        entries.push(Entry { name, file, size: meta.len(), mtime });
+    }
+
+    // Each entry is a header block plus its padded contents, the archive ends with two empty blocks.
+    let len = entries.iter().map(|e| BLOCK + e.size.div_ceil(BLOCK) * BLOCK).sum::<u64>() + 2 * BLOCK;
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        // A failed write means the client went away, a failed read closes the connection early.
+        let _ = write_archive(writer, entries).await;
+    });
+
+    Ok(Response::stream_with_len(StatusCode::Ok, StreamWriter::new(reader), len)
+        .header("Content-Type", "application/x-tar"))
+}
+
+async fn write_archive(mut out: DuplexStream, entries: Vec<Entry>) -> Result<()> {
+    for entry in entries {
+        out.write_all(&header(&entry)).await?;
+        let mut contents = entry.file.take(entry.size);
+        let copied = tokio::io::copy(&mut contents, &mut out).await?;
+        if copied < entry.size {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "File shrank while archiving"));
+        }
+        let padding = (BLOCK - entry.size % BLOCK) % BLOCK;
+        out.write_all(&vec![0; padding as usize]).await?;
+    }
+    out.write_all(&[0; 2 * BLOCK as usize]).await?;
+    out.shutdown().await
+}
+
+/// Builds the ustar header block describing `entry`.
+fn header(entry: &Entry) -> [u8; BLOCK as usize] {
+    let mut block = [0u8; BLOCK as usize];
+    let mut field = |offset: usize, value: &[u8]| block[offset..offset + value.len()].copy_from_slice(value);
+    field(0, entry.name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", entry.size).as_bytes());
+    field(136, format!("{:011o}\0", entry.mtime.min(0o77777777777)).as_bytes());
+    // The checksum is computed with its own field filled with spaces.
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    let checksum: u32 = block.iter().map(|&b| b as u32).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    block
+}
@@ -0,0 +1,594 @@
+//! Reverse proxying to a set of upstream servers, with load balancing and connection reuse.
+
+use super::is_hop_by_hop;
+use crate::server::{parse_chunk_size, parse_response_head};
+use crate::{HeaderMap, Method, Request, Response, StatusCode, Upgraded};
+use std::future::Future;
+use std::io::{ErrorKind, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// Maximum size of an upstream response head.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// How long to wait for `100 Continue` from an upstream before sending the request body anyway.
+const CONTINUE_WAIT: Duration = Duration::from_secs(1);
+
+type Conn = BufReader<TcpStream>;
+
+/// How a [`ReverseProxy`] picks the upstream for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each upstream in turn.
+    RoundRobin,
+    /// The upstream with the fewest requests in progress, in turn among equals.
+    LeastConnections,
+}
+
+struct Upstream {
+    addr: String,
+    in_flight: AtomicUsize,
+    idle: Mutex<Vec<Conn>>,
+}
+
+impl Upstream {
+    /// Keeps a connection whose last response was read completely, for the next request.
+    fn release(&self, conn: Conn, max_idle: usize) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Reverse proxy that spreads requests over a set of upstream servers.
+/// Connections to each upstream are kept open and reused between requests.
+/// If an upstream can't be connected to, idempotent requests (GET, HEAD, PUT, DELETE, OPTIONS)
+/// are retried on the next upstream, other requests are answered with 502 Bad Gateway.
+///
+/// Requests are forwarded with their original path and `Host`, hop-by-hop headers are dropped
+/// and the client address is appended to `X-Forwarded-For`.
+/// WebSocket handshakes (`Upgrade: websocket`) are forwarded too, once the upstream accepts with
+/// 101 Switching Protocols the proxy relays frames both ways until either side closes.
+/// Uploads sent with `Expect: 100-continue` are only read from the client once the upstream asks
+/// for them, a final response the upstream sends instead is relayed without reading the upload.
+///
+/// Upstreams that can't be reached are answered with 502 Bad Gateway and the body
+/// `upstream unavailable`, invalid upstream responses with 502 and `invalid upstream response`,
+/// and timeouts with 504 Gateway Timeout and `upstream timed out`.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, serve::{ReverseProxy, Strategy}};
+///
+/// let api = ReverseProxy::new(["10.0.0.1:8080", "10.0.0.2:8080"]).strategy(Strategy::LeastConnections);
+///
+/// let mut router = Router::new();
+/// router.route(Method::GET, "/api/:resource", move |req| api.handle(req));
+/// ```
+#[derive(Clone)]
+pub struct ReverseProxy {
+    upstreams: Arc<[Upstream]>,
+    strategy: Strategy,
+    next: Arc<AtomicUsize>,
+    max_idle: usize,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    buffer: Option<usize>,
+}
+
+impl ReverseProxy {
+    /// Returns a round-robin proxy for the given `host:port` upstreams.
+    pub fn new<I, S>(upstreams: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let upstreams = upstreams
+            .into_iter()
+            .map(|addr| Upstream { addr: addr.into(), in_flight: AtomicUsize::new(0), idle: Mutex::new(Vec::new()) })
+            .collect();
+        ReverseProxy {
+            upstreams,
+            strategy: Strategy::RoundRobin,
+            next: Arc::new(AtomicUsize::new(0)),
+            max_idle: 8,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+            buffer: None,
+        }
+    }
+
+    /// Sets how upstreams are picked. Defaults to [`Strategy::RoundRobin`].
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Sets how many idle connections are kept open per upstream. Defaults to 8, 0 disables reuse.
+    pub fn max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Sets how long connecting to an upstream may take. Defaults to 5 seconds.
+    /// Idempotent requests move on to the next upstream when it passes.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long an upstream may take to respond once the request was sent, up to the end of
+    /// the response head, or the whole body when responses are buffered. Defaults to 60 seconds.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Reads upstream responses completely before answering, instead of streaming them to the client.
+    /// Frees the upstream connection sooner and lets middleware see the body, at the cost of memory.
+    /// Responses longer than `max_bytes` are answered with 502 Bad Gateway and the body
+    /// `upstream response too large`. WebSocket connections are always streamed.
+    pub fn buffer_responses(mut self, max_bytes: usize) -> Self {
+        self.buffer = Some(max_bytes);
+        self
+    }
+
+    /// Proxies a request to one of the upstreams.
+    pub fn handle(&self, req: Request) -> impl Future<Output = Response> + Send + use<> {
+        let proxy = self.clone();
+        async move { proxy.proxy(req).await }
+    }
+
+    async fn proxy(&self, mut req: Request) -> Response {
+        let idempotent = matches!(
+            req.method,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        );
+        let mut tried = Vec::new();
+        let mut timed_out = false;
+        while let Some(index) = self.pick(&tried) {
+            tried.push(index);
+            let in_flight = InFlight::new(self.upstreams.clone(), index);
+
+            let pooled = self.idle_conn(index);
+            if let Some(conn) = pooled {
+                // The upstream may still close an idle connection just as it's being reused.
+                // That's safe to retry as long as no request body was sent.
+                let resendable = idempotent && req.body.is_empty() && req.stream.is_none();
+                let in_flight = match self.send(conn, &mut req, in_flight).await {
+                    Ok(resp) => return resp,
+                    Err((e, in_flight)) if resendable && e.kind() != ErrorKind::TimedOut => in_flight,
+                    Err((e, _)) => return gateway_error(&e),
+                };
+                match self.connect(index).await {
+                    Ok(conn) => return self.respond(conn, &mut req, in_flight).await,
+                    Err(e) => timed_out |= e.kind() == ErrorKind::TimedOut,
+                }
+                continue;
+            }
+            match self.connect(index).await {
+                Ok(conn) => return self.respond(conn, &mut req, in_flight).await,
+                Err(e) => timed_out |= e.kind() == ErrorKind::TimedOut,
+            }
+            if !idempotent {
+                break;
+            }
+        }
+        if timed_out {
+            return gateway_error(&ErrorKind::TimedOut.into());
+        }
+        error_response(StatusCode::BadGateway, "upstream unavailable")
+    }
+
+    /// Returns the index of the next upstream to try, skipping the ones in `tried`.
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let len = self.upstreams.len();
+        if len == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut candidates = (0..len).map(|i| (start + i) % len).filter(|i| !tried.contains(i));
+        match self.strategy {
+            Strategy::RoundRobin => candidates.next(),
+            Strategy::LeastConnections => {
+                candidates.min_by_key(|&i| self.upstreams[i].in_flight.load(Ordering::Relaxed))
+            }
+        }
+    }
+
+    /// Takes an idle connection to the upstream, dropping ones the upstream has closed meanwhile.
+    fn idle_conn(&self, index: usize) -> Option<Conn> {
+        let mut idle = self.upstreams[index].idle.lock().unwrap();
+        while let Some(conn) = idle.pop() {
+            // An idle connection has nothing to read, a closed one reads EOF.
+            let open = conn.buffer().is_empty()
+                && matches!(conn.get_ref().try_read(&mut [0; 1]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+            if open {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    async fn connect(&self, index: usize) -> Result<Conn> {
+        let connect = TcpStream::connect(&self.upstreams[index].addr);
+        let stream = tokio::time::timeout(self.connect_timeout, connect).await.map_err(|_| ErrorKind::TimedOut)??;
+        stream.set_nodelay(true)?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Like [`ReverseProxy::send`], answering failures with an error response.
+    async fn respond(&self, conn: Conn, req: &mut Request, in_flight: InFlight) -> Response {
+        match self.send(conn, req, in_flight).await {
+            Ok(resp) => resp,
+            Err((e, _)) => gateway_error(&e),
+        }
+    }
+
+    /// Sends the request on `conn` and returns the response. Unless responses are buffered, its
+    /// body is relayed from `conn` once the server wrote the response head.
+    /// Hands `in_flight` back on failure.
+    async fn send(
+        &self,
+        mut conn: Conn,
+        req: &mut Request,
+        in_flight: InFlight,
+    ) -> std::result::Result<Response, (std::io::Error, InFlight)> {
+        let deadline = Instant::now() + self.read_timeout;
+        let head = match timed(deadline, self.exchange(&mut conn, req)).await {
+            Ok(head) => head,
+            Err(e) => return Err((e, in_flight)),
+        };
+        let (framing, keep_alive) = (head.framing, head.keep_alive);
+        let mut resp = Response::new(StatusCode::from(head.code)).headermap(head.headers);
+
+        if let Some(max_bytes) = self.buffer
+            && !matches!(framing, Framing::Tunnel)
+        {
+            // Bodiless responses keep the upstream's Content-Length, e.g. for HEAD requests.
+            if !matches!(framing, Framing::Empty) {
+                let body = match timed(deadline, read_body(&mut conn, framing, max_bytes)).await {
+                    Ok(body) => body,
+                    Err(e) => return Err((e, in_flight)),
+                };
+                if let Some(headers) = &mut resp.headers {
+                    headers.retain(|k, _| {
+                        !k.eq_ignore_ascii_case("content-length") && !k.eq_ignore_ascii_case("transfer-encoding")
+                    });
+                }
+                resp.body = Some(body);
+            }
+            if keep_alive && !matches!(framing, Framing::UntilClose) {
+                self.upstreams[in_flight.index].release(conn, self.max_idle);
+            }
+            return Ok(resp);
+        }
+
+        let pool = self.upstreams.clone();
+        let max_idle = self.max_idle;
+        Ok(resp.upgrade(move |mut io| async move {
+            let complete = relay_body(&mut conn, &mut io, framing).await.unwrap_or(false);
+            let _ = io.shutdown().await;
+            if complete && keep_alive {
+                pool[in_flight.index].release(conn, max_idle);
+            }
+            drop(in_flight);
+        }))
+    }
+
+    /// Writes the request and reads the response head.
+    async fn exchange(&self, conn: &mut Conn, req: &mut Request) -> Result<ResponseHead> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", req.method, req.path);
+        if !req.headers.keys().any(|k| k.eq_ignore_ascii_case("host")) {
+            head.push_str(&format!("Host: {}\r\n", conn.get_ref().peer_addr()?));
+        }
+        // WebSocket handshakes are forwarded with their upgrade headers, which are otherwise hop-by-hop.
+        let websocket = req.upgrade_protocols().iter().any(|p| p.eq_ignore_ascii_case("websocket"));
+        if websocket {
+            head.push_str("Connection: Upgrade\r\nUpgrade: websocket\r\n");
+        }
+        let mut forwarded_for = None;
+        let mut expect_continue = req.expects_continue();
+        for (key, value) in &req.headers {
+            if key.eq_ignore_ascii_case("expect") {
+                continue;
+            }
+            if is_hop_by_hop(key)
+                || key.eq_ignore_ascii_case("content-length")
+                || key.eq_ignore_ascii_case("transfer-encoding")
+            {
+                continue;
+            }
+            if key.eq_ignore_ascii_case("x-forwarded-for") {
+                forwarded_for = Some(value.as_str());
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        let client = req.remote_addr.ip();
+        match forwarded_for {
+            Some(chain) => head.push_str(&format!("X-Forwarded-For: {}, {}\r\n", chain, client)),
+            None => head.push_str(&format!("X-Forwarded-For: {}\r\n", client)),
+        }
+
+        if !req.body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+        } else if req.stream.is_some() {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        } else {
+            expect_continue = false;
+        }
+        if expect_continue {
+            head.push_str("Expect: 100-continue\r\n");
+        }
+        head.push_str("\r\n");
+        conn.get_mut().write_all(head.as_bytes()).await?;
+
+        // The client's body is only pulled, which tells the client to send it, once the upstream
+        // asks for it. Upstreams that ignore `Expect` wait for the body instead, so give up waiting
+        // for them after a while, like clients do.
+        if expect_continue && let Ok(ready) = tokio::time::timeout(CONTINUE_WAIT, conn.fill_buf()).await {
+            ready?;
+            let (raw, code, headers) = read_head(conn, true).await?;
+            if code != 100 {
+                // An early final response, such as 401 or 413. The body is never sent, so the
+                // upstream may still be waiting for it and the connection can't be reused.
+                return Ok(response_head(req, websocket, &raw, code, headers, false));
+            }
+        }
+
+        let upstream = conn.get_mut();
+        if !req.body.is_empty() {
+            while let Some(chunk) = req.body.chunk().await? {
+                upstream.write_all(&chunk).await?;
+            }
+        } else if let Some(stream) = &mut req.stream {
+            while let Some(chunk) = stream.next_chunk().await? {
+                upstream.write_all(format!("{:X}\r\n", chunk.len()).as_bytes()).await?;
+                upstream.write_all(&chunk).await?;
+                upstream.write_all(b"\r\n").await?;
+            }
+            upstream.write_all(b"0\r\n\r\n").await?;
+        }
+
+        let (raw, code, headers) = read_head(conn, false).await?;
+        Ok(response_head(req, websocket, &raw, code, headers, true))
+    }
+}
+
+/// Reads upstream response heads up to the final one, skipping interim (1xx) responses
+/// except `101 Switching Protocols`, and `100 Continue` if `until_continue` is set.
+/// Returns the raw head along with the parsed status and headers.
+async fn read_head(conn: &mut Conn, until_continue: bool) -> Result<(Vec<u8>, u16, HeaderMap)> {
+    loop {
+        let mut buffer = Vec::with_capacity(1024);
+        loop {
+            let line_start = buffer.len();
+            if (&mut *conn).take((MAX_HEAD_SIZE - line_start) as u64).read_until(b'\n', &mut buffer).await? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let line = &buffer[line_start..];
+            if line == b"\r\n" || line == b"\n" {
+                break;
+            }
+            if buffer.len() >= MAX_HEAD_SIZE {
+                return Err(ErrorKind::InvalidData.into());
+            }
+        }
+        let (code, headers) = parse_response_head(&buffer).ok_or(ErrorKind::InvalidData)?;
+        let interim = (100..200).contains(&code) && code != 101 && !(until_continue && code == 100);
+        if !interim {
+            return Ok((buffer, code, headers));
+        }
+    }
+}
+
+/// Works out how the response body is framed and which headers are relayed to the client.
+/// `reusable` is false if the connection is left in a state where it can't carry another request.
+fn response_head(
+    req: &Request,
+    websocket: bool,
+    raw: &[u8],
+    code: u16,
+    upstream_headers: HeaderMap,
+    reusable: bool,
+) -> ResponseHead {
+    let header = |name: &str| {
+        upstream_headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    };
+    let keep_alive = reusable
+        && raw.starts_with(b"HTTP/1.1")
+        && !header("connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+    let switching = websocket && code == 101;
+    let framing = if switching {
+        Framing::Tunnel
+    } else if req.method == Method::HEAD || code == 204 || code == 304 || code < 200 {
+        Framing::Empty
+    } else if header("transfer-encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked")) {
+        Framing::Chunked
+    } else if let Some(len) = header("content-length").and_then(|v| v.trim().parse().ok()) {
+        Framing::Length(len)
+    } else {
+        Framing::UntilClose
+    };
+
+    let mut headers = HeaderMap::new();
+    for (key, value) in upstream_headers {
+        if !is_hop_by_hop(&key) {
+            headers.insert(key, value);
+        }
+    }
+    if switching {
+        headers.insert("Connection".to_string(), "Upgrade".to_string());
+        headers.insert("Upgrade".to_string(), "websocket".to_string());
+    } else {
+        headers.insert("Connection".to_string(), "close".to_string());
+    }
+    ResponseHead { code, headers, framing, keep_alive }
+}
+
+/// Response head read from an upstream.
+struct ResponseHead {
+    code: u16,
+    headers: HeaderMap,
+    framing: Framing,
+    /// Whether the upstream keeps the connection open after the body.
+    keep_alive: bool,
+}
+
+/// Answers a failed exchange with an upstream.
+fn gateway_error(e: &std::io::Error) -> Response {
+    match e.kind() {
+        ErrorKind::TimedOut => error_response(StatusCode::GatewayTimeout, "upstream timed out"),
+        ErrorKind::FileTooLarge => error_response(StatusCode::BadGateway, "upstream response too large"),
+        _ => error_response(StatusCode::BadGateway, "invalid upstream response"),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    let mut resp = Response::new(status);
+    resp.body(message);
+    resp
+}
+
+/// Runs `future` until `deadline`, failing with `TimedOut` once it passes.
+async fn timed<T>(deadline: Instant, future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout_at(deadline, future).await.map_err(|_| ErrorKind::TimedOut)?
+}
+
+/// Counts a request against an upstream until dropped, for [`Strategy::LeastConnections`].
+struct InFlight {
+    upstreams: Arc<[Upstream]>,
+    index: usize,
+}
+
+impl InFlight {
+    fn new(upstreams: Arc<[Upstream]>, index: usize) -> Self {
+        upstreams[index].in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { upstreams, index }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.upstreams[self.index].in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How the end of an upstream response body is found.
+#[derive(Clone, Copy)]
+enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// The body runs until the upstream closes the connection.
+    UntilClose,
+    /// The upstream switched protocols, bytes flow both ways until either side closes.
+    Tunnel,
+}
+
+/// Reads a chunk size line into `line`, returns `None` at EOF.
+async fn read_chunk_size(conn: &mut Conn, line: &mut Vec<u8>) -> Result<Option<u64>> {
+    line.clear();
+    if (&mut *conn).take(1024).read_until(b'\n', line).await? == 0 {
+        return Ok(None);
+    }
+    let size = parse_chunk_size(line).ok_or(ErrorKind::InvalidData)?;
+    Ok(Some(size as u64))
+}
+
+/// Reads a whole response body, without chunk framing.
+/// Fails with `FileTooLarge` if it's longer than `max_bytes`.
+async fn read_body(conn: &mut Conn, framing: Framing, max_bytes: usize) -> Result<Vec<u8>> {
+    let too_large = || std::io::Error::from(ErrorKind::FileTooLarge);
+    let mut body = Vec::new();
+    match framing {
+        Framing::Empty | Framing::Tunnel => {}
+        Framing::Length(len) => {
+            if len > max_bytes as u64 {
+                return Err(too_large());
+            }
+            body.resize(len as usize, 0);
+            conn.read_exact(&mut body).await?;
+        }
+        Framing::UntilClose => {
+            (&mut *conn).take(max_bytes as u64 + 1).read_to_end(&mut body).await?;
+            if body.len() > max_bytes {
+                return Err(too_large());
+            }
+        }
+        Framing::Chunked => {
+            let mut line = Vec::new();
+            loop {
+                let size = read_chunk_size(conn, &mut line).await?.ok_or(ErrorKind::UnexpectedEof)?;
+                if size == 0 {
+                    break;
+                }
+                if body.len() as u64 + size > max_bytes as u64 {
+                    return Err(too_large());
+                }
+                let start = body.len();
+                body.resize(start + size as usize + 2, 0);
+                conn.read_exact(&mut body[start..]).await?;
+                body.truncate(start + size as usize);
+            }
+            // Trailers, up to the empty line that ends the body.
+            loop {
+                line.clear();
+                if (&mut *conn).take(8192).read_until(b'\n', &mut line).await? == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                if line == b"\r\n" || line == b"\n" {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(body)
+}
+
+/// Copies the response body from the upstream to the client, chunk framing included.
+/// Returns true if the body ended where its framing says, so the connection is ready for another request.
+async fn relay_body(conn: &mut Conn, io: &mut Upgraded, framing: Framing) -> Result<bool> {
+    match framing {
+        Framing::Empty => Ok(true),
+        Framing::Length(len) => Ok(tokio::io::copy(&mut (&mut *conn).take(len), io).await? == len),
+        Framing::UntilClose => {
+            tokio::io::copy(conn, io).await?;
+            Ok(false)
+        }
+        Framing::Tunnel => {
+            tokio::io::copy_bidirectional(io, conn).await?;
+            Ok(false)
+        }
+        Framing::Chunked => loop {
+            let mut line = Vec::new();
+            let Some(size) = read_chunk_size(conn, &mut line).await? else {
+                return Ok(false);
+            };
+            io.write_all(&line).await?;
+            if size == 0 {
+                // Trailers, up to the empty line that ends the body.
+                loop {
+                    line.clear();
+                    if (&mut *conn).take(8192).read_until(b'\n', &mut line).await? == 0 {
+                        return Ok(false);
+                    }
+                    io.write_all(&line).await?;
+                    if line == b"\r\n" || line == b"\n" {
+                        return Ok(true);
+                    }
+                }
+            }
+            let len = size + 2;
+            if tokio::io::copy(&mut (&mut *conn).take(len), io).await? != len {
+                return Ok(false);
+            }
+        },
+    }
+}
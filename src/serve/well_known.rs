@@ -0,0 +1,151 @@
+//! Documents under `/.well-known/` (RFC 8615), such as `security.txt`, ACME challenges and JSON
+//! discovery documents, served with the content types clients expect.
+
+use super::{cached, etag_of};
+use crate::{Handler, Method, Request, Response};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// The `/.well-known/` documents of a site, served by a middleware so names with several segments,
+/// like `matrix/server`, work. Requests for other names under `/.well-known/` go on to the next
+/// layer, like any request outside of it.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, serde_json::json, serve::well_known::{AcmeChallenges, WellKnown}};
+///
+/// let challenges = AcmeChallenges::new();
+/// let well_known = WellKnown::new()
+///     .security_txt("Contact: mailto:security@example.com\nExpires: 2030-01-01T00:00:00Z\n")
+///     .json("matrix/server", &json!({"m.server": "matrix.example.com:443"}))
+///     .acme_challenges(challenges.clone());
+///
+/// let mut router = Router::new();
+/// router.global_middleware(move |req, next| well_known.handle(req, next));
+/// // Once the ACME client has a challenge to answer:
+/// challenges.set("token", "token.thumbprint");
+/// ```
+#[derive(Clone, Default)]
+pub struct WellKnown {
+    documents: Vec<Document>,
+    acme: Option<AcmeChallenges>,
+}
+
+#[derive(Clone)]
+struct Document {
+    name: String,
+    content_type: String,
+    body: Arc<[u8]>,
+    etag: Arc<str>,
+}
+
+impl WellKnown {
+    /// Returns a WellKnown without documents.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `body` as `/.well-known/<name>` with the given `Content-Type`, replacing a document
+    /// of the same name. Documents get an `ETag` and are cached for a day.
+    pub fn document(mut self, name: &str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        let name = name.trim_matches('/').to_string();
+        let body: Arc<[u8]> = body.into().into();
+        let etag = Arc::from(etag_of(&body));
+        self.documents.retain(|document| document.name != name);
+        self.documents.push(Document { name, content_type: content_type.to_string(), body, etag });
+        self
+    }
+
+    /// Serves `/.well-known/security.txt` (RFC 9116), which tells researchers how to report
+    /// vulnerabilities. It must contain a `Contact` and an `Expires` field.
+    pub fn security_txt(self, contents: &str) -> Self {
+        self.document("security.txt", "text/plain; charset=utf-8", contents)
+    }
+
+    /// Serves `value` as the JSON document `/.well-known/<name>`, such as `matrix/client` or
+    /// `openid-configuration`. Names ending in `host-meta.json` or `webfinger` get the
+    /// `application/jrd+json` type of RFC 6415 and RFC 7033, others `application/json`.
+    pub fn json(self, name: &str, value: &Value) -> Self {
+        let content_type = if name.ends_with("host-meta.json") || name.ends_with("webfinger") {
+            "application/jrd+json"
+        } else {
+            "application/json"
+        };
+        self.document(name, content_type, value.to_string())
+    }
+
+    /// Answers ACME HTTP-01 challenges (RFC 8555 section 8.3) at `/.well-known/acme-challenge/<token>`
+    /// with the key authorizations set on `challenges`.
+    pub fn acme_challenges(mut self, challenges: AcmeChallenges) -> Self {
+        self.acme = Some(challenges);
+        self
+    }
+
+    /// Returns the response for the document `name`, the path after `/.well-known/`, or `None` if
+    /// there is no such document.
+    pub fn respond(&self, req: &Request, name: &str) -> Option<Response> {
+        let name = name.trim_matches('/');
+        if let Some(acme) = &self.acme
+            && let Some(token) = name.strip_prefix("acme-challenge/")
+        {
+            let key_authorization = acme.get(token)?;
+            return Some(
+                Response::ok(key_authorization)
+                    .header("Content-Type", "application/octet-stream")
+                    .header("Cache-Control", "no-store"),
+            );
+        }
+        let document = self.documents.iter().find(|document| document.name == name)?;
+        Some(cached(req, &document.body, &document.etag, &document.content_type))
+    }
+
+    /// Middleware serving GET and HEAD requests for the documents, other requests go on to `next`.
+    pub fn handle(&self, req: Request, next: Handler) -> impl Future<Output = Response> + Send + use<> {
+        let path = req.path.split('?').next().unwrap_or_default();
+        let served = matches!(req.method, Method::GET | Method::HEAD)
+            .then(|| path.strip_prefix("/.well-known/"))
+            .flatten()
+            .and_then(|name| self.respond(&req, name));
+        async move {
+            match served {
+                Some(resp) => resp,
+                None => next(req).await,
+            }
+        }
+    }
+}
+
+/// Key authorizations of pending ACME HTTP-01 challenges, by token, see
+/// [`WellKnown::acme_challenges`]. Clones share the same challenges, so the ACME client can set
+/// them while the server answers.
+#[derive(Clone, Default)]
+pub struct AcmeChallenges {
+    pending: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl AcmeChallenges {
+    /// Returns an AcmeChallenges without challenges.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers the challenge for `token` with `key_authorization` until it's removed.
+    pub fn set(&self, token: &str, key_authorization: &str) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(token.to_string(), key_authorization.to_string());
+        }
+    }
+
+    /// Stops answering the challenge for `token`, once the certificate was issued.
+    pub fn remove(&self, token: &str) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(token);
+        }
+    }
+
+    fn get(&self, token: &str) -> Option<String> {
+        self.pending.lock().ok()?.get(token).cloned()
+    }
+}
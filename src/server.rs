@@ -1,22 +1,212 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncRead, ReadBuf, BufReader, AsyncBufReadExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncRead, AsyncWrite, ReadBuf, BufReader, AsyncBufReadExt, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::pin::Pin;
 use std::task::{Poll, Context};
-use crate::route::Router;
-use crate::types::{HeaderMap, Method, ParamMap, Request, Response, Version};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use crate::body::{Body, Unread};
+#[cfg(unix)]
+use crate::handoff;
+use crate::extensions::Extensions;
+use crate::context::{RequestContext, RequestLog};
+use crate::panic::{BacktraceSlot, CaptureBacktrace, PanicReport};
+use crate::route::{RouteSlot, Router};
+use crate::stats::{Counted, ServerStats, Tally};
+use crate::upgrade::Upgraded;
+use crate::types::{HeaderMap, Method, ParamMap, Request, RequestHead, Response, StatusCode, Version};
 use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use crate::proto::ParseError;
 
 /// Server that wraps the whole HTTP server in itself.
 pub struct Server {
-    addr: &'static str,
+    addr: String,
     router: Arc<Router>,
+    config: Config,
+}
+
+pub(crate) type PanicCallback = Arc<dyn Fn(&PanicReport) + Send + Sync>;
+
+pub(crate) type SlowRequestCallback = Arc<dyn Fn(&SlowRequest) + Send + Sync>;
+
+pub(crate) type RequestLogCallback = Arc<dyn Fn(&RequestLog) + Send + Sync>;
+
+pub(crate) type WriteErrorCallback = Arc<dyn Fn(&WriteFailure) + Send + Sync>;
+
+type BackgroundTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+type StartupError = Box<dyn std::error::Error + Send + Sync>;
+
+pub(crate) type StartCallback =
+    Arc<dyn Fn(SocketAddr) -> Pin<Box<dyn Future<Output = Result<(), StartupError>> + Send>> + Send + Sync>;
+
+/// A request whose handler took longer than the threshold set with [`Server::on_slow_request`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SlowRequest {
+    pub method: Method,
+    /// The pattern of the route that handled the request, such as `/users/:id`, or `None` if no route matched.
+    pub route: Option<String>,
+    pub path: String,
+    /// How long the handler took, including its middleware.
+    pub duration: Duration,
+    pub remote_addr: SocketAddr,
+    /// See [`Request.id`](Request::id).
+    pub id: u64,
+}
+
+/// A response that could not be written, passed to the callback set with [`Server::on_write_error`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct WriteFailure {
+    pub remote_addr: SocketAddr,
+    /// See [`Request.id`](Request::id).
+    pub id: u64,
+    /// True if the failure happened while streaming the body, after the head was sent.
+    pub streaming: bool,
+    pub kind: ErrorKind,
+    /// The error message, such as `Broken pipe`.
+    pub reason: String,
+}
+
+/// Flag telling a [`Server`] whether it's ready for requests, see [`Server::readiness`].
+/// Starts out not ready. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    /// Returns a flag that isn't ready yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the server ready, or not ready again, e.g. to drain it before a shutdown.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Release);
+    }
+
+    /// Returns true if the server is ready for requests.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+}
+
+/// Connection-level options shared by every connection of a Server.
+#[derive(Clone, Default)]
+pub(crate) struct Config {
+    pub(crate) strict_headers: bool,
+    pub(crate) require_host: bool,
+    pub(crate) allowed_hosts: Option<Vec<String>>,
+    pub(crate) max_uri_length: Option<usize>,
+    pub(crate) max_path_segments: Option<usize>,
+    pub(crate) encoded_path_policy: EncodedPathPolicy,
+    pub(crate) unexpected_body_policy: UnexpectedBodyPolicy,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) max_requests_per_connection: Option<usize>,
+    pub(crate) bandwidth_limit: Option<u64>,
+    pub(crate) server_timing: bool,
+    pub(crate) stats: ServerStats,
+    pub(crate) on_accounting: Option<AccountingCallback>,
+    pub(crate) request_ids: Arc<AtomicU64>,
+    pub(crate) on_panic: Option<PanicCallback>,
+    pub(crate) slow_requests: Option<(Duration, SlowRequestCallback)>,
+    pub(crate) request_log: Option<RequestLogCallback>,
+    pub(crate) on_write_error: Option<WriteErrorCallback>,
+    pub(crate) on_start: Option<StartCallback>,
+    pub(crate) readiness: Option<Readiness>,
+    /// Tasks registered with `Server::spawn_background`, taken by the first run.
+    background: Arc<std::sync::Mutex<Vec<BackgroundTask>>>,
+    #[cfg(feature = "rustls")]
+    pub(crate) tls: Option<crate::tls::TlsConfig>,
+    #[cfg(feature = "http3")]
+    pub(crate) http3: bool,
+    /// Alt-Svc header advertising HTTP/3, set once its socket is bound.
+    pub(crate) alt_svc: Option<String>,
+}
+
+impl Config {
+    /// Returns the id for the next request, see `Request.id`.
+    pub(crate) fn next_request_id(&self) -> u64 {
+        self.request_ids.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+pub(crate) type AccountingCallback = Arc<dyn Fn(&Accounting) + Send + Sync>;
+
+/// The bytes a request and its response took on the wire, passed to the callback set with
+/// [`Server::on_accounting`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Accounting {
+    pub method: Method,
+    pub path: String,
+    pub status: StatusCode,
+    pub remote_addr: SocketAddr,
+    /// See [`Request.id`](Request::id).
+    pub id: u64,
+    /// Size of the request head, from the request line to the empty line ending the headers.
+    pub request_head_bytes: u64,
+    /// Bytes of the request body read off the connection, chunked framing included.
+    /// A body the handler didn't read is only counted as far as it was discarded before closing.
+    pub request_body_bytes: u64,
+    /// Bytes written for the response, head and body, a streamed body included.
+    pub response_bytes: u64,
+    /// False if the client went away before a streamed body was completely written.
+    pub complete: bool,
+}
+
+/// What a [`Server`] does with percent-encoded slashes (`%2F`) and null bytes (`%00`) in request
+/// paths, see [`Server::encoded_path_policy`]. They're a classic source of path confusion, where
+/// the router and the file system disagree about where a path's segments are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncodedPathPolicy {
+    /// Answer requests whose path contains either with 400 Bad Request.
+    Reject,
+    /// Decode `%2F` into `/` before routing, so it separates segments like a plain slash.
+    /// A null byte is never meaningful in a path, so `%00` is still rejected.
+    Decode,
+    /// Leave the path as it was sent.
+    #[default]
+    PassThrough,
+}
+
+/// What a [`Server`] does with bodies sent on GET, HEAD and DELETE requests, which have no
+/// defined meaning for a body, see [`Server::unexpected_body_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnexpectedBodyPolicy {
+    /// Read and discard the body before the handler runs, which then sees an empty body.
+    /// Bodies over 1 MiB are answered with 400 Bad Request instead.
+    Ignore,
+    /// Answer the request with 400 Bad Request.
+    Reject,
+    /// Hand the body to the handler like on any other method.
+    #[default]
+    PassThrough,
+}
+
+/// Largest body [`UnexpectedBodyPolicy::Ignore`] reads to discard it.
+const MAX_IGNORED_BODY: usize = 1024 * 1024;
+
+/// Returns true for methods whose requests have no defined meaning for a body.
+fn is_bodyless(method: &Method) -> bool {
+    matches!(method, Method::GET | Method::HEAD | Method::DELETE)
 }
 
 impl Server {
     /// Returns a new Server struct.
-    /// Requires an address and router.
+    /// Requires an address and router. The address, such as `0.0.0.0:8080` or `localhost:8080`,
+    /// is resolved when the server binds it, so it can come from configuration read at runtime.
+    /// Binding port 0 picks a free port, see [`ServerHandle::local_addr`] and [`Server::on_start`]
+    /// for the address actually bound.
     ///
     /// # Example:
     /// ```
@@ -24,13 +214,455 @@ impl Server {
     ///
     /// let mut router = Router::new();
     /// let server = Server::new("0.0.0.0:8080", router);
+    /// let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    /// let server = Server::new(format!("0.0.0.0:{}", port), Router::new());
+    /// ```
+    pub fn new(addr: impl Into<String>, router: Router) -> Self {
+        Server { addr: addr.into(), router: Arc::from(router), config: Config::default() }
+    }
+
+    /// Returns a Server that speaks HTTPS, with the certificate and key in `tls`.
+    /// Connections are decrypted before parsing, so handlers and options work as with [`Server::new`].
+    /// The handshake must finish within the [read timeout](Server::read_timeout), if one is set.
+    /// Clients that support HTTP/2 get it, picked during the handshake with ALPN. Each of their
+    /// requests is handled like one sent over HTTP/1.1, with `Request.version` set to
+    /// [`Version::Http2`](crate::Version::Http2). An HTTP/2 connection serves many requests,
+    /// the read timeout closes it once it's idle.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Router, Server, tls::TlsConfig};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let tls = TlsConfig::from_pem_files("cert.pem", "key.pem").unwrap();
+    ///     let server = Server::new_tls("0.0.0.0:8443", Router::new(), tls);
+    ///     let _ = server.run().await;
+    /// }
+    /// ```
+    #[cfg(feature = "rustls")]
+    pub fn new_tls(addr: impl Into<String>, router: Router, tls: crate::tls::TlsConfig) -> Self {
+        let mut server = Server::new(addr, router);
+        server.config.tls = Some(tls);
+        server
+    }
+
+    /// Enables or disables HTTP/3 for a server made with [`Server::new_tls`]. Disabled by default.
+    /// Requests arrive over QUIC on a UDP socket bound to the same port as the TCP listener, and are
+    /// handled like the others, with `Request.version` set to [`Version::Http3`](crate::Version::Http3).
+    /// Clients learn about it from the `Alt-Svc` header added to responses sent over HTTP/1.1 and
+    /// HTTP/2, unless the handler set one. Running fails with [`Error::Bind`](crate::Error::Bind)
+    /// if the server has no TLS config or the UDP port can't be bound.
+    /// The UDP socket isn't handed over by [`ServerHandle::restart`], so a successor with HTTP/3
+    /// enabled fails to bind it while this server is still running.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Router, Server, tls::TlsConfig};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let tls = TlsConfig::from_pem_files("cert.pem", "key.pem").unwrap();
+    ///     let server = Server::new_tls("0.0.0.0:443", Router::new(), tls).http3(true);
+    ///     let _ = server.run().await;
+    /// }
+    /// ```
+    #[cfg(feature = "http3")]
+    pub fn http3(mut self, enabled: bool) -> Self {
+        self.config.http3 = enabled;
+        self
+    }
+
+    /// Enables or disables strict header validation. Disabled by default.
+    /// In strict mode, requests with header names or values containing control characters,
+    /// whitespace before the colon, or obsolete line folding are answered with 400 Bad Request.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).strict_headers(true);
+    /// ```
+    pub fn strict_headers(mut self, strict: bool) -> Self {
+        self.config.strict_headers = strict;
+        self
+    }
+
+    /// Requires HTTP/1.1 requests to carry exactly one `Host` header, as RFC 9112 section 3.2 demands.
+    /// Disabled by default. Requests without one, or with several, are answered with 400 Bad Request.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).require_host(true);
+    /// ```
+    pub fn require_host(mut self, require: bool) -> Self {
+        self.config.require_host = require;
+        self
+    }
+
+    /// Only serves requests whose `Host` names one of `hosts`, answering others with
+    /// 421 Misdirected Request. This blocks DNS rebinding attacks, where a hostile domain is
+    /// pointed at a server on a private network. Implies [`Server::require_host`].
+    /// Entries match case-insensitively, an entry without a port matches any port and an entry
+    /// starting with `*.` matches every subdomain of the rest.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new())
+    ///     .allowed_hosts(&["example.com", "*.example.com", "localhost:8080"]);
+    /// ```
+    pub fn allowed_hosts(mut self, hosts: &[&str]) -> Self {
+        self.config.require_host = true;
+        self.config.allowed_hosts = Some(hosts.iter().map(|host| host.to_ascii_lowercase()).collect());
+        self
+    }
+
+    /// Limits the length of the request target (path and query) in bytes, longer ones are
+    /// answered with 414 URI Too Long. Without a limit the target only has to fit in the 16 KiB
+    /// allowed for the request head.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).max_uri_length(2048);
     /// ```
-    pub fn new(addr: &'static str, router: Router) -> Self {
-        Server { addr, router: Arc::from(router) }
+    pub fn max_uri_length(mut self, max: usize) -> Self {
+        self.config.max_uri_length = Some(max);
+        self
+    }
+
+    /// Limits the number of `/`-separated segments in the request path, requests with more are
+    /// answered with 414 URI Too Long before they reach the router.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).max_path_segments(32);
+    /// ```
+    pub fn max_path_segments(mut self, max: usize) -> Self {
+        self.config.max_path_segments = Some(max);
+        self
+    }
+
+    /// Sets how percent-encoded slashes and null bytes in request paths are handled, before
+    /// routing and before handlers see `Request.path`. Passed through by default.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{EncodedPathPolicy, Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).encoded_path_policy(EncodedPathPolicy::Reject);
+    /// ```
+    pub fn encoded_path_policy(mut self, policy: EncodedPathPolicy) -> Self {
+        self.config.encoded_path_policy = policy;
+        self
+    }
+
+    /// Sets how bodies sent on GET, HEAD and DELETE requests are handled. Passed through by default.
+    /// Either way the body is consumed or refused before the response, so it's never mistaken for
+    /// the start of another request.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server, UnexpectedBodyPolicy};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).unexpected_body_policy(UnexpectedBodyPolicy::Ignore);
+    /// ```
+    pub fn unexpected_body_policy(mut self, policy: UnexpectedBodyPolicy) -> Self {
+        self.config.unexpected_body_policy = policy;
+        self
+    }
+
+    /// Sets how long a client may take to send the request line and headers. Disabled by default.
+    /// Clients that stall halfway through are answered with 408 Request Timeout,
+    /// connections that never start a request are closed without a response.
+    ///
+    /// # Example:
+    /// ```
+    /// use std::time::Duration;
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).read_timeout(Duration::from_secs(10));
+    /// ```
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long a write to a client may stall before the connection is dropped. Disabled by default.
+    /// A client that stops reading the response, or reads it extremely slowly, otherwise keeps
+    /// its connection and the task sending to it alive for as long as it likes.
+    ///
+    /// # Example:
+    /// ```
+    /// use std::time::Duration;
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).write_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how many requests a client may send on one connection before it has to open another,
+    /// so load balancers get to spread long-lived clients over their backends again. Disabled by default.
+    /// HTTP/2 connections are sent GOAWAY once `max` streams were opened, the ones open are still
+    /// answered. HTTP/1.1 connections serve a single request, so with a limit set their responses
+    /// say `Connection: close` rather than leaving the client to find out. A `max` of 0 counts as 1.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).max_requests_per_connection(1000);
+    /// ```
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.config.max_requests_per_connection = Some(max.max(1));
+        self
+    }
+
+    /// Limits how fast streamed response bodies are sent on each connection, in bytes per second.
+    /// Disabled by default. A connection may send up to one second's worth of bytes at once,
+    /// after that it's paced to the limit, so a few large downloads can't take all the bandwidth.
+    /// Responses can set a lower limit of their own with [`Response::bandwidth_limit`].
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).bandwidth_limit(1024 * 1024);
+    /// ```
+    pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.config.bandwidth_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Adds a `Server-Timing` header to every response, which browsers show in their developer
+    /// tools: `parse` is the time from the arrival of the request line until the handler started,
+    /// `handler` the time the handler took, middleware included. Writing the response can't be
+    /// timed before its head is sent, so it isn't included. Disabled by default, since it tells
+    /// clients how long requests take to process.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).server_timing(true);
+    /// ```
+    pub fn server_timing(mut self, enabled: bool) -> Self {
+        self.config.server_timing = enabled;
+        self
+    }
+
+    /// Calls `f` with the message, backtrace and request of every handler that panics, for example
+    /// to send crashes to an error tracker. The client still gets a 500 response and the panic is
+    /// still printed by the panic hook that was installed before.
+    /// Backtraces are captured with a process-wide panic hook, installed on the first call, that
+    /// only records panics of handlers run by servers with a callback set.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).on_panic(|report| {
+    ///     eprintln!("{} {} panicked: {}\n{}", report.request.method, report.request.path, report.message, report.backtrace);
+    /// });
+    /// ```
+    pub fn on_panic<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&PanicReport) + Send + Sync + 'static,
+    {
+        crate::panic::install_hook();
+        self.config.on_panic = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` for every request whose handler, middleware included, takes longer than
+    /// `threshold`, with its method, matched route, duration and peer, so latency outliers show
+    /// up without tracing infrastructure. The time is measured until the handler returns its
+    /// response, a streamed body isn't included.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).on_slow_request(Duration::from_millis(500), |slow| {
+    ///     eprintln!("slow: {} {} took {:?} for {}", slow.method, slow.route.as_deref().unwrap_or(&slow.path), slow.duration, slow.remote_addr);
+    /// });
+    /// ```
+    pub fn on_slow_request<F>(mut self, threshold: Duration, f: F) -> Self
+    where
+        F: Fn(&SlowRequest) + Send + Sync + 'static,
+    {
+        self.config.slow_requests = Some((threshold, Arc::new(f)));
+        self
+    }
+
+    /// Calls `f` when writing a response fails, for example because the client went away or
+    /// stopped reading, once the connection has been torn down: a streamed body is dropped, which
+    /// fails the producer's next write, and the failure is counted in
+    /// [`ServerStats::write_errors`](crate::ServerStats::write_errors).
+    /// Use it to release what the response was holding on to, or to log the reason.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).on_write_error(|failure| {
+    ///     eprintln!("response {} to {} failed: {}", failure.id, failure.remote_addr, failure.reason);
+    /// });
+    /// ```
+    pub fn on_write_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&WriteFailure) + Send + Sync + 'static,
+    {
+        self.config.on_write_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` once for every handled request, when its response is ready, with the method,
+    /// path, status, duration, peer and the fields middleware and handlers added to its
+    /// [`RequestContext`], for structured logging without global state.
+    /// Requests rejected before reaching the router aren't logged.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).on_request_log(|log| {
+    ///     println!("{}", log);
+    /// });
+    /// ```
+    pub fn on_request_log<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RequestLog) + Send + Sync + 'static,
+    {
+        self.config.request_log = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` once the response to a request has been written, with the size of the request
+    /// head and body and of the response, streamed bodies included, as they went over the
+    /// connection. Suits access logs and billing that need exact byte counts, which
+    /// [`on_request_log`](Self::on_request_log) can't give as it runs before the response is sent.
+    /// A client that abandons a streamed body is still reported, with what it was sent.
+    /// Only HTTP/1.1 connections are counted, upgraded connections and requests rejected before
+    /// reaching the router aren't.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).on_accounting(|bytes| {
+    ///     println!("id={} in={} out={}", bytes.id, bytes.request_head_bytes + bytes.request_body_bytes, bytes.response_bytes);
+    /// });
+    /// ```
+    pub fn on_accounting<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Accounting) + Send + Sync + 'static,
+    {
+        self.config.on_accounting = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets an async hook run once the listener is bound, before the first connection is accepted,
+    /// with the address it is bound to. Suits warming caches, running migrations or registering
+    /// with service discovery. Clients connecting meanwhile wait in the socket's backlog.
+    /// If the hook fails, the listener is closed and [`Server::run`] or [`Server::spawn`] return
+    /// [`Error::Startup`](crate::Error::Startup) with its error.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Router, Server};
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).on_start(|addr| async move {
+    ///     let config = std::fs::read_to_string("/etc/app/config.toml")?;
+    ///     println!("loaded {} bytes of config, serving on {}", config.len(), addr);
+    ///     Ok::<_, std::io::Error>(())
+    /// });
+    /// ```
+    pub fn on_start<F, Fut, E>(mut self, f: F) -> Self
+    where
+        F: Fn(SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        self.config.on_start = Some(Arc::new(move |addr| {
+            let started = f(addr);
+            Box::pin(async move { started.await.map_err(Into::into) })
+        }));
+        self
+    }
+
+    /// Answers every request with 503 Service Unavailable until `readiness` is marked ready.
+    /// Unlike [`Server::on_start`], connections are accepted from the start, so the port is claimed
+    /// and load balancers probing it see the server come up, then get ready, which suits rolling
+    /// deployments. Handlers and middleware don't run for requests answered with 503.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Readiness, Router, Server};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let readiness = Readiness::new();
+    ///     let server = Server::new("0.0.0.0:8080", Router::new()).readiness(readiness.clone());
+    ///     tokio::spawn(async move {
+    ///         // Connect to the database, fill caches...
+    ///         readiness.set_ready(true);
+    ///     });
+    ///     let _ = server.run().await;
+    /// }
+    /// ```
+    pub fn readiness(mut self, readiness: Readiness) -> Self {
+        self.config.readiness = Some(readiness);
+        self
+    }
+
+    /// Runs `task` alongside the server, started once the listener is bound.
+    /// On shutdown, once the connections in progress have finished, tasks still running are
+    /// cancelled, and the server returns only after they have been dropped. Suits periodic jobs
+    /// such as refreshing a cache or removing expired sessions. Tasks run with the server's first
+    /// [`run`](Server::run), [`run_until`](Server::run_until) or [`spawn`](Server::spawn) only.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{tokio, Router, Server};
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::new("0.0.0.0:8080", Router::new()).spawn_background(async {
+    ///     let mut interval = tokio::time::interval(Duration::from_secs(60));
+    ///     loop {
+    ///         interval.tick().await;
+    ///         println!("removing expired sessions");
+    ///     }
+    /// });
+    /// ```
+    pub fn spawn_background<F>(self, task: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if let Ok(mut background) = self.config.background.lock() {
+            background.push(Box::pin(task));
+        }
+        self
+    }
+
+    /// Returns a handle to this server's live statistics, see [`ServerStats`].
+    pub fn stats(&self) -> ServerStats {
+        self.config.stats.clone()
     }
 
     /// Starts listening and handling requests on the address we defined in new().
-    /// Returns an std::io::Result enum if there was an error.
+    /// Returns an [`Error`](crate::Error) if the listener could not be bound or accepting failed.
     ///
     /// # Example:
     /// ```no_run
@@ -43,161 +675,1369 @@ impl Server {
     ///     let _ = server.run().await;
     /// }
     /// ```
-    pub async fn run(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(&self.addr).await?;
-        println!("Server running on {}", &self.addr);
+    pub async fn run(&self) -> Result<(), crate::Error> {
+        self.run_until(std::future::pending()).await
+    }
 
-        loop {
-            let (socket, remote_addr) = listener.accept().await?;
-            let router = self.router.clone();
-            tokio::spawn(async move {
-                if let Err(e) = handle_conn(socket, remote_addr, router).await {
-                    eprintln!("error, conn: {}, err: {:?}", remote_addr, e);
-                }
-            });
+    /// Like [`Server::run`], but stops accepting connections once `shutdown` completes,
+    /// then waits for connections in progress to finish before returning.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Router, Server};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let server = Server::new("0.0.0.0:8080", Router::new());
+    ///     let _ = server.run_until(async {
+    ///         let _ = tokio::signal::ctrl_c().await;
+    ///     }).await;
+    /// }
+    /// ```
+    pub async fn run_until<F>(&self, shutdown: F) -> Result<(), crate::Error>
+    where
+        F: Future<Output = ()>,
+    {
+        let (listeners, config) = self.start().await?;
+        serve(listeners, self.router.clone(), config, shutdown).await
+    }
+
+    /// Binds the listener and starts the accept loop in a background task.
+    /// Returns a [`ServerHandle`] to get the bound address, shut the server down and await its completion.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Router, Server};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let server = Server::new("127.0.0.1:0", Router::new());
+    ///     let handle = server.spawn().await.unwrap();
+    ///     println!("listening on {}", handle.local_addr());
+    ///
+    ///     handle.shutdown();
+    ///     let _ = handle.await;
+    /// }
+    /// ```
+    pub async fn spawn(&self) -> Result<ServerHandle, crate::Error> {
+        let (listeners, config) = self.start().await?;
+        let local_addr = listeners.tcp.local_addr().map_err(crate::Error::Bind)?;
+        #[cfg(unix)]
+        let listener_fd = std::os::fd::AsRawFd::as_raw_fd(&listeners.tcp);
+        let notify = Arc::new(Notify::new());
+
+        let shutdown = notify.clone();
+        let task = tokio::spawn(serve(
+            listeners,
+            self.router.clone(),
+            config,
+            async move { shutdown.notified().await },
+        ));
+
+        Ok(ServerHandle {
+            local_addr,
+            #[cfg(unix)]
+            listener_fd,
+            shutdown: notify,
+            stopping: AtomicBool::new(false),
+            task,
+        })
+    }
+
+    /// Like [`Server::run_until`], but accepts connections and reads and writes their sockets with
+    /// io_uring, on a tokio-uring runtime started on the calling thread, which this blocks until
+    /// the server has stopped. Call it from `main` instead of `#[tokio::main]`. Handlers run on
+    /// that runtime too, so run one server per core, each on its own thread, to use more of them.
+    ///
+    /// Experimental, and a compatibility shim rather than a faster path: the connection handling
+    /// needs `Send` streams and tokio-uring's aren't, so every connection is bridged to it through
+    /// an in-memory pipe. That costs an extra copy each way and a task per connection, which eats
+    /// what io_uring saves, don't expect more throughput than [`Server::run`].
+    /// TLS isn't supported, and neither is taking over the listener of a [`ServerHandle::restart`].
+    /// Fails with [`Error::Bind`](crate::Error::Bind) if the kernel doesn't support io_uring.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{Router, Server};
+    ///
+    /// fn main() {
+    ///     let server = Server::new("0.0.0.0:8080", Router::new());
+    ///     let _ = server.run_io_uring(std::future::pending());
+    /// }
+    /// ```
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn run_io_uring<F>(&self, shutdown: F) -> Result<(), crate::Error>
+    where
+        F: Future<Output = ()>,
+    {
+        #[cfg(feature = "rustls")]
+        if self.config.tls.is_some() {
+            let unsupported = Error::new(ErrorKind::Unsupported, "the io_uring backend doesn't support TLS");
+            return Err(crate::Error::Bind(unsupported));
+        }
+        let runtime = tokio_uring::Runtime::new(&tokio_uring::builder()).map_err(crate::Error::Bind)?;
+        runtime.block_on(async {
+            let addr = tokio::net::lookup_host(self.addr.as_str())
+                .await
+                .and_then(|mut addrs| addrs.next().ok_or_else(|| Error::from(ErrorKind::AddrNotAvailable)))
+                .map_err(crate::Error::Bind)?;
+            let listener = tokio_uring::net::TcpListener::bind(addr).map_err(crate::Error::Bind)?;
+            let local_addr = listener.local_addr().map_err(crate::Error::Bind)?;
+            println!("Server running on {} with io_uring", local_addr);
+            if let Some(on_start) = &self.config.on_start {
+                on_start(local_addr).await.map_err(crate::Error::Startup)?;
+            }
+            let mut background = spawn_background(&self.config);
+            let result = crate::uring::serve(listener, self, shutdown).await;
+            background.shutdown().await;
+            result
+        })
+    }
+
+    /// Handles a single connection from any transport that implements `AsyncRead + AsyncWrite`,
+    /// such as a Unix socket, a TLS stream or an in-memory `tokio::io::duplex` pipe, using this
+    /// server's router and options. `remote_addr` becomes `Request.remote_addr`, transports that
+    /// don't have one can pass a placeholder.
+    /// The returned future doesn't borrow the server, so it can be spawned.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Router, Server};
+    /// use std::net::SocketAddr;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let server = Server::new("unix", Router::new());
+    ///     let listener = tokio::net::UnixListener::bind("/tmp/zep.sock").unwrap();
+    ///     loop {
+    ///         let (stream, _) = listener.accept().await.unwrap();
+    ///         tokio::spawn(server.serve_connection(stream, SocketAddr::from(([0, 0, 0, 0], 0))));
+    ///     }
+    /// }
+    /// ```
+    pub fn serve_connection<S>(
+        &self,
+        io: S,
+        remote_addr: SocketAddr,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send + use<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        self.serve_connection_with_id(io, remote_addr, self.config.next_request_id())
+    }
+
+    fn serve_connection_with_id<S>(
+        &self,
+        io: S,
+        remote_addr: SocketAddr,
+        id: u64,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send + use<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        let (read, write) = tokio::io::split(io);
+        let config = Arc::new(self.config.clone());
+        handle_conn(Box::new(read), Box::new(write), remote_addr, id, Extensions::new(), self.router.clone(), config)
+    }
+
+    /// Opens an in-memory connection to this server and returns the client's end of it.
+    /// The connection is served in a background task like one accepted from the listener, but
+    /// over a `tokio::io::duplex` pipe instead of a socket, so tests can exercise the whole
+    /// server, including streaming and upgrades, without network access. Must be called from
+    /// within a tokio runtime.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{tokio, Router, Method, Request, Response, Server};
+    /// use zep::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    ///
+    /// async fn hello(_req: Request) -> Response {
+    ///     Response::ok("hello")
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut router = Router::new();
+    ///     router.route(Method::GET, "/", hello);
+    ///     let server = Server::new("memory", router);
+    ///
+    ///     let mut conn = server.connect_in_memory();
+    ///     conn.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    ///     let mut resp = String::new();
+    ///     conn.read_to_string(&mut resp).await.unwrap();
+    ///     assert!(resp.ends_with("\r\n\r\nhello"));
+    /// }
+    /// ```
+    pub fn connect_in_memory(&self) -> DuplexStream {
+        let (client, conn) = tokio::io::duplex(64 * 1024);
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let id = self.config.next_request_id();
+        let served = self.serve_connection_with_id(conn, remote_addr, id);
+        tokio::spawn(async move {
+            if let Err(e) = served.await {
+                eprintln!("error, request: {}, conn: in-memory, err: {:?}", id, e);
+            }
+        });
+        client
+    }
+
+    /// Binds the listeners and runs the hook set with [`Server::on_start`]. Returns them with the
+    /// config their connections share.
+    async fn start(&self) -> Result<(Listeners, Arc<Config>), crate::Error> {
+        let listener = self.bind().await?;
+        #[cfg_attr(not(feature = "http3"), allow(unused_mut))]
+        let mut config = self.config.clone();
+        #[cfg(feature = "http3")]
+        let quic = match (config.http3, &config.tls) {
+            (false, _) => None,
+            (true, None) => {
+                return Err(crate::Error::Bind(Error::new(ErrorKind::InvalidInput, "HTTP/3 requires TLS")));
+            }
+            (true, Some(tls)) => {
+                let addr = listener.local_addr().map_err(crate::Error::Bind)?;
+                let endpoint = crate::h3::bind(addr, tls, &config).map_err(crate::Error::Bind)?;
+                let port = endpoint.local_addr().map_err(crate::Error::Bind)?.port();
+                config.alt_svc = Some(format!("h3=\":{}\"; ma=86400", port));
+                Some(endpoint)
+            }
+        };
+        if let Some(on_start) = &config.on_start {
+            let addr = listener.local_addr().map_err(crate::Error::Bind)?;
+            on_start(addr).await.map_err(crate::Error::Startup)?;
+        }
+        let listeners = Listeners {
+            tcp: listener,
+            #[cfg(feature = "http3")]
+            quic,
+        };
+        Ok((listeners, Arc::new(config)))
+    }
+
+    /// Binds the configured address, unless a predecessor handed over a listener bound to it,
+    /// see [`ServerHandle::restart`].
+    async fn bind(&self) -> Result<TcpListener, crate::Error> {
+        #[cfg(unix)]
+        if let Ok(addrs) = tokio::net::lookup_host(self.addr.as_str()).await
+            && let Some(inherited) = handoff::take_inherited(&addrs.collect::<Vec<_>>())
+        {
+            let listener = inherited.and_then(TcpListener::from_std).map_err(crate::Error::Bind)?;
+            println!("Server running on inherited listener {}", bound_addr(&listener, &self.addr));
+            return Ok(listener);
+        }
+        let listener = TcpListener::bind(self.addr.as_str()).await.map_err(crate::Error::Bind)?;
+        println!("Server running on {}", bound_addr(&listener, &self.addr));
+        Ok(listener)
+    }
+}
+
+/// Returns the address `listener` is bound to for logging, `addr` if it's unknown.
+fn bound_addr(listener: &TcpListener, addr: &str) -> String {
+    listener.local_addr().map_or_else(|_| addr.to_string(), |addr| addr.to_string())
+}
+
+/// Sockets a server accepts connections on.
+struct Listeners {
+    tcp: TcpListener,
+    /// QUIC endpoint for HTTP/3, see [`Server::http3`].
+    #[cfg(feature = "http3")]
+    quic: Option<quinn::Endpoint>,
+}
+
+/// Handle to a server started with [`Server::spawn`].
+/// Awaiting the handle waits until the accept loop has stopped and all connections are finished.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    #[cfg(unix)]
+    listener_fd: std::os::fd::RawFd,
+    shutdown: Arc<Notify>,
+    stopping: AtomicBool,
+    task: JoinHandle<Result<(), crate::Error>>,
+}
+
+impl ServerHandle {
+    /// Returns the address the server is bound to, useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting new connections. Connections in progress are allowed to finish,
+    /// await the handle to wait for them.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        self.shutdown.notify_one();
+    }
+
+    /// Restarts without dropping connections: starts the current executable again with the same
+    /// arguments, hands it the listening socket, then shuts down like [`ServerHandle::shutdown`].
+    /// The first of the successor's servers configured with the socket's address picks it up
+    /// instead of binding, and clients
+    /// connecting in between wait in the socket's backlog. Await the handle to let the connections
+    /// in progress finish, then exit.
+    /// Returns the successor process, or an error if the server is already shutting down or the
+    /// process could not be started, in which case this server keeps running.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{tokio, Router, Server};
+    /// use tokio::signal::unix::{signal, SignalKind};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let handle = Server::new("0.0.0.0:8080", Router::new()).spawn().await.unwrap();
+    ///     let mut hangup = signal(SignalKind::hangup()).unwrap();
+    ///     hangup.recv().await;
+    ///
+    ///     handle.restart().unwrap();
+    ///     let _ = handle.await;
+    /// }
+    /// ```
+    #[cfg(unix)]
+    pub fn restart(&self) -> Result<std::process::Child, crate::Error> {
+        // Once the accept loop stops, the descriptor is closed and may already belong to something else.
+        if self.stopping.swap(true, Ordering::Relaxed) {
+            return Err(crate::Error::Io(Error::other("Server is already shutting down")));
+        }
+        match handoff::spawn_successor(self.listener_fd) {
+            Ok(child) => {
+                self.shutdown.notify_one();
+                Ok(child)
+            }
+            Err(e) => {
+                self.stopping.store(false, Ordering::Relaxed);
+                Err(crate::Error::Io(e))
+            }
+        }
+    }
+}
+
+impl Future for ServerHandle {
+    type Output = Result<(), crate::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.task).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(crate::Error::Io(Error::other(e)))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Accepts and handles connections until `shutdown` completes, then waits for open connections to
+/// finish and cancels the background tasks. HTTP/2 and HTTP/3 connections, which stay open for
+/// more requests, are told to close once their open requests are answered.
+async fn serve(
+    listeners: Listeners,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), crate::Error> {
+    let mut connections = JoinSet::new();
+    let mut background = spawn_background(&config);
+    let (closing, closing_rx) = watch::channel(false);
+    #[cfg(feature = "http3")]
+    if let Some(endpoint) = listeners.quic {
+        connections.spawn(crate::h3::serve(endpoint, router.clone(), config.clone(), closing_rx.clone()));
+    }
+    let listener = listeners.tcp;
+    tokio::pin!(shutdown);
+    let mut result = Ok(());
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, remote_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => match accept_backoff(&e) {
+                        Some(backoff) => {
+                            eprintln!("error, accept failed, retrying in {:?}: {:?}", backoff, e);
+                            tokio::select! {
+                                _ = tokio::time::sleep(backoff) => continue,
+                                _ = &mut shutdown => break,
+                            }
+                        }
+                        None => {
+                            result = Err(crate::Error::Accept(e));
+                            break;
+                        }
+                    },
+                };
+                let router = router.clone();
+                let config = config.clone();
+                let closing = closing_rx.clone();
+                let id = config.next_request_id();
+                connections.spawn(async move {
+                    if let Err(e) = serve_socket(socket, remote_addr, id, router, config, closing).await {
+                        eprintln!("error, request: {}, conn: {}, err: {:?}", id, remote_addr, e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    let _ = closing.send(true);
+    while connections.join_next().await.is_some() {}
+    background.shutdown().await;
+    result
+}
+
+/// Starts the tasks registered with `Server::spawn_background`, unless an earlier run took them.
+fn spawn_background(config: &Config) -> JoinSet<()> {
+    let mut background = JoinSet::new();
+    let tasks = config.background.lock().map(|mut tasks| std::mem::take(&mut *tasks)).unwrap_or_default();
+    for task in tasks {
+        background.spawn(task);
+    }
+    background
+}
+
+/// How long the accept loop waits before accepting again after `err`, or `None` if the listener
+/// can't accept anymore. Errors of a single connection, like one reset before it was accepted, are
+/// retried right away. Others, mostly running out of file descriptors or memory, are retried after
+/// a pause, so the loop doesn't spin while connections in progress finish and free them.
+pub(crate) fn accept_backoff(err: &Error) -> Option<Duration> {
+    // EBADF, which has the same number on Linux, macOS and the BSDs.
+    if cfg!(unix) && err.raw_os_error() == Some(9) {
+        return None;
+    }
+    match err.kind() {
+        ErrorKind::InvalidInput | ErrorKind::NotConnected => None,
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::PermissionDenied
+        | ErrorKind::TimedOut
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock => Some(Duration::ZERO),
+        _ => Some(Duration::from_millis(100)),
+    }
+}
+
+/// Handles a connection accepted from the listener, after the TLS handshake if the server has TLS.
+/// Clients that pick HTTP/2 during the handshake are served by `h2::serve_connection`.
+async fn serve_socket(
+    socket: TcpStream,
+    remote_addr: SocketAddr,
+    id: u64,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    #[cfg_attr(not(feature = "rustls"), allow(unused_variables))] closing: watch::Receiver<bool>,
+) -> Result<(), crate::Error> {
+    #[cfg(feature = "rustls")]
+    if let Some(tls) = &config.tls {
+        let handshake = tls.accept(socket);
+        let handshake = match config.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))),
+            None => handshake.await,
+        };
+        let stream = match handshake {
+            Ok(stream) => stream,
+            Err(e) => {
+                config.stats.handshake_failure();
+                return Err(crate::Error::Tls(e));
+            }
+        };
+        let peer_certificate = crate::tls::TlsConfig::peer_certificate(&stream);
+        if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+            let stats = config.stats.clone();
+            let _active = stats.connection();
+            let (read, write) = tokio::io::split(stream);
+            let (read, write) = instrument(Box::new(read), Box::new(write), &config);
+            let result = crate::h2::serve_connection(read, write, remote_addr, id, peer_certificate, router, config, closing).await;
+            count_error(&stats, &result);
+            return result;
+        }
+        let mut extensions = Extensions::new();
+        if let Some(cert) = peer_certificate {
+            extensions.insert(cert);
+        }
+        let (read, write) = tokio::io::split(stream);
+        return handle_conn(Box::new(read), Box::new(write), remote_addr, id, extensions, router, config).await;
+    }
+    let (read, write) = socket.into_split();
+    handle_conn(Box::new(read), Box::new(write), remote_addr, id, Extensions::new(), router, config).await
+}
+
+/// Read half of a connection, whatever transport it runs over.
+pub(crate) type ConnRead = Box<dyn AsyncRead + Unpin + Send + Sync>;
+/// Write half of a connection.
+pub(crate) type ConnWrite = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
+/// Write half that fails with `TimedOut` once a write has been pending for longer than `timeout`.
+struct WriteTimeout<W> {
+    inner: W,
+    timeout: Duration,
+    stalled: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<W> WriteTimeout<W> {
+    fn new(inner: W, timeout: Duration) -> Self {
+        WriteTimeout { inner, timeout, stalled: None }
+    }
+
+    /// Resets the timer if `poll` made progress, otherwise fails once the timer has run out.
+    fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<std::io::Result<T>>) -> Poll<std::io::Result<T>> {
+        if poll.is_ready() {
+            self.stalled = None;
+            return poll;
+        }
+        let timeout = self.timeout;
+        let stalled = self.stalled.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match stalled.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Error::new(ErrorKind::TimedOut, "Client stopped reading the response"))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.check(cx, poll)
+    }
+}
+
+/// Buffered read half of a connection.
+/// Shared between the connection handler and the request body, which holds the lock while it's alive.
+pub(crate) type ConnReader = BufReader<ConnRead>;
+
+/// Maximum size of a request line plus headers.
+const MAX_HEAD_SIZE: usize = 16_384;
+
+/// Reads the request line and headers, up to and including the empty line that ends them.
+/// Returns them with the time the request line arrived. Fails with `TimedOut` if `timeout` passes first.
+async fn read_head(reader: &mut ConnReader, timeout: Option<Duration>) -> std::io::Result<(Vec<u8>, Instant)> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut head = Vec::with_capacity(1024);
+    let mut received = Instant::now();
+    loop {
+        let line_start = head.len();
+        let read = async {
+            let mut limited = (&mut *reader).take((MAX_HEAD_SIZE + 1 - line_start) as u64);
+            limited.read_until(b'\n', &mut head).await
+        };
+        let read = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, read).await,
+            None => Ok(read.await),
+        };
+        let n = match read {
+            Ok(n) => n?,
+            // Only a client that started sending a request is owed a response.
+            Err(_) if head.is_empty() && reader.buffer().is_empty() => {
+                return Err(Error::new(ErrorKind::TimedOut, "Idle connection timed out"));
+            }
+            Err(_) => return Err(ParseError::TimedOut.into()),
+        };
+        if n == 0 {
+            if head.is_empty() {
+                return Err(Error::new(ErrorKind::ConnectionReset, "Connection closed unexpectedly"));
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Headers truncated"));
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(head_too_large(line_start == 0).into());
+        }
+        let line = &head[line_start..];
+        if line == b"\r\n" || line == b"\n" {
+            if line_start == 0 {
+                // Empty lines before the request line are ignored.
+                head.clear();
+                continue;
+            }
+            return Ok((head, received));
+        }
+        if line_start == 0 {
+            received = Instant::now();
+        }
+    }
+}
+
+/// Finds the request head at the start of `buf` the way `read_head` reads it from a connection.
+/// Returns its range, which skips empty lines before the request line and includes the empty
+/// line ending the head.
+pub(crate) fn find_head(buf: &[u8]) -> Result<Range<usize>, ParseError> {
+    let mut start = 0;
+    let mut line_start = 0;
+    loop {
+        let line_end = match buf[line_start..].iter().position(|&b| b == b'\n') {
+            Some(i) => line_start + i + 1,
+            None if buf.len() - start > MAX_HEAD_SIZE => return Err(head_too_large(line_start == start)),
+            None => return Err(ParseError::Incomplete),
+        };
+        if line_end - start > MAX_HEAD_SIZE {
+            return Err(head_too_large(line_start == start));
+        }
+        let line = &buf[line_start..line_end];
+        if line == b"\r\n" || line == b"\n" {
+            if line_start != start {
+                return Ok(start..line_end);
+            }
+            start = line_end;
+        }
+        line_start = line_end;
+    }
+}
+
+fn head_too_large(request_line: bool) -> ParseError {
+    // A request line that alone fills the limit is almost always an overlong URI.
+    if request_line {
+        return ParseError::RequestLineTooLong;
+    }
+    ParseError::HeadersTooLarge
+}
+
+pub(crate) async fn parse_request(
+    remote_addr: SocketAddr,
+    reader: Arc<Mutex<ConnReader>>,
+    unread: &Unread,
+    config: &Config,
+) -> std::io::Result<(Request, usize)> {
+    let mut reader = reader.lock_owned().await;
+    let (head, received) = read_head(&mut reader, config.read_timeout).await?;
+    let mut head = parse_head(&head, config)?;
+    head.remote_addr = remote_addr;
+    head.received = received.into_std();
+    let head_len = head.len;
+
+    let body = if head.chunked {
+        Body::chunked(StreamReader::new(reader, unread.clone()))
+    } else if let Some(len) = head.content_length {
+        Body::remote(reader, len, unread.clone())
+    } else {
+        Body::empty()
+    };
+    let body = apply_unexpected_body_policy(&head.method, body, config).await?;
+    Ok((Request::from_parts(head, body), head_len))
+}
+
+/// Reads and discards the body of a GET, HEAD or DELETE request if the server ignores those,
+/// see [`UnexpectedBodyPolicy::Ignore`]. Rejecting them happens in `parse_head`.
+pub(crate) async fn apply_unexpected_body_policy(method: &Method, mut body: Body, config: &Config) -> std::io::Result<Body> {
+    if config.unexpected_body_policy != UnexpectedBodyPolicy::Ignore || !is_bodyless(method) || body.is_empty() {
+        return Ok(body);
+    }
+    if body.len() > MAX_IGNORED_BODY {
+        return Err(ParseError::Malformed("Body too large to ignore").into());
+    }
+    body.bytes(MAX_IGNORED_BODY).await?;
+    Ok(Body::empty())
+}
+
+/// Parses a complete request head, as returned by `read_head` or `find_head`, and checks it
+/// against the server's options.
+pub(crate) fn parse_head(head: &[u8], config: &Config) -> Result<RequestHead, ParseError> {
+    let header_str = match std::str::from_utf8(head) {
+        Ok(value) => value,
+        Err(_) => return Err(ParseError::Malformed("UTF-8 error")),
+    };
+
+    let mut lines = header_str.lines();
+    let request_line = match lines.next() {
+        Some(line) => line,
+        None => return Err(ParseError::Malformed("Missing request line")),
+    };
+
+    let mut parts = request_line.split_whitespace();
+
+    let method = Method::from(
+        match parts.next() {
+            Some(part) => part,
+            None => return Err(ParseError::Malformed("Missing method")),
+        }
+    );
+    let path = match parts.next() {
+        Some(part) => part,
+        None => return Err(ParseError::Malformed("Missing path")),
+    }
+    .to_string();
+    let path = match apply_encoded_path_policy(path, config.encoded_path_policy) {
+        Some(path) => path,
+        None => return Err(ParseError::Malformed("Encoded slash or null byte in path")),
+    };
+    let version = Version::from(
+        match parts.next() {
+            Some(part) => part,
+            None => return Err(ParseError::Malformed("Missing version")),
+        }
+    );
+
+    if method == Method::CONNECT && parse_authority(&path).is_none() {
+        return Err(ParseError::Malformed("Invalid CONNECT target"));
+    }
+    if config.max_uri_length.is_some_and(|max| path.len() > max) {
+        return Err(ParseError::UriTooLong);
+    }
+    if config.max_path_segments.is_some_and(|max| path_segments(&path) > max) {
+        return Err(ParseError::UriTooLong);
+    }
+    // The asterisk-form target only makes sense for server-wide OPTIONS (RFC 9112 section 3.2.4).
+    if path == "*" && method != Method::OPTIONS {
+        return Err(ParseError::Malformed("Asterisk-form target is only allowed for OPTIONS"));
+    }
+
+    let mut headers = HeaderMap::new();
+    let mut content_length: Option<usize> = None;
+    let mut hosts = 0;
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = if config.strict_headers {
+            match validate_header_line(line) {
+                Some(pair) => pair,
+                None => return Err(ParseError::Malformed("Malformed header")),
+            }
+        } else {
+            match line.split_once(':') {
+                Some(pair) => pair,
+                None => continue,
+            }
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        if key.eq_ignore_ascii_case("content-length") {
+            let len = match parse_content_length(value) {
+                Some(len) => len,
+                None => return Err(ParseError::Malformed("Invalid Content-Length")),
+            };
+            if content_length.is_some_and(|prev| prev != len) {
+                return Err(ParseError::Malformed("Conflicting Content-Length headers"));
+            }
+            content_length = Some(len);
+        }
+        if key.eq_ignore_ascii_case("host") {
+            hosts += 1;
+        }
+
+        headers.insert(key.to_string(), value.to_string());
+    }
+
+    if config.require_host && version == Version::Http11 && hosts != 1 {
+        return Err(ParseError::Malformed("Missing or repeated Host header"));
+    }
+    if let Some(allowed) = &config.allowed_hosts {
+        let host = headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("host")).map(|(_, v)| v.as_str());
+        if !host.is_some_and(|host| host_allowed(host, allowed)) {
+            return Err(ParseError::HostNotAllowed);
         }
     }
+
+    let transfer_encoding = headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"));
+    // A request framed both ways is how requests get smuggled past a proxy that picks the other
+    // framing, so it's refused rather than resolved in favour of Transfer-Encoding.
+    if transfer_encoding.is_some() && content_length.is_some() {
+        return Err(ParseError::Malformed("Both Content-Length and Transfer-Encoding"));
+    }
+    let chunked = transfer_encoding.is_some_and(|(_, v)| v.split(',').any(|s| s.trim().eq_ignore_ascii_case("chunked")));
+
+    if config.unexpected_body_policy == UnexpectedBodyPolicy::Reject
+        && is_bodyless(&method)
+        && (chunked || content_length.is_some_and(|len| len > 0))
+    {
+        return Err(ParseError::Malformed("Body not allowed on GET, HEAD or DELETE"));
+    }
+
+    Ok(RequestHead {
+        method,
+        path,
+        version,
+        headers,
+        remote_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+        params: ParamMap::new(),
+        extensions: Extensions::new(),
+        id: 0,
+        received: std::time::Instant::now(),
+        content_length,
+        chunked,
+        len: head.len(),
+    })
 }
 
-async fn parse_request(
-    remote_addr: std::net::SocketAddr,
-    mut reader: tokio::net::tcp::OwnedReadHalf,
-) -> std::io::Result<Request> {
-    let mut buffer = Vec::with_capacity(16_384);
 
-    let n = reader.read_buf(&mut buffer).await?;
-    if n == 0 { return Err(Error::new(ErrorKind::ConnectionReset, "Connection closed unexpectedly")) }
+/// Returns the status a failed parse should be answered with, or `None` if the connection
+/// should just be closed, e.g. because the client went away.
+pub(crate) fn rejection_status(e: &Error) -> Option<StatusCode> {
+    e.get_ref()?.downcast_ref::<ParseError>()?.status()
+}
 
-    let headers_end = match find_headers_end(&buffer) {
-        Some(value) => value,
-        None => return Err(Error::new(ErrorKind::InvalidData, "Invalid headers")),
-    };
-    
-    let header_bytes = &buffer[..headers_end];
-    let header_str = match std::str::from_utf8(header_bytes) {
-        Ok(value) => value,
-        Err(_) => return Err(Error::new(ErrorKind::InvalidData, "UTF-8 error")),
+/// Applies `policy` to the path part of a request target, returns `None` if it must be rejected.
+pub(crate) fn apply_encoded_path_policy(target: String, policy: EncodedPathPolicy) -> Option<String> {
+    if policy == EncodedPathPolicy::PassThrough {
+        return Some(target);
+    }
+    let end = target.find(['?', '#']).unwrap_or(target.len());
+    let (path, rest) = target.split_at(end);
+    let lower = path.to_ascii_lowercase();
+    if lower.contains("%00") || (policy == EncodedPathPolicy::Reject && lower.contains("%2f")) {
+        return None;
+    }
+    if !lower.contains("%2f") {
+        return Some(target);
+    }
+    Some(path.replace("%2F", "/").replace("%2f", "/") + rest)
+}
+
+/// Counts the segments of a request target's path the way the router splits it.
+pub(crate) fn path_segments(target: &str) -> usize {
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    path.trim_matches('/').split('/').count()
+}
+
+/// Returns whether `host`, the value of a `Host` header, matches an entry of `allowed`,
+/// see [`Server::allowed_hosts`].
+pub(crate) fn host_allowed(host: &str, allowed: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    // Ports are only split off when the rest is a name or a bracketed IPv6 address.
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') || name.ends_with(']') => {
+            if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+                return false;
+            }
+            name
+        }
+        _ => host.as_str(),
     };
+    allowed.iter().any(|entry| {
+        let pattern = if entry.contains(':') && !entry.ends_with(']') { host.as_str() } else { name };
+        match entry.strip_prefix("*.") {
+            Some(domain) => pattern.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => pattern == entry,
+        }
+    })
+}
 
-    let mut lines = header_str.lines();
-    let request_line = match lines.next() {
-        Some(line) => line,
-        None => return Err(Error::new(ErrorKind::InvalidData, "Missing request line")),
+/// Parses an authority-form request target (`host:port`), as used by CONNECT requests.
+pub(crate) fn parse_authority(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.strip_suffix(']')?,
+        None => host,
     };
+    if host.is_empty() || host.contains(['/', '@', '[', ']']) {
+        return None;
+    }
+    if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
 
-    let mut parts = request_line.split_whitespace();
+/// Splits an absolute-form request target (`http://host[:port]/path`) into an authority
+/// with an explicit port and the origin-form path.
+pub(crate) fn parse_absolute_form(target: &str) -> Option<(String, &str)> {
+    let rest = target.strip_prefix("http://")?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    let authority = if parse_authority(authority).is_some() {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    parse_authority(&authority)?;
+    Some((authority, path))
+}
 
-    let method = Method::from(
-        match parts.next() {
-            Some(part) => part,
-            None => return Err(Error::new(ErrorKind::InvalidData, "Missing method")),
-        }
-    );
-    let path = match parts.next() {
-        Some(part) => part,
-        None => return Err(Error::new(ErrorKind::InvalidData, "Missing path")),
+/// Parses the head of an HTTP response into its status code and headers.
+pub(crate) fn parse_response_head(head: &[u8]) -> Option<(u16, HeaderMap)> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.lines();
+    let mut status_line = lines.next()?.split_whitespace();
+    if !status_line.next()?.starts_with("HTTP/") {
+        return None;
     }
-    .to_string();
-    let version = Version::from(
-        match parts.next() {
-            Some(part) => part,
-            None => return Err(Error::new(ErrorKind::InvalidData, "Missing version")),
-        }
-    );
+    let code = status_line.next()?.parse().ok()?;
 
     let mut headers = HeaderMap::new();
     for line in lines {
         if line.is_empty() {
             break;
         }
-        if let Some((key, value)) = line.split_once(": ") {
+        if let Some((key, value)) = line.split_once(':') {
             headers.insert(key.trim().to_string(), value.trim().to_string());
         }
     }
+    Some((code, headers))
+}
 
-    let remote_addr = remote_addr.to_string();
-    let params = ParamMap::new();
-    let is_chunked = headers.iter().any(|(k, v)| {
-        k.eq_ignore_ascii_case("transfer-encoding") &&
-        v.split(',').any(|s| s.trim().eq_ignore_ascii_case("chunked"))
-    });
+/// Parses a Content-Length value.
+/// A comma separated list is accepted only if every element is the same length.
+/// Returns None for empty, non-numeric, signed or overflowing values.
+pub(crate) fn parse_content_length(value: &str) -> Option<usize> {
+    let mut len = None;
+    for part in value.split(',') {
+        let part = part.trim();
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let parsed = part.parse::<usize>().ok()?;
+        if len.is_some_and(|prev| prev != parsed) {
+            return None;
+        }
+        len = Some(parsed);
+    }
+    len
+}
 
-    let body = {
-        if let Some((_, value)) = headers
-            .iter()
-            .find(|(k, _)| k.to_lowercase() == "content-length")
-            && let Ok(len) = value.parse::<usize>()
-        {
-            let mut body = Vec::with_capacity(len);
-            body.extend_from_slice(&buffer[headers_end..]);
-            while body.len() < len {
-                let read = reader.read_buf(&mut body).await?;
-                if read == 0 {
-                    return Err(Error::new(
-                    ErrorKind::UnexpectedEof,
-                    "Body truncated",
-                    ));
+/// Parses the size on a chunk size line, ignoring chunk extensions.
+/// Returns None unless the size is hex digits only, so a `+` or `0x` prefix, which some parsers
+/// accept and others don't, can't be used to make them disagree about where the body ends.
+pub(crate) fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+/// Validates a header line in strict mode and splits it into name and value.
+/// Rejects obsolete line folding, whitespace before the colon, non-token characters in the name
+/// and control characters (other than horizontal tab) in the value.
+pub(crate) fn validate_header_line(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with([' ', '\t']) {
+        return None;
+    }
+    let (key, value) = line.split_once(':')?;
+    if key.is_empty() || !key.bytes().all(is_token_byte) {
+        return None;
+    }
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return None;
+    }
+    Some((key, value))
+}
+
+pub(crate) fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+async fn handle_conn(
+    read: ConnRead,
+    write: ConnWrite,
+    remote_addr: SocketAddr,
+    id: u64,
+    extensions: Extensions,
+    router: Arc<Router>,
+    config: Arc<Config>,
+) -> Result<(), crate::Error> {
+    let stats = config.stats.clone();
+    let _active = stats.connection();
+    let (read, write) = instrument(read, write, &config);
+    let result = respond(read, write, remote_addr, id, extensions, router, config).await;
+    count_error(&stats, &result);
+    result
+}
+
+/// Wraps the halves of a connection in the byte counters and the write timeout.
+pub(crate) fn instrument(read: ConnRead, write: ConnWrite, config: &Config) -> (ConnRead, ConnWrite) {
+    let stats = &config.stats;
+    let read = Box::new(Counted::new(read, stats.clone()));
+    let write: ConnWrite = match config.write_timeout {
+        Some(timeout) => Box::new(WriteTimeout::new(Counted::new(write, stats.clone()), timeout)),
+        None => Box::new(Counted::new(write, stats.clone())),
+    };
+    (read, write)
+}
+
+/// Adds the outcome of answering a request to the server's counters.
+pub(crate) fn count_error(stats: &ServerStats, result: &Result<(), crate::Error>) {
+    match result {
+        Ok(()) => {}
+        Err(crate::Error::Parse(e)) => {
+            match rejection_status(e) {
+                Some(status) => {
+                    stats.parse_error();
+                    if status == StatusCode::RequestTimeout {
+                        stats.timeout();
+                    }
                 }
+                None if e.kind() == ErrorKind::TimedOut => stats.timeout(),
+                None => stats.handshake_failure(),
             }
-            Some(body)
-        } else { None }
+        }
+        Err(crate::Error::HandlerPanic(_)) => stats.handler_panic(),
+        Err(e) => {
+            stats.io_error();
+            if let crate::Error::Io(e) = e {
+                if e.kind() == ErrorKind::TimedOut {
+                    stats.timeout();
+                } else if is_client_gone(e) {
+                    stats.client_close();
+                }
+            }
+        }
+    }
+}
+
+/// Reads one request from the connection and writes the router's response to it.
+/// `id` is stamped on the request, connections serve a single request so it's chosen per connection.
+/// `extensions` holds what the transport knows about the connection, such as the client's
+/// certificate, and become the request's extensions.
+async fn respond(
+    read: ConnRead,
+    write: ConnWrite,
+    remote_addr: SocketAddr,
+    id: u64,
+    extensions: Extensions,
+    router: Arc<Router>,
+    config: Arc<Config>,
+) -> Result<(), crate::Error> {
+    let tally = config.on_accounting.as_ref().map(|_| Tallies::default());
+    let (read, mut write): (ConnRead, ConnWrite) = match &tally {
+        Some(tally) => (
+            Box::new(Tally::new(read, tally.read.clone())),
+            Box::new(Tally::new(write, tally.written.clone())),
+        ),
+        None => (read, write),
     };
+    let reader = Arc::new(Mutex::new(BufReader::new(read)));
 
-    let stream = if is_chunked {
-        let leftover = buffer.split_off(headers_end);
-        Some(StreamReader::new(leftover, reader))
-    } else {
-        None
+    let unread = Unread::default();
+    let idle = config.stats.idle();
+    let parsed = parse_request(remote_addr, reader.clone(), &unread, &config).await;
+    drop(idle);
+    let (mut req, head_len) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            if let Some(status) = rejection_status(&e) {
+                let req = Request { remote_addr, ..Default::default() };
+                let resp = router.error_response(status, req).await.header("Connection", "close");
+                write.write_all(&serialize_response(&resp)).await?;
+                write.shutdown().await?;
+            }
+            return Err(crate::Error::Parse(e));
+        }
+    };
+    config.stats.request();
+    req.id = id;
+    req.extensions = extensions;
+
+    let account = tally.map(|tally| (tally, req.method.clone(), req.path.clone(), head_len as u64));
+    let head_only = req.method == Method::HEAD;
+    let send_continue = write.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+    let (mut resp, panic, withheld) = dispatch(req, &router, &config, send_continue).await?;
+    // Closing with body bytes still unread makes the OS reset the connection, which can destroy
+    // the response before the client reads it. What's left of a small body is read off after the
+    // response, anything else is announced with `Connection: close`.
+    let drain = match unread.get() {
+        // An upgraded connection hands whatever follows the request to the upgrade.
+        _ if resp.upgrade.is_some() => 0,
+        0 => 0,
+        left if left <= MAX_DRAIN && !withheld => left,
+        _ => {
+            resp = resp.header("Connection", "close");
+            0
+        }
     };
+    // The one request of a connection is always the last it's allowed.
+    if config.max_requests_per_connection.is_some() && resp.upgrade.is_none() {
+        resp = resp.header("Connection", "close");
+    }
+    let status = resp.status_code.clone();
+    let mut resp_bytes = serialize_response(&resp);
+    // Responses to HEAD keep their Content-Length but never carry a body.
+    if head_only {
+        if let Some(body) = &resp.body {
+            resp_bytes.truncate(resp_bytes.len() - body.len());
+        }
+        resp.stream = None;
+    }
+    if let Err(e) = write.write_all(&resp_bytes).await {
+        drop(resp.stream.take());
+        write_failed(&config, remote_addr, id, false, &e);
+        return Err(e.into());
+    }
+
+    if let Some(msg) = panic {
+        write.shutdown().await?;
+        account_for(&config, account, status, remote_addr, id, true);
+        return Err(crate::Error::HandlerPanic(msg));
+    }
+
+    if let Some(on_upgrade) = resp.upgrade.take() {
+        // The request body holds the reader while it's alive, so this fails if it's still in use.
+        let read = match Arc::try_unwrap(reader) {
+            Ok(read) => read.into_inner(),
+            Err(_) => return Err(Error::new(ErrorKind::Unsupported, "Request body still in use during upgrade").into()),
+        };
+        on_upgrade(Upgraded::new(read, write)).await;
+        return Ok(());
+    }
+
+    if let Some(mut stream) = resp.stream {
+        if let Some(limit) = config.bandwidth_limit {
+            stream.limit_rate(limit);
+        }
+        match stream_resp(&mut write, stream).await {
+            Ok(sent) => config.stats.streamed(sent),
+            Err(e) => {
+                write_failed(&config, remote_addr, id, true, &e);
+                // Clients are free to abandon a download, that's not worth an error.
+                if is_client_gone(&e) {
+                    config.stats.client_close();
+                    account_for(&config, account, status, remote_addr, id, false);
+                    return Ok(());
+                }
+                return Err(e.into());
+            }
+        }
+    }
+
+    if drain > 0 {
+        drain_body(&reader, drain, config.read_timeout.unwrap_or(DRAIN_TIMEOUT)).await;
+    }
+    write.shutdown().await?;
+    account_for(&config, account, status, remote_addr, id, true);
+
+    Ok(())
+}
+
+/// Byte counts of a single connection, kept while [`Server::on_accounting`] is set.
+#[derive(Default)]
+struct Tallies {
+    read: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
 
-    Ok(Request {
+/// Reports the bytes of a finished request to the accounting callback, `complete` is false if
+/// the client went away during a streamed body.
+fn account_for(
+    config: &Config,
+    account: Option<(Tallies, Method, String, u64)>,
+    status: StatusCode,
+    remote_addr: SocketAddr,
+    id: u64,
+    complete: bool,
+) {
+    let (Some(callback), Some((tally, method, path, head))) = (&config.on_accounting, account) else {
+        return;
+    };
+    let read = tally.read.load(Ordering::Relaxed);
+    callback(&Accounting {
         method,
         path,
-        version,
-        headers,
-        body,
+        status,
         remote_addr,
-        params,
-        stream,
-    })
+        id,
+        request_head_bytes: head,
+        request_body_bytes: read.saturating_sub(head),
+        response_bytes: tally.written.load(Ordering::Relaxed),
+        complete,
+    });
+}
+
+/// Runs the router on `req` in its own task, so a panic is turned into a 500 response, with the
+/// server's hooks around it: the panic and slow request callbacks, Server-Timing and the request log.
+/// Until the server is ready, see `Server::readiness`, `req` is answered with 503 instead.
+/// A client waiting for `100 Continue` is told to send its body by awaiting `send_continue` once
+/// the handler starts reading it. Returns the response, the panic message if the handler panicked,
+/// and whether the handler answered without asking for a body the client held back.
+pub(crate) async fn dispatch(
+    mut req: Request,
+    router: &Arc<Router>,
+    config: &Config,
+    send_continue: impl Future<Output = std::io::Result<()>>,
+) -> std::io::Result<(Response, Option<String>, bool)> {
+    if config.readiness.as_ref().is_some_and(|readiness| !readiness.is_ready()) {
+        let withheld = req.expects_continue();
+        return Ok((router.error_response(StatusCode::ServiceUnavailable, req).await, None, withheld));
+    }
+    let continue_requested = expect_continue(&mut req);
+    let alt_svc = config.alt_svc.as_ref().filter(|_| req.version != Version::Http3);
+    // Handlers run in their own task so a panic is turned into a 500 response.
+    // The panic callback and an error handler for 500 get what's left of the request if the handler panics.
+    let capture = config.on_panic.is_some();
+    let parts = (capture || router.has_error_handler(&StatusCode::InternalServerError)).then(|| req.clone_parts());
+    let backtrace = BacktraceSlot::default();
+    let slow = config.slow_requests.as_ref().map(|(threshold, callback)| {
+        let route = RouteSlot::default();
+        req.extensions.insert(route.clone());
+        let slow = SlowRequest {
+            method: req.method.clone(),
+            route: None,
+            path: req.path.clone(),
+            duration: Duration::ZERO,
+            remote_addr: req.remote_addr,
+            id: req.id,
+        };
+        (*threshold, callback, route, slow)
+    });
+    let log = config.request_log.as_ref().map(|callback| {
+        let log = RequestLog {
+            method: req.method.clone(),
+            path: req.path.clone(),
+            status: StatusCode::Ok,
+            duration: Duration::ZERO,
+            remote_addr: req.remote_addr,
+            id: req.id,
+            fields: Vec::new(),
+        };
+        (callback, RequestContext::of(&mut req), log)
+    });
+    let received = Instant::from_std(req.received);
+    let started = Instant::now();
+    let handler_router = router.clone();
+    let handling = async move { handler_router.handle_request(req).await };
+    let mut task = if capture {
+        tokio::spawn(CaptureBacktrace::new(handling, backtrace.clone()))
+    } else {
+        tokio::spawn(handling)
+    };
+    let mut withheld = false;
+    let handled = match continue_requested {
+        // The client holds the body back until it's told to continue, which happens once the
+        // handler starts reading it. Handlers that answer without reading it never ask for it.
+        Some(mut requested) => tokio::select! {
+            handled = &mut task => {
+                withheld = true;
+                handled
+            }
+            Ok(()) = &mut requested => {
+                send_continue.await?;
+                task.await
+            }
+        },
+        None => task.await,
+    };
+    let handler_time = started.elapsed();
+    if let Some((threshold, callback, RouteSlot(route), mut slow)) = slow {
+        slow.duration = started.elapsed();
+        if slow.duration > threshold {
+            slow.route = route.lock().ok().and_then(|mut route| route.take());
+            callback(&slow);
+        }
+    }
+    let (mut resp, panic) = match handled {
+        Ok(resp) => (resp, None),
+        Err(e) => {
+            let message = panic_message(e);
+            if let Some(on_panic) = &config.on_panic {
+                let backtrace = backtrace.lock().ok().and_then(|mut slot| slot.take());
+                on_panic(&PanicReport {
+                    message: message.clone(),
+                    backtrace: backtrace.unwrap_or_else(std::backtrace::Backtrace::disabled),
+                    request: parts.as_ref().map(Request::clone_parts).unwrap_or_default(),
+                });
+            }
+            let req = parts.unwrap_or_default();
+            (router.error_response(StatusCode::InternalServerError, req).await, Some(message))
+        }
+    };
+    if let Some(alt_svc) = alt_svc
+        && !resp.headers.as_ref().is_some_and(|headers| headers.keys().any(|k| k.eq_ignore_ascii_case("alt-svc")))
+    {
+        resp = resp.header("Alt-Svc", alt_svc);
+    }
+    if config.server_timing {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let timing = format!("parse;dur={:.3}, handler;dur={:.3}", ms(started - received), ms(handler_time));
+        resp = resp.append_header("Server-Timing", &timing);
+    }
+    if let Some((callback, context, mut log)) = log {
+        log.status = resp.status_code.clone();
+        log.duration = started.elapsed();
+        log.fields = context.fields();
+        callback(&log);
+    }
+    Ok((resp, panic, withheld))
+}
+
+/// Tears the connection down after writing the response failed: counts the failure and calls the
+/// `on_write_error` callback. A streamed body is already dropped by then, which fails its producer's next write.
+pub(crate) fn write_failed(config: &Config, remote_addr: SocketAddr, id: u64, streaming: bool, e: &Error) {
+    config.stats.write_error();
+    if let Some(callback) = &config.on_write_error {
+        callback(&WriteFailure { remote_addr, id, streaming, kind: e.kind(), reason: e.to_string() });
+    }
 }
 
-async fn handle_conn(socket: TcpStream, remote_addr: SocketAddr, router: Arc<Router>) -> std::io::Result<()> {
-    let (read, mut write) = socket.into_split();
+/// Most unread body bytes read off the connection before closing it, see `respond`.
+const MAX_DRAIN: usize = 256 * 1024;
 
-    let req = parse_request(remote_addr, read).await?;
+/// How long draining may take if the server has no read timeout.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
-    let resp = router.handle_request(req).await;
-    let resp_bytes = serialize_response(&resp);
-    write.write_all(&resp_bytes).await?;
+/// Reads and discards up to `n` bytes, unless the request body is still held by a task the handler spawned.
+async fn drain_body(reader: &Mutex<ConnReader>, n: usize, timeout: Duration) {
+    let Ok(mut reader) = reader.try_lock() else { return };
+    let mut left = (&mut *reader).take(n as u64);
+    let _ = tokio::time::timeout(timeout, tokio::io::copy(&mut left, &mut tokio::io::sink())).await;
+}
 
-    if let Some(stream) = resp.stream {
-        stream_resp(&mut write, stream).await?;
+/// Returns a receiver that fires when the handler starts reading the body of a request
+/// sent with `Expect: 100-continue`.
+fn expect_continue(req: &mut Request) -> Option<oneshot::Receiver<()>> {
+    if !req.expects_continue() {
+        return None;
+    }
+    let (notify, requested) = oneshot::channel();
+    if let Some(stream) = &mut req.stream {
+        stream.notify_on_read(notify);
+    } else if !req.body.notify_on_read(notify) {
+        return None;
+    }
+    Some(requested)
+}
+
+fn panic_message(e: tokio::task::JoinError) -> String {
+    if !e.is_panic() {
+        return e.to_string();
+    }
+    let payload = e.into_panic();
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_string()
     }
-    
-    write.shutdown().await?;
-    
-    Ok(())
 }
 
-fn serialize_response(resp: &Response) -> Vec<u8> {
+pub(crate) fn serialize_response(resp: &Response) -> Vec<u8> {
     let mut response = format!("HTTP/1.1 {}\r\n", resp.status_code).into_bytes();
     if let Some(headers) = &resp.headers {
         for (key, value) in headers {
-            response.extend(format!("{}: {}\r\n", key, value).as_bytes());
-        }
-        response.extend(b"\r\n");
-        if let Some(body) = &resp.body {
-        if !headers.contains_key("Content-Length") {
-                response.extend(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+            // Repeated headers are stored newline separated, see `Response::append_header`.
+            for value in value.split('\n') {
+                response.extend(format!("{}: {}\r\n", key, value.trim_end_matches('\r')).as_bytes());
             }
-            response.extend(body);
         }
     }
+    if let Some(body) = &resp.body {
+        let has_length = resp.headers.as_ref().is_some_and(|headers| {
+            headers.keys().any(|k| k.eq_ignore_ascii_case("content-length"))
+        });
+        if !has_length {
+            response.extend(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+        }
+    }
+    response.extend(b"\r\n");
+    if let Some(body) = &resp.body {
+        response.extend(body);
+    }
     response
 }
 
-fn find_headers_end(buf: &[u8]) -> Option<usize> {
+pub(crate) fn find_headers_end(buf: &[u8]) -> Option<usize> {
     let needle = b"\r\n\r\n";
     if buf.len() < needle.len() {
         return None;
@@ -212,83 +2052,193 @@ fn find_headers_end(buf: &[u8]) -> Option<usize> {
     None
 }
 
-async fn stream_resp(write: &mut tokio::net::tcp::OwnedWriteHalf, mut stream: StreamWriter)
--> std::io::Result<()> {
+/// Returns true for errors meaning the client closed the connection.
+pub(crate) fn is_client_gone(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::ConnectionAborted)
+}
+
+/// Writes the stream to `write`, paced by its bandwidth limit if it has one.
+/// Returns the number of bytes written, including chunk framing.
+pub(crate) async fn stream_resp<W>(write: &mut W, mut stream: StreamWriter) -> std::io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut sent = 0;
     while let Some(chunk) = stream.next_chunk().await {
-        if let Err(e) = write.write_all(&chunk).await {
-            if e.kind() == std::io::ErrorKind::ConnectionReset
-                || e.kind() == std::io::ErrorKind::BrokenPipe 
-            {
-                return Ok(());
-            } else {
-                return Err(e);
-            }
+        let mut rest = &chunk[..];
+        while !rest.is_empty() {
+            let n = match &mut stream.rate {
+                Some(bucket) => bucket.take(rest.len()).await,
+                None => rest.len(),
+            };
+            write.write_all(&rest[..n]).await?;
+            sent += n as u64;
+            rest = &rest[n..];
         }
     }
-    Ok(())
+    Ok(sent)
+}
+
+/// Token bucket limiting the bytes per second sent for a stream, holding up to one second's worth.
+pub(crate) struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        TokenBucket { rate, tokens: rate as f64, refilled: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + earned).min(self.rate as f64);
+        self.refilled = now;
+    }
+
+    /// Waits until up to `want` bytes may be sent and returns how many.
+    async fn take(&mut self, want: usize) -> usize {
+        let want = (want as u64).min(self.rate) as f64;
+        self.refill();
+        if self.tokens < want {
+            tokio::time::sleep(Duration::from_secs_f64((want - self.tokens) / self.rate as f64)).await;
+            self.refill();
+        }
+        let granted = self.tokens.min(want).max(1.0);
+        self.tokens -= granted;
+        granted as usize
+    }
 }
 
 ///used for streamed file reading
 pub struct StreamReader {
-    leftover: Vec<u8>,
-    pos: usize,
-    bufreader: tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>,
+    reader: OwnedMutexGuard<ConnReader>,
+    unread: Unread,
+    on_read: Option<oneshot::Sender<()>>,
+    /// Bytes of the current chunk not returned yet, 0 between chunks.
+    chunk_left: usize,
 }
 
+/// Longest chunk size or trailer line accepted, extensions included.
+const MAX_CHUNK_LINE: u64 = 1024;
+/// Most bytes of a chunk returned at once, larger chunks are returned in pieces.
+const MAX_CHUNK_PIECE: usize = 16 * 1024;
+
 impl StreamReader {
-    pub(crate) fn new(leftover: Vec<u8>, reader: tokio::net::tcp::OwnedReadHalf) -> Self {
-        StreamReader { leftover, pos: 0, bufreader: BufReader::new(reader) }
+    pub(crate) fn new(reader: OwnedMutexGuard<ConnReader>, unread: Unread) -> Self {
+        unread.set(Unread::UNKNOWN);
+        StreamReader { reader, unread, on_read: None, chunk_left: 0 }
+    }
+
+    /// Notifies `on_read` when the stream is first read, see `Body::notify_on_read`.
+    pub(crate) fn notify_on_read(&mut self, notify: oneshot::Sender<()>) {
+        self.on_read = Some(notify);
+    }
+
+    fn notify_read(&mut self) {
+        if let Some(notify) = self.on_read.take() {
+            let _ = notify.send(());
+        }
     }
 
     /// Returns next chunk from incoming stream.
-    pub async fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> { 
-        let mut size_line = String::new();
-        let n = self.bufreader.read_line(&mut size_line).await?;
+    /// Chunks larger than 16 KiB are returned in several pieces.
+    pub async fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        self.next_chunk_within(usize::MAX).await
+    }
+
+    /// Like `next_chunk`, but fails with `InvalidData` before reading a chunk whose declared size
+    /// is over `budget`, so a client can't make the server allocate more than it's willing to.
+    pub(crate) async fn next_chunk_within(&mut self, budget: usize) -> std::io::Result<Option<Vec<u8>>> {
+        self.notify_read();
+        if self.chunk_left == 0 {
+            let size = match self.read_chunk_size().await? {
+                Some(size) => size,
+                None => return Ok(None),
+            };
+            if size > budget {
+                return Err(Error::new(ErrorKind::InvalidData, "Body too large"));
+            }
+            self.chunk_left = size;
+        }
+
+        let mut payload = vec![0u8; self.chunk_left.min(MAX_CHUNK_PIECE)];
+        self.reader.read_exact(&mut payload).await?;
+        self.chunk_left -= payload.len();
+
+        if self.chunk_left == 0 {
+            let mut crlf = [0u8; 2];
+            self.reader.read_exact(&mut crlf).await?;
+            if &crlf != b"\r\n" {
+                return Err(ParseError::InvalidChunk("Missing CRLF after chunk").into());
+            }
+        }
+
+        Ok(Some(payload))
+    }
+
+    /// Reads a chunk size line, or the last chunk and the trailers after it, then returns `None`.
+    async fn read_chunk_size(&mut self) -> std::io::Result<Option<usize>> {
+        let mut size_line = Vec::new();
+        let n = (&mut *self.reader).take(MAX_CHUNK_LINE).read_until(b'\n', &mut size_line).await?;
         if n == 0 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
                 "unexpected eof reading chunk size",
             ));
         }
-
-        let size_hex = size_line
-            .trim_end_matches(&['\r', '\n'][..])
-            .split(';')
-            .next()
-            .unwrap_or("0")
-            .trim();
-
-        let size = usize::from_str_radix(size_hex, 16).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("invalid chunk size: {}", e),
-            )
-        })?;
-
-        if size == 0 {
-            loop {
-                let mut trailer = String::new();
-                let n = self.bufreader.read_line(&mut trailer).await?;
-                if n == 0 || trailer == "\r\n" || trailer.trim().is_empty() {
-                    break;
-                }
-            }
-            return Ok(None);
+        if !size_line.ends_with(b"\n") {
+            return Err(ParseError::InvalidChunk("Chunk size line too long").into());
         }
 
-        let mut payload = vec![0u8; size];
-        self.bufreader.read_exact(&mut payload).await?;
+        let size = parse_chunk_size(&size_line).ok_or(ParseError::InvalidChunk("Invalid chunk size"))?;
+        if size > 0 {
+            return Ok(Some(size));
+        }
 
-        let mut crlf = [0u8; 2];
-        self.bufreader.read_exact(&mut crlf).await?;
-        if &crlf != b"\r\n" {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "missing CRLF after chunk",
-            ));
+        loop {
+            let mut trailer = Vec::new();
+            let n = (&mut *self.reader).take(MAX_CHUNK_LINE).read_until(b'\n', &mut trailer).await?;
+            if n == 0 || trailer.trim_ascii().is_empty() {
+                break;
+            }
+            if !trailer.ends_with(b"\n") {
+                return Err(ParseError::InvalidChunk("Trailer line too long").into());
+            }
         }
+        self.unread.set(0);
+        Ok(None)
+    }
+}
 
-        Ok(Some(payload))
+impl StreamReader {
+    /// Reads and de-chunks the rest of the stream into memory.
+    /// Returns an `InvalidData` error as soon as a chunk would take it over `max_bytes` bytes,
+    /// before reading that chunk.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Request, Response};
+    ///
+    /// async fn upload(mut req: Request) -> Response {
+    ///     if let Some(stream) = req.stream.take() {
+    ///         match stream.collect(1024 * 1024).await {
+    ///             Ok(data) => return Response::ok(format!("received {} bytes", data.len())),
+    ///             Err(_) => return Response::bad_request(),
+    ///         }
+    ///     }
+    ///     Response::bad_request()
+    /// }
+    /// ```
+    pub async fn collect(mut self, max_bytes: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.next_chunk_within(max_bytes - data.len()).await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
     }
 }
 
@@ -298,21 +2248,34 @@ impl AsyncRead for StreamReader {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        if self.pos < self.leftover.len() {
-            let rem = &self.leftover[self.pos..];
-            let take = std::cmp::min(rem.len(), buf.remaining());
-            buf.put_slice(&rem[..take]);
-            self.pos = take;
-            return Poll::Ready(Ok(()));
-        }
-
-        Pin::new(&mut self.bufreader.get_mut()).poll_read(cx, buf)
+        self.notify_read();
+        Pin::new(&mut *self.reader).poll_read(cx, buf)
     }
 }
 
 /// Used for responding with streams.
 pub struct StreamWriter {
     reader: BufReader<Box<dyn AsyncRead + Unpin + Send>>,
+    framing: Framing,
+    trailers: Option<oneshot::Receiver<HeaderMap>>,
+    rate: Option<TokenBucket>,
+}
+
+/// A piece of a streamed response, see `StreamWriter::next_part`.
+#[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+pub(crate) enum StreamPart {
+    Data(Vec<u8>),
+    Trailers(HeaderMap),
+}
+
+/// How a StreamWriter frames the bytes it reads.
+pub(crate) enum Framing {
+    /// Chunked transfer encoding, ends with the terminating chunk.
+    Chunked,
+    /// Raw bytes, with this many still to send.
+    Length(u64),
+    /// Everything has been sent.
+    Done,
 }
 
 impl StreamWriter {
@@ -324,19 +2287,140 @@ impl StreamWriter {
     {
         Self {
             reader: BufReader::new(Box::new(stream)),
+            framing: Framing::Chunked,
+            trailers: None,
+            rate: None,
+        }
+    }
+
+    pub(crate) fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Paces the stream to at most `bytes_per_sec`, keeping a lower limit that's already set.
+    pub(crate) fn limit_rate(&mut self, bytes_per_sec: u64) {
+        if self.rate.as_ref().is_none_or(|bucket| bytes_per_sec < bucket.rate) {
+            self.rate = Some(TokenBucket::new(bytes_per_sec));
+        }
+    }
+
+    pub(crate) fn is_chunked(&self) -> bool {
+        matches!(self.framing, Framing::Chunked)
+    }
+
+    /// Reads the rest of the stream into memory without framing, for transports that can't stream.
+    #[cfg_attr(not(any(feature = "lambda", feature = "fastcgi")), allow(dead_code))]
+    pub(crate) async fn read_to_end(self) -> std::io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        match self.framing {
+            Framing::Chunked => {
+                let mut reader = self.reader;
+                reader.read_to_end(&mut body).await?;
+            }
+            Framing::Length(len) => {
+                self.reader.take(len).read_to_end(&mut body).await?;
+                if (body.len() as u64) < len {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Stream ended early"));
+                }
+            }
+            Framing::Done => {}
+        }
+        Ok(body)
+    }
+
+    /// Waits for `trailers` once the stream ends and sends them after the terminating chunk.
+    pub(crate) fn set_trailers(&mut self, trailers: oneshot::Receiver<HeaderMap>) {
+        self.trailers = Some(trailers);
+    }
+
+    /// Returns the terminating chunk, followed by the trailer fields if any were sent.
+    async fn last_chunk(&mut self) -> Vec<u8> {
+        let mut chunk = b"0\r\n".to_vec();
+        let fields = match self.trailers.take() {
+            Some(trailers) => trailers.await.unwrap_or_default(),
+            None => HeaderMap::new(),
+        };
+        for (key, value) in &fields {
+            for value in value.split('\n') {
+                chunk.extend(format!("{}: {}\r\n", key, value.trim_end_matches('\r')).as_bytes());
+            }
+        }
+        chunk.extend(b"\r\n");
+        chunk
+    }
+
+    /// Like `next_chunk`, but without chunk framing, for transports that frame the body themselves:
+    /// returns the next piece of data, paced by the bandwidth limit, then the trailers if any were
+    /// sent. Fails if a fixed-length stream ends early.
+    #[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+    pub(crate) async fn next_part(&mut self) -> std::io::Result<Option<StreamPart>> {
+        const MAX_PART_SIZE: usize = 16 * 1024;
+        let max = match self.framing {
+            Framing::Chunked => MAX_PART_SIZE,
+            Framing::Length(remaining) => remaining.min(MAX_PART_SIZE as u64) as usize,
+            Framing::Done => return Ok(None),
+        };
+        if max == 0 {
+            self.framing = Framing::Done;
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; max];
+        let n = self.reader.read(&mut buf).await?;
+        if n == 0 {
+            let chunked = self.is_chunked();
+            self.framing = Framing::Done;
+            if !chunked {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Stream ended early"));
+            }
+            let trailers = match self.trailers.take() {
+                Some(trailers) => trailers.await.unwrap_or_default(),
+                None => HeaderMap::new(),
+            };
+            return Ok((!trailers.is_empty()).then_some(StreamPart::Trailers(trailers)));
+        }
+        buf.truncate(n);
+        if let Framing::Length(remaining) = &mut self.framing {
+            *remaining -= n as u64;
+        }
+        if let Some(bucket) = &mut self.rate {
+            let mut granted = 0;
+            while granted < n {
+                granted += bucket.take(n - granted).await;
+            }
         }
+        Ok(Some(StreamPart::Data(buf)))
     }
 
     pub(crate) async fn next_chunk(&mut self) -> Option<Vec<u8>> {
         const MAX_CHUNK_SIZE: usize = 64 * 1024;
-        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        let max = match self.framing {
+            Framing::Chunked => MAX_CHUNK_SIZE,
+            Framing::Length(remaining) => remaining.min(MAX_CHUNK_SIZE as u64) as usize,
+            Framing::Done => return None,
+        };
+        if max == 0 {
+            self.framing = Framing::Done;
+            return None;
+        }
+        let mut buf = vec![0u8; max];
 
         match self.reader.read(&mut buf).await {
             Ok(0) => {
-                Some(b"0\r\n\r\n".to_vec())
+                let chunked = matches!(self.framing, Framing::Chunked);
+                self.framing = Framing::Done;
+                // A fixed-length stream that ends early can't be completed, the connection is closed instead.
+                if !chunked {
+                    return None;
+                }
+                Some(self.last_chunk().await)
             }
             Ok(n) => {
                 buf.truncate(n);
+                if let Framing::Length(remaining) = &mut self.framing {
+                    *remaining -= n as u64;
+                    return Some(buf);
+                }
+
                 let mut chunk = Vec::new();
 
                 chunk.extend_from_slice(format!("{:X}\r\n", n).as_bytes());
@@ -0,0 +1,296 @@
+//! Server-wide counters, updated by every connection and readable from any task.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Default)]
+struct Counters {
+    active_connections: AtomicU64,
+    idle_connections: AtomicU64,
+    total_connections: AtomicU64,
+    total_requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    bytes_streamed: AtomicU64,
+    parse_errors: AtomicU64,
+    io_errors: AtomicU64,
+    write_errors: AtomicU64,
+    handler_panics: AtomicU64,
+    timeouts: AtomicU64,
+    client_closes: AtomicU64,
+    handshake_failures: AtomicU64,
+}
+
+/// Live statistics of a [`Server`](crate::Server), returned by [`Server::stats`](crate::Server::stats).
+/// Cloning is cheap and every clone reads the same counters, so one can be moved into an admin
+/// route or a polling task. Counters are read without locking and may be a few updates behind.
+///
+/// # Example:
+/// ```
+/// use zep::{Router, Method, Request, Response, Server};
+///
+/// let server = Server::new("0.0.0.0:8080", Router::new());
+/// let stats = server.stats();
+///
+/// let mut admin = Router::new();
+/// admin.route(Method::GET, "/stats", move |_req: Request| {
+///     let stats = stats.clone();
+///     async move {
+///         Response::ok(format!("active={} requests={}", stats.active_connections(), stats.total_requests()))
+///     }
+/// });
+/// ```
+#[derive(Clone, Default)]
+pub struct ServerStats {
+    counters: Arc<Counters>,
+}
+
+impl ServerStats {
+    /// Returns the number of connections currently open.
+    pub fn active_connections(&self) -> u64 {
+        self.counters.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of open connections still waiting for the client to send a request head.
+    /// A connection serves a single request, so this is the part of [`active_connections`](Self::active_connections)
+    /// not busy with one.
+    pub fn idle_connections(&self) -> u64 {
+        self.counters.idle_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections accepted since the server was created.
+    pub fn total_connections(&self) -> u64 {
+        self.counters.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that were parsed and handed to the router.
+    pub fn total_requests(&self) -> u64 {
+        self.counters.total_requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes read from clients, including headers.
+    pub fn bytes_in(&self) -> u64 {
+        self.counters.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes written to clients, including headers.
+    pub fn bytes_out(&self) -> u64 {
+        self.counters.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes written for streamed response bodies, a part of [`bytes_out`](Self::bytes_out).
+    pub fn bytes_streamed(&self) -> u64 {
+        self.counters.bytes_streamed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of malformed requests that were answered with an error status.
+    pub fn parse_errors(&self) -> u64 {
+        self.counters.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections that failed while reading or writing.
+    pub fn io_errors(&self) -> u64 {
+        self.counters.io_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of responses that failed to be written, see
+    /// [`Server::on_write_error`](crate::Server::on_write_error). Abandoned downloads are included.
+    pub fn write_errors(&self) -> u64 {
+        self.counters.write_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of handlers that panicked.
+    pub fn handler_panics(&self) -> u64 {
+        self.counters.handler_panics.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections dropped because the client was too slow, either sending
+    /// the request head (see [`Server::read_timeout`](crate::Server::read_timeout)) or reading the
+    /// response (see [`Server::write_timeout`](crate::Server::write_timeout)).
+    pub fn timed_out_connections(&self) -> u64 {
+        self.counters.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections the client closed after sending a request, before its
+    /// response was completely written.
+    pub fn client_closed_connections(&self) -> u64 {
+        self.counters.client_closes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections the client closed before sending a complete request head,
+    /// such as port scans, health checks that only connect, or clients giving up early.
+    pub fn handshake_failures(&self) -> u64 {
+        self.counters.handshake_failures.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new connection, which stays active until the returned guard is dropped.
+    pub(crate) fn connection(&self) -> ActiveConnection {
+        self.counters.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { stats: self.clone() }
+    }
+
+    /// Counts a connection as idle until the returned guard is dropped.
+    pub(crate) fn idle(&self) -> IdleConnection {
+        self.counters.idle_connections.fetch_add(1, Ordering::Relaxed);
+        IdleConnection { stats: self.clone() }
+    }
+
+    pub(crate) fn timeout(&self) {
+        self.counters.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_close(&self) {
+        self.counters.client_closes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_failure(&self) {
+        self.counters.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request(&self) {
+        self.counters.total_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn streamed(&self, bytes: u64) {
+        self.counters.bytes_streamed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn parse_error(&self) {
+        self.counters.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn io_error(&self) {
+        self.counters.io_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write_error(&self) {
+        self.counters.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handler_panic(&self) {
+        self.counters.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Decrements the active connection count when dropped.
+pub(crate) struct ActiveConnection {
+    stats: ServerStats,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.counters.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Decrements the idle connection count when dropped.
+pub(crate) struct IdleConnection {
+    stats: ServerStats,
+}
+
+impl Drop for IdleConnection {
+    fn drop(&mut self) {
+        self.stats.counters.idle_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connection half that adds the bytes passing through it to the server's counters.
+pub(crate) struct Counted<T> {
+    inner: T,
+    stats: ServerStats,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, stats: ServerStats) -> Self {
+        Counted { inner, stats }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.stats.counters.bytes_in.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.stats.counters.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Connection half that adds the bytes passing through it to a count of its own, used to report
+/// the bytes of a single connection, see [`Server::on_accounting`](crate::Server::on_accounting).
+pub(crate) struct Tally<T> {
+    inner: T,
+    count: Arc<AtomicU64>,
+}
+
+impl<T> Tally<T> {
+    pub(crate) fn new(inner: T, count: Arc<AtomicU64>) -> Self {
+        Tally { inner, count }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tally<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.count.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tally<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.count.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
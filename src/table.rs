@@ -0,0 +1,129 @@
+//! Building a [`Router`] from a route table loaded at startup, for gateway-style deployments
+//! where routes change without recompiling. Handlers and middleware are registered by name in a
+//! [`Registry`], the table refers to them by those names. Tables are written in JSON.
+
+use crate::route::{Middleware, layered};
+use crate::{Handler, Method, Request, Response, Router};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Error returned when a route table can't be turned into a Router.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error { message: format!("invalid JSON: {}", e) }
+    }
+}
+
+fn invalid(index: usize, message: &str) -> Error {
+    Error { message: format!("route {}: {}", index, message) }
+}
+
+/// Named handlers and middleware that route tables can refer to.
+///
+/// # Example:
+/// ```
+/// use zep::{Handler, Request, Response, table::Registry};
+///
+/// async fn get_user(req: Request) -> Response {
+///     Response::ok(format!("user {}", req.params["id"]))
+/// }
+///
+/// async fn auth(req: Request, next: Handler) -> Response {
+///     if req.headers.contains_key("Authorization") { next(req).await } else { Response::bad_request() }
+/// }
+///
+/// let mut registry = Registry::new();
+/// registry.handler("get_user", get_user);
+/// registry.middleware("auth", auth);
+///
+/// let router = registry.build(r#"[
+///     {"method": "GET", "path": "/users/:id", "handler": "get_user", "middleware": ["auth"]}
+/// ]"#).unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct Registry {
+    handlers: HashMap<String, Handler>,
+    middleware: HashMap<String, Middleware>,
+}
+
+impl Registry {
+    /// Returns an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler under `name`.
+    pub fn handler<F, Fut>(&mut self, name: &str, handler: F)
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.handlers.insert(name.to_string(), Arc::new(move |req| Box::pin(handler(req))));
+    }
+
+    /// Registers a middleware under `name`, see [`Router::middleware`].
+    pub fn middleware<F, Fut>(&mut self, name: &str, f: F)
+    where
+        F: Fn(Request, Handler) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.middleware.insert(name.to_string(), Arc::new(move |req, next| Box::pin(f(req, next))));
+    }
+
+    /// Builds a Router from a JSON route table.
+    /// The table is an array of objects with `method`, `path` and `handler` members and an
+    /// optional `middleware` array, whose first entry is the outermost layer.
+    /// Fails if the table is malformed or names a handler or middleware that isn't registered.
+    pub fn build(&self, table: &str) -> Result<Router, Error> {
+        let table: Value = serde_json::from_str(table)?;
+        let routes = table.as_array().ok_or(Error { message: "route table must be an array".to_string() })?;
+
+        let mut router = Router::new();
+        for (i, route) in routes.iter().enumerate() {
+            let field = |name: &str| {
+                route.get(name).and_then(Value::as_str).ok_or_else(|| invalid(i, &format!("missing \"{}\"", name)))
+            };
+            let method = Method::from(field("method")?.to_ascii_uppercase().as_str());
+            let path = field("path")?;
+            let handler_name = field("handler")?;
+            let handler = self
+                .handlers
+                .get(handler_name)
+                .cloned()
+                .ok_or_else(|| invalid(i, &format!("unknown handler \"{}\"", handler_name)))?;
+
+            let names = match route.get("middleware") {
+                None => &[][..],
+                Some(names) => names.as_array().ok_or_else(|| invalid(i, "\"middleware\" must be an array"))?,
+            };
+            let layers = names
+                .iter()
+                .map(|name| {
+                    let name = name.as_str().ok_or_else(|| invalid(i, "middleware names must be strings"))?;
+                    self.middleware
+                        .get(name)
+                        .ok_or_else(|| invalid(i, &format!("unknown middleware \"{}\"", name)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let handler = layered(layers.into_iter(), handler);
+            router.route_handler(method, path, handler);
+        }
+        Ok(router)
+    }
+}
@@ -0,0 +1,327 @@
+//! HTTPS, see [`Server::new_tls`](crate::Server::new_tls).
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use x509_parser::asn1_rs::{Tag, ToDer};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::X509Name;
+
+pub use tokio_rustls::rustls;
+
+/// Certificate and private key a server presents to clients, see [`Server::new_tls`](crate::Server::new_tls).
+/// Cloning is cheap, clones share the same configuration.
+///
+/// # Example:
+/// ```no_run
+/// use zep::{Router, Server, tls::TlsConfig};
+///
+/// let tls = TlsConfig::from_pem_files("/etc/zep/fullchain.pem", "/etc/zep/privkey.pem").unwrap();
+/// let server = Server::new_tls("0.0.0.0:443", Router::new(), tls);
+/// ```
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    /// The server's certificates, `None` for configs made with [`TlsConfig::from_rustls`].
+    certs: Option<Arc<Certs>>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+}
+
+/// Protocols offered to clients during the handshake, HTTP/2 preferred.
+fn alpn_protocols() -> Vec<Vec<u8>> {
+    vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+}
+
+/// Whether a [`TlsConfig`] lets clients without a certificate connect, see [`TlsConfig::client_auth`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Handshakes fail unless the client presents a valid certificate.
+    Required,
+    /// Clients are asked for a certificate but may connect without one.
+    /// A certificate they do present must still be valid.
+    Optional,
+}
+
+impl TlsConfig {
+    /// Returns a config from a PEM encoded certificate chain, leaf first, and private key
+    /// (PKCS#8, PKCS#1 or SEC1). Fails if either can't be parsed or they don't belong together.
+    pub fn from_pem(cert_chain: &[u8], private_key: &[u8]) -> std::io::Result<Self> {
+        let certs = Certs { default: Arc::new(certified_key(cert_chain, private_key)?), hosts: HashMap::new() };
+        Ok(TlsConfig::build(Arc::new(certs), None))
+    }
+
+    /// Like [`TlsConfig::from_pem`], reading the chain and key from files.
+    pub fn from_pem_files(cert_chain: &str, private_key: &str) -> std::io::Result<Self> {
+        TlsConfig::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(private_key)?)
+    }
+
+    /// Adds a certificate for `host`, presented to clients that ask for it by name (SNI), so one
+    /// server can serve several domains. `host` may start with `*.` to cover every name one label
+    /// below it, exact names take precedence. Clients asking for another name or none at all get the
+    /// certificate the config was made with. Fails like [`TlsConfig::from_pem`], or if the config was
+    /// made with [`TlsConfig::from_rustls`], whose certificates are set on the rustls config instead.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// use zep::{Router, Server, tls::TlsConfig};
+    /// use std::fs::read;
+    ///
+    /// let tls = TlsConfig::from_pem_files("example.com.pem", "example.com.key")
+    ///     .and_then(|tls| tls.add_host("example.org", &read("example.org.pem")?, &read("example.org.key")?))
+    ///     .and_then(|tls| tls.add_host("*.example.org", &read("wildcard.pem")?, &read("wildcard.key")?))
+    ///     .unwrap();
+    /// let server = Server::new_tls("0.0.0.0:443", Router::new(), tls);
+    /// ```
+    pub fn add_host(self, host: &str, cert_chain: &[u8], private_key: &[u8]) -> std::io::Result<Self> {
+        let Some(certs) = &self.certs else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Certificates can't be added to a rustls config"));
+        };
+        let mut certs = Certs::clone(certs);
+        certs.hosts.insert(host.to_ascii_lowercase(), Arc::new(certified_key(cert_chain, private_key)?));
+        Ok(TlsConfig::build(Arc::new(certs), self.client_verifier))
+    }
+
+    /// Returns a config using a rustls server configuration as is, for options not covered here.
+    /// Its ALPN protocols are replaced with `h2` and `http/1.1`, the protocols the server speaks.
+    pub fn from_rustls(mut config: ServerConfig) -> Self {
+        config.alpn_protocols = alpn_protocols();
+        TlsConfig { acceptor: TlsAcceptor::from(Arc::new(config)), certs: None, client_verifier: None }
+    }
+
+    /// Asks clients for a certificate signed by one of the PEM encoded CA certificates in
+    /// `ca_certs`, for authenticating them by it. Handlers find the verified certificate in the
+    /// request's extensions as a [`PeerCertificate`].
+    /// Fails if `ca_certs` holds no valid certificate, or if the config was made with
+    /// [`TlsConfig::from_rustls`], whose client authentication is set on the rustls config instead.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Request, Response};
+    /// use zep::tls::PeerCertificate;
+    ///
+    /// async fn whoami(req: Request) -> Response {
+    ///     match req.extensions.get::<PeerCertificate>() {
+    ///         Some(cert) => Response::ok(cert.subject.clone()),
+    ///         None => Response::ok("anonymous"),
+    ///     }
+    /// }
+    /// ```
+    pub fn client_auth(self, ca_certs: &[u8], auth: ClientAuth) -> std::io::Result<Self> {
+        let Some(certs) = self.certs else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Client authentication can't be added to a rustls config"));
+        };
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(ca_certs) {
+            let cert = cert.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid CA certificate: {}", e)))?;
+            roots.add(cert).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        }
+        if roots.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "No CA certificate"));
+        }
+        let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(ring::default_provider()));
+        if auth == ClientAuth::Optional {
+            verifier = verifier.allow_unauthenticated();
+        }
+        let verifier = verifier.build().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(TlsConfig::build(certs, Some(verifier)))
+    }
+
+    fn build(certs: Arc<Certs>, client_verifier: Option<Arc<dyn ClientCertVerifier>>) -> Self {
+        let builder = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions");
+        let builder = match &client_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier.clone()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(certs.clone());
+        config.alpn_protocols = alpn_protocols();
+        TlsConfig { acceptor: TlsAcceptor::from(Arc::new(config)), certs: Some(certs), client_verifier }
+    }
+
+    /// Runs the server side of the handshake on a freshly accepted connection.
+    pub(crate) async fn accept(&self, socket: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.acceptor.accept(socket).await
+    }
+
+    /// Returns the certificate the client of `stream` authenticated with, if it presented one.
+    pub(crate) fn peer_certificate(stream: &TlsStream<TcpStream>) -> Option<PeerCertificate> {
+        let cert = stream.get_ref().1.peer_certificates()?.first()?;
+        PeerCertificate::from_der(cert)
+    }
+
+    /// Returns the QUIC version of this config, offering only HTTP/3, see [`Server::http3`](crate::Server::http3).
+    #[cfg(feature = "http3")]
+    pub(crate) fn quic_config(&self) -> std::io::Result<quinn::ServerConfig> {
+        let mut config = ServerConfig::clone(self.acceptor.config());
+        config.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(config)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+    }
+}
+
+/// Returns the chain and key of a certificate, see [`TlsConfig::from_pem`].
+fn certified_key(cert_chain: &[u8], private_key: &[u8]) -> std::io::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_slice_iter(cert_chain)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid certificate chain: {}", e)))?;
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "No certificate in chain"));
+    }
+    let key = PrivateKeyDer::from_pem_slice(private_key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid private key: {}", e)))?;
+    CertifiedKey::from_der(certs, key, &ring::default_provider()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Certificates of a [`TlsConfig`], picked by the name the client asks for.
+#[derive(Debug, Clone)]
+struct Certs {
+    default: Arc<CertifiedKey>,
+    /// By lowercase host name, or `*.` and the domain for wildcards.
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Certs {
+    fn find(&self, name: &str) -> &Arc<CertifiedKey> {
+        let name = name.to_ascii_lowercase();
+        let wildcard = name.split_once('.').map(|(_, domain)| format!("*.{}", domain));
+        self.hosts
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.hosts.get(&wildcard)))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        match client_hello.server_name() {
+            Some(name) => Some(self.find(name).clone()),
+            None => Some(self.default.clone()),
+        }
+    }
+}
+
+/// The certificate a client authenticated with, see [`TlsConfig::client_auth`].
+/// The server adds it to the extensions of requests on connections whose client presented one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerCertificate {
+    /// The subject's distinguished name in RFC 4514 form, most specific attribute first,
+    /// such as `CN=alice,O=Example`.
+    pub subject: String,
+    /// The names in the subject alternative name extension that have a text form.
+    pub alt_names: Vec<AltName>,
+    /// The whole certificate, DER encoded.
+    pub der: Vec<u8>,
+}
+
+/// A subject alternative name of a [`PeerCertificate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+impl PeerCertificate {
+    /// Returns the subject and alternative names of a DER encoded X.509 certificate,
+    /// or `None` if it can't be parsed. The certificate isn't verified.
+    pub fn from_der(der: &[u8]) -> Option<PeerCertificate> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let alt_names = match cert.subject_alternative_name().ok()? {
+            Some(extension) => extension.value.general_names.iter().filter_map(alt_name).collect(),
+            None => Vec::new(),
+        };
+        Some(PeerCertificate { subject: distinguished_name(cert.subject())?, alt_names, der: der.to_vec() })
+    }
+}
+
+/// Returns the text form of a subject alternative name, `None` for the kinds without one.
+fn alt_name(name: &GeneralName<'_>) -> Option<AltName> {
+    match *name {
+        GeneralName::RFC822Name(email) => Some(AltName::Email(email.to_string())),
+        GeneralName::DNSName(dns) => Some(AltName::Dns(dns.to_string())),
+        GeneralName::URI(uri) => Some(AltName::Uri(uri.to_string())),
+        GeneralName::IPAddress(ip) => match ip.len() {
+            4 => Some(AltName::Ip(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?))),
+            16 => Some(AltName::Ip(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Short names of the attributes RFC 4514 names, by their object identifier.
+const ATTRIBUTE_NAMES: &[(&str, &str)] = &[
+    ("2.5.4.3", "CN"),
+    ("2.5.4.6", "C"),
+    ("2.5.4.7", "L"),
+    ("2.5.4.8", "ST"),
+    ("2.5.4.9", "STREET"),
+    ("2.5.4.10", "O"),
+    ("2.5.4.11", "OU"),
+    ("0.9.2342.19200300.100.1.1", "UID"),
+    ("0.9.2342.19200300.100.1.25", "DC"),
+];
+
+/// Formats an X.509 Name as RFC 4514 does, last relative name first.
+fn distinguished_name(name: &X509Name<'_>) -> Option<String> {
+    let mut relative_names = Vec::new();
+    for relative_name in name.iter_rdn() {
+        let mut attributes = Vec::new();
+        for attribute in relative_name.iter() {
+            let oid = attribute.attr_type().to_id_string();
+            let key = match ATTRIBUTE_NAMES.iter().find(|(known, _)| *known == oid) {
+                Some((_, short)) => short.to_string(),
+                None => oid,
+            };
+            let value = attribute.attr_value();
+            let value = match value.tag() {
+                Tag::Utf8String | Tag::PrintableString | Tag::TeletexString | Tag::Ia5String => {
+                    escape_dn_value(std::str::from_utf8(value.data).ok()?)
+                }
+                Tag::BmpString => escape_dn_value(&String::from_utf16(
+                    &value.data.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect::<Vec<_>>(),
+                ).ok()?),
+                _ => format!("#{}", value.to_der_vec().ok()?.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            };
+            attributes.push(format!("{}={}", key, value));
+        }
+        relative_names.push(attributes.join("+"));
+    }
+    relative_names.reverse();
+    Some(relative_names.join(","))
+}
+
+/// Escapes an attribute value for a distinguished name string, see RFC 4514 section 2.4.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.char_indices() {
+        let edge = (i == 0 && (c == ' ' || c == '#')) || (i + c.len_utf8() == value.len() && c == ' ');
+        if edge || matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\') {
+            escaped.push('\\');
+        }
+        if c == '\0' {
+            escaped.push_str("\\00");
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
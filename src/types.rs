@@ -1,7 +1,21 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use crate::server::{StreamReader, StreamWriter};
+use std::time::Instant;
+use crate::server::{Framing, StreamReader, StreamWriter};
+use crate::body::Body;
+use crate::cookie::{self, Cookie};
+use crate::extensions::Extensions;
+#[cfg(feature = "decompression")]
+use crate::inflate;
+use crate::upgrade::{OnUpgrade, Upgraded};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
 
 /// Type alias of `HashMap<String, String>` for convenience.
 pub type HeaderMap = HashMap<String, String>;
@@ -12,9 +26,12 @@ pub type ParamMap = HashMap<Arc<str>, String>;
 #[derive(Debug, Clone, PartialEq)]
 pub enum Method {
     GET,
+    HEAD,
     POST,
     PUT,
     DELETE,
+    CONNECT,
+    OPTIONS,
     Other(String),
 }
 
@@ -22,9 +39,12 @@ impl From<&str> for Method {
     fn from(s: &str) -> Self {
         match s {
             "GET" => Method::GET,
+            "HEAD" => Method::HEAD,
             "POST" => Method::POST,
             "PUT" => Method::PUT,
             "DELETE" => Method::DELETE,
+            "CONNECT" => Method::CONNECT,
+            "OPTIONS" => Method::OPTIONS,
             s => Method::Other(s.to_string()),
         }
     }
@@ -34,10 +54,13 @@ impl fmt::Display for Method {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Method::GET => write!(f, "GET"),
+            Method::HEAD => write!(f, "HEAD"),
             Method::POST => write!(f, "POST"),
             Method::PUT => write!(f, "PUT"),
             Method::DELETE => write!(f, "DELETE"),
-            Method::Other(_) => write!(f, "OTHER"),
+            Method::CONNECT => write!(f, "CONNECT"),
+            Method::OPTIONS => write!(f, "OPTIONS"),
+            Method::Other(s) => write!(f, "{}", s),
         }
     }
 }
@@ -47,9 +70,12 @@ impl Method {
     fn _to_str(&self) -> &str {
         match self {
             Method::GET => "GET",
+            Method::HEAD => "HEAD",
             Method::POST => "POST",
             Method::PUT => "PUT",
             Method::DELETE => "DELETE",
+            Method::CONNECT => "CONNECT",
+            Method::OPTIONS => "OPTIONS",
             Method::Other(_) => "OTHER",
         }
     }
@@ -103,16 +129,42 @@ impl Version {
 }
 
 /// Enum to conveniently handle status codes.
-#[derive(Debug, Clone, PartialEq)]
+/// Codes without their own variant are written as `Custom`, which is sent with the reason phrase
+/// registered for the code, see [`StatusCode::canonical_reason`]. Statuses compare by their code,
+/// so `Custom(404)` equals `NotFound`.
+#[derive(Debug, Clone)]
 pub enum StatusCode {
+    SwitchingProtocols,
     Ok,
+    PartialContent,
+    NotModified,
     NotFound,
+    RequestTimeout,
     InternalServerError,
     BadRequest,
+    Unauthorized,
     Forbidden,
+    PreconditionFailed,
+    PayloadTooLarge,
+    URITooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    MisdirectedRequest,
+    UnprocessableEntity,
+    PreconditionRequired,
+    RequestHeaderFieldsTooLarge,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
     Custom(u16),
 }
 
+impl PartialEq for StatusCode {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_u16() == other.as_u16()
+    }
+}
+
 impl fmt::Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let code = self.as_u16();
@@ -121,41 +173,282 @@ impl fmt::Display for StatusCode {
     }
 }
 
+impl From<u16> for StatusCode {
+    fn from(code: u16) -> Self {
+        match code {
+            101 => StatusCode::SwitchingProtocols,
+            200 => StatusCode::Ok,
+            206 => StatusCode::PartialContent,
+            304 => StatusCode::NotModified,
+            400 => StatusCode::BadRequest,
+            401 => StatusCode::Unauthorized,
+            403 => StatusCode::Forbidden,
+            404 => StatusCode::NotFound,
+            408 => StatusCode::RequestTimeout,
+            412 => StatusCode::PreconditionFailed,
+            413 => StatusCode::PayloadTooLarge,
+            414 => StatusCode::URITooLong,
+            415 => StatusCode::UnsupportedMediaType,
+            416 => StatusCode::RangeNotSatisfiable,
+            421 => StatusCode::MisdirectedRequest,
+            422 => StatusCode::UnprocessableEntity,
+            428 => StatusCode::PreconditionRequired,
+            431 => StatusCode::RequestHeaderFieldsTooLarge,
+            500 => StatusCode::InternalServerError,
+            502 => StatusCode::BadGateway,
+            503 => StatusCode::ServiceUnavailable,
+            504 => StatusCode::GatewayTimeout,
+            c => StatusCode::Custom(c),
+        }
+    }
+}
+
 impl StatusCode {
-    fn as_u16(&self) -> u16 {
+    /// Returns the numeric status code.
+    pub fn as_u16(&self) -> u16 {
         match self {
+            StatusCode::SwitchingProtocols => 101,
             StatusCode::Ok => 200,
+            StatusCode::PartialContent => 206,
+            StatusCode::NotModified => 304,
             StatusCode::NotFound => 404,
+            StatusCode::RequestTimeout => 408,
             StatusCode::InternalServerError => 500,
             StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
             StatusCode::Forbidden => 403,
+            StatusCode::PreconditionFailed => 412,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::URITooLong => 414,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::MisdirectedRequest => 421,
+            StatusCode::UnprocessableEntity => 422,
+            StatusCode::PreconditionRequired => 428,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
             StatusCode::Custom(c) => *c,
         }
     }
 
     fn reason(&self) -> &'static str {
         match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
             StatusCode::Ok => "OK",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::NotModified => "Not Modified",
             StatusCode::NotFound => "Not Found",
+            StatusCode::RequestTimeout => "Request Timeout",
             StatusCode::InternalServerError => "Internal Server Error",
             StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
             StatusCode::Forbidden => "Forbidden",
-            StatusCode::Custom(_) => "Custom Code",
+            StatusCode::PreconditionFailed => "Precondition Failed",
+            StatusCode::PayloadTooLarge => "Content Too Large",
+            StatusCode::URITooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::MisdirectedRequest => "Misdirected Request",
+            StatusCode::UnprocessableEntity => "Unprocessable Content",
+            StatusCode::PreconditionRequired => "Precondition Required",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::Custom(c) => StatusCode::canonical_reason(*c).unwrap_or(""),
         }
     }
+
+    /// Returns the reason phrase registered with IANA for `code`, e.g. "I'm a teapot" for 418.
+    /// Codes without a registered phrase are sent with an empty reason phrase.
+    pub fn canonical_reason(code: u16) -> Option<&'static str> {
+        Some(match code {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            102 => "Processing",
+            103 => "Early Hints",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            203 => "Non-Authoritative Information",
+            204 => "No Content",
+            205 => "Reset Content",
+            206 => "Partial Content",
+            207 => "Multi-Status",
+            208 => "Already Reported",
+            226 => "IM Used",
+            300 => "Multiple Choices",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            305 => "Use Proxy",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            402 => "Payment Required",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            407 => "Proxy Authentication Required",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            417 => "Expectation Failed",
+            418 => "I'm a teapot",
+            421 => "Misdirected Request",
+            422 => "Unprocessable Content",
+            423 => "Locked",
+            424 => "Failed Dependency",
+            425 => "Too Early",
+            426 => "Upgrade Required",
+            428 => "Precondition Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            451 => "Unavailable For Legal Reasons",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            506 => "Variant Also Negotiates",
+            507 => "Insufficient Storage",
+            508 => "Loop Detected",
+            510 => "Not Extended",
+            511 => "Network Authentication Required",
+            _ => return None,
+        })
+    }
 }
 
 /// Deserialized HTTP request in the form of a struct for easy handling in code.
-/// Contains request method, path, version, headers, body, remote_addr(ip address of client), and parameters.
+/// Contains request method, path, version, headers, body, remote_addr(socket address of client), and parameters.
 pub struct Request {
     pub method: Method,
     pub path: String,
     pub version: Version,
     pub headers: HeaderMap,
-    pub body: Option<Vec<u8>>,
-    pub remote_addr: String,
+    pub body: Body,
+    pub remote_addr: SocketAddr,
     pub params: ParamMap,
     pub stream: Option<StreamReader>,
+    pub extensions: Extensions,
+    /// Sequence number the server gave this request, unique per [`Server`](crate::Server) and
+    /// starting at 1. The server's own error logs name it, so they can be matched with the
+    /// handler's. Requests that didn't come from a server have 0.
+    pub id: u64,
+    /// When the server started receiving the request, as soon as its request line arrived.
+    /// Requests that didn't come from a server have the time they were created.
+    pub received: Instant,
+}
+
+/// Everything about a request but its body, see [`Request::into_parts`] and
+/// [`proto::parse_head`](crate::proto::parse_head).
+/// Cloning copies everything but the extensions, like [`Request::clone_parts`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RequestHead {
+    pub method: Method,
+    pub path: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub remote_addr: SocketAddr,
+    pub params: ParamMap,
+    pub extensions: Extensions,
+    /// See [`Request.id`](Request::id).
+    pub id: u64,
+    /// See [`Request.received`](Request::received).
+    pub received: Instant,
+    /// The value of the `Content-Length` header, if there is one.
+    pub content_length: Option<usize>,
+    /// Whether the body uses chunked transfer encoding. Requests with both it and a Content-Length are refused.
+    pub chunked: bool,
+    /// Number of bytes the head took up in the input of `parse_head`, the body starts right after.
+    /// 0 for heads taken from a Request.
+    pub len: usize,
+}
+
+impl Clone for RequestHead {
+    fn clone(&self) -> Self {
+        RequestHead {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            remote_addr: self.remote_addr,
+            params: self.params.clone(),
+            extensions: Extensions::new(),
+            id: self.id,
+            received: self.received,
+            content_length: self.content_length,
+            chunked: self.chunked,
+            len: self.len,
+        }
+    }
+}
+
+impl Default for RequestHead {
+    fn default() -> Self {
+        Request::default().into_parts().0
+    }
+}
+
+/// Status and headers of a response, see [`Response::into_parts`].
+/// Cloning leaves out the upgrade set with [`Response::upgrade`], it can only run once.
+pub struct ResponseHead {
+    pub status_code: StatusCode,
+    pub headers: HeaderMap,
+    pub(crate) upgrade: Option<OnUpgrade>,
+}
+
+impl ResponseHead {
+    /// Returns a head with the given status and no headers.
+    pub fn new(status_code: StatusCode) -> Self {
+        ResponseHead { status_code, headers: HeaderMap::new(), upgrade: None }
+    }
+}
+
+impl std::fmt::Debug for ResponseHead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseHead")
+            .field("status", &self.status_code)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+impl Clone for ResponseHead {
+    fn clone(&self) -> Self {
+        ResponseHead { status_code: self.status_code.clone(), headers: self.headers.clone(), upgrade: None }
+    }
+}
+
+/// Body of a response, see [`Response::into_parts`].
+pub enum ResponseBody {
+    Empty,
+    Bytes(Vec<u8>),
+    Stream(StreamWriter),
+}
+
+impl std::fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseBody::Empty => f.write_str("Empty"),
+            ResponseBody::Bytes(body) => f.debug_tuple("Bytes").field(body).finish(),
+            ResponseBody::Stream(_) => f.write_str("Stream"),
+        }
+    }
 }
 
 /// Deserialized HTTP response in the form of a struct for easy handling in code.
@@ -165,6 +458,7 @@ pub struct Response {
     pub headers: Option<HeaderMap>,
     pub body: Option<Vec<u8>>,
     pub stream: Option<StreamWriter>,
+    pub(crate) upgrade: Option<OnUpgrade>,
 }
 
 impl PartialEq for Response {
@@ -178,6 +472,276 @@ impl PartialEq for Response {
     }
 }
 
+impl std::fmt::Debug for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("version", &self.version)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("remote_addr", &self.remote_addr)
+            .field("params", &self.params)
+            .field("extensions", &self.extensions)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl Request {
+    /// Returns the value of the cookie `name` sent in the `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| cookie::parse_cookies(v))
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the connection options listed in the `Connection` headers, such as `close` or `upgrade`.
+    pub fn connection_options(&self) -> Vec<&str> {
+        self.header_list("connection")
+    }
+
+    /// Returns the protocols the client asks to switch to, such as `websocket` or `h2c`, in order
+    /// of preference. Empty unless `Connection` lists `upgrade` too, as RFC 9110 section 7.8
+    /// requires, since an `Upgrade` header without it may have been left by an intermediary.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Request, Response, StatusCode};
+    ///
+    /// async fn socket(req: Request) -> Response {
+    ///     if !req.upgrade_protocols().iter().any(|p| p.eq_ignore_ascii_case("websocket")) {
+    ///         return Response::new(StatusCode::Custom(426)).header("Upgrade", "websocket");
+    ///     }
+    ///     Response::ok("upgrading")
+    /// }
+    /// ```
+    pub fn upgrade_protocols(&self) -> Vec<&str> {
+        if !self.connection_options().iter().any(|o| o.eq_ignore_ascii_case("upgrade")) {
+            return Vec::new();
+        }
+        self.header_list("upgrade")
+    }
+
+    /// Returns true if the client sent `Expect: 100-continue` with an HTTP/1.1 request and waits
+    /// for `100 Continue` before sending the body. The server sends it on its own once the handler
+    /// starts reading the body.
+    pub fn expects_continue(&self) -> bool {
+        self.version == Version::Http11
+            && self.headers.iter().any(|(k, v)| k.eq_ignore_ascii_case("expect") && v.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Returns the comma separated elements of the headers named `name`.
+    fn header_list(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .collect()
+    }
+
+    /// Returns a copy of the request without the live stream and extensions.
+    /// The body is only copied if it's already in memory, a body still waiting on the
+    /// connection is left out like the stream.
+    /// Useful for middleware that wants to log or enqueue a request while still passing it on.
+    pub fn clone_parts(&self) -> Request {
+        Request {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone_buffered(),
+            remote_addr: self.remote_addr,
+            params: self.params.clone(),
+            stream: None,
+            extensions: Extensions::new(),
+            id: self.id,
+            received: self.received,
+        }
+    }
+
+    /// Splits the request into its head and its body, a chunked `stream` becomes the body.
+    /// The head can be inspected, cloned or changed on its own, and put back together with
+    /// the body by [`Request::from_parts`].
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Request, Response, Handler};
+    ///
+    /// async fn log_requests(req: Request, next: Handler) -> Response {
+    ///     let (head, body) = req.into_parts();
+    ///     println!("{} {} from {}", head.method, head.path, head.remote_addr);
+    ///     next(Request::from_parts(head, body)).await
+    /// }
+    /// ```
+    pub fn into_parts(self) -> (RequestHead, Body) {
+        let content_length = self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| crate::server::parse_content_length(v));
+        let chunked = self.stream.is_some();
+        let body = match self.stream {
+            Some(stream) => Body::chunked(stream),
+            None => self.body,
+        };
+        let head = RequestHead {
+            method: self.method,
+            path: self.path,
+            version: self.version,
+            headers: self.headers,
+            remote_addr: self.remote_addr,
+            params: self.params,
+            extensions: self.extensions,
+            id: self.id,
+            received: self.received,
+            content_length,
+            chunked,
+            len: 0,
+        };
+        (head, body)
+    }
+
+    /// Builds a request from a head and a body, the reverse of [`Request::into_parts`].
+    /// A chunked body goes back to `Request.stream`.
+    pub fn from_parts(head: RequestHead, body: Body) -> Request {
+        let (body, stream) = match body.into_stream() {
+            Ok(stream) => (Body::empty(), Some(stream)),
+            Err(body) => (body, None),
+        };
+        Request {
+            method: head.method,
+            path: head.path,
+            version: head.version,
+            headers: head.headers,
+            body,
+            remote_addr: head.remote_addr,
+            params: head.params,
+            stream,
+            extensions: head.extensions,
+            id: head.id,
+            received: head.received,
+        }
+    }
+
+    /// Reads the whole body into memory, whether it was sent with a Content-Length or chunked.
+    /// Returns an `InvalidData` error if the body is larger than `limit` bytes.
+    /// The body is taken out of the request, so it can only be read once.
+    pub async fn bytes(&mut self, limit: usize) -> std::io::Result<Vec<u8>> {
+        match self.stream.take() {
+            Some(stream) => stream.collect(limit).await,
+            None => std::mem::take(&mut self.body).bytes(limit).await,
+        }
+    }
+
+    /// Reads the whole body and undoes its `Content-Encoding`, see [`Request::bytes`].
+    /// Supports `gzip`, `x-gzip`, `deflate` and `identity`, including several codings applied in sequence.
+    /// `limit` applies to both the encoded and the decoded size, so compression bombs are refused.
+    /// Returns an `Unsupported` error for any other coding, which handlers can answer with 415.
+    /// Enabled with the `decompression` feature.
+    #[cfg(feature = "decompression")]
+    pub async fn decoded_body(&mut self, limit: usize) -> std::io::Result<Vec<u8>> {
+        let codings: Vec<String> = self
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-encoding"))
+            .map(|(_, v)| v.split(',').map(|c| c.trim().to_ascii_lowercase()).collect())
+            .unwrap_or_default();
+
+        let mut body = self.bytes(limit).await?;
+        for coding in codings.iter().rev() {
+            body = match coding.as_str() {
+                "gzip" | "x-gzip" => inflate::gunzip(&body, limit)?,
+                "deflate" => inflate::zlib_decompress(&body, limit)?,
+                "identity" | "" => body,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!("Unsupported Content-Encoding: {}", coding),
+                    ));
+                }
+            };
+        }
+        Ok(body)
+    }
+
+    /// Reads the whole body as text, see [`Request::bytes`].
+    /// Decodes according to the `charset` parameter of `Content-Type`: ISO-8859-1 (latin-1) and
+    /// windows-1252 are supported, everything else is decoded as UTF-8 with invalid sequences
+    /// replaced by U+FFFD.
+    pub async fn text(&mut self, limit: usize) -> std::io::Result<String> {
+        let charset = self
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+            .and_then(|(_, v)| charset(v))
+            .map(|c| c.to_ascii_lowercase());
+        let body = self.bytes(limit).await?;
+        Ok(match charset.as_deref() {
+            Some("iso-8859-1" | "latin1" | "latin-1" | "l1" | "iso_8859-1") => {
+                body.iter().map(|&b| b as char).collect()
+            }
+            Some("windows-1252" | "cp1252") => body.iter().map(|&b| windows_1252(b)).collect(),
+            _ => match String::from_utf8(body) {
+                Ok(text) => text,
+                Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            },
+        })
+    }
+
+    /// Reads the whole body and deserializes it from JSON into any `T` implementing serde's
+    /// `Deserialize`, [`serde_json::Value`] included, see [`Request::bytes`]. Bodies that aren't
+    /// valid JSON for `T` fail with `InvalidData`. Enabled with the `json` feature.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{serde_json::Value, Request, Response};
+    ///
+    /// async fn create(mut req: Request) -> Response {
+    ///     match req.json::<Value>(64 * 1024).await {
+    ///         Ok(value) => match value.get("name").and_then(|v| v.as_str()) {
+    ///             Some(name) => Response::ok(format!("created {}", name)),
+    ///             None => Response::bad_request(),
+    ///         },
+    ///         Err(_) => Response::bad_request(),
+    ///     }
+    /// }
+    /// ```
+    #[cfg(feature = "json")]
+    pub async fn json<T: serde::de::DeserializeOwned>(&mut self, limit: usize) -> std::io::Result<T> {
+        let body = self.bytes(limit).await?;
+        serde_json::from_slice(&body).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Returns the charset parameter of a Content-Type value, without quotes.
+fn charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+/// Decodes a windows-1252 byte, which only differs from latin-1 in the 0x80..=0x9F range.
+fn windows_1252(b: u8) -> char {
+    const HIGH: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+        '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+    ];
+    match b {
+        0x80..=0x9f => HIGH[(b - 0x80) as usize],
+        _ => b as char,
+    }
+}
+
 impl std::fmt::Debug for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Response")
@@ -205,6 +769,7 @@ impl Response {
                 None
             },
             stream: None,
+            upgrade: None,
         }
     }*/
 
@@ -215,6 +780,7 @@ impl Response {
             headers: None,
             body: None,
             stream: None,
+            upgrade: None,
         }
     }
 
@@ -234,6 +800,7 @@ impl Response {
             headers: None,
             body: Some(body.into()),
             stream: None,
+            upgrade: None,
         }
     }
 
@@ -244,6 +811,18 @@ impl Response {
             headers: None,
             body: Some("404 Not Found".into()),
             stream: None,
+            upgrade: None,
+        }
+    }
+
+    /// Helper function to return a 400 Bad Request response.
+    pub fn bad_request() -> Self {
+        Response {
+            status_code: StatusCode::BadRequest,
+            headers: None,
+            body: Some("400 Bad Request".into()),
+            stream: None,
+            upgrade: None,
         }
     }
 
@@ -254,6 +833,7 @@ impl Response {
             headers: None,
             body: None,
             stream: None,
+            upgrade: None,
         }
     }
 
@@ -272,6 +852,28 @@ impl Response {
         self
     }
 
+    /// Adds a header without replacing one of the same name, for headers that may appear
+    /// several times such as `Set-Cookie`. The values are kept newline separated in the
+    /// headermap and written as separate header lines.
+    pub fn append_header(mut self, key: &str, value: &str) -> Self {
+        let headers = self.headers.get_or_insert_with(HeaderMap::new);
+        match headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, existing)) => {
+                existing.push('\n');
+                existing.push_str(value);
+            }
+            None => {
+                headers.insert(key.to_string(), value.to_string());
+            }
+        }
+        self
+    }
+
+    /// Adds a `Set-Cookie` header for `cookie`, see [`Cookie`](crate::cookie::Cookie).
+    pub fn cookie(self, cookie: Cookie) -> Self {
+        self.append_header("Set-Cookie", &cookie.to_string())
+    }
+
     /// Adds given headermap to a Response.
     pub fn headermap(mut self, headers: HeaderMap) -> Self {
         self.headers = Some(headers);
@@ -289,9 +891,191 @@ impl Response {
             },
             body: None,
             stream: Some(stream),
+            upgrade: None,
         }
         
     }
+
+    /// Returns a response that streams exactly `len` bytes from the given StreamWriter with a
+    /// `Content-Length` header instead of chunked encoding.
+    /// Required by HTTP/1.0 clients and some download managers, and avoids chunk framing for
+    /// content of known size. If the stream ends before `len` bytes, the connection is closed.
+    pub fn stream_with_len(status_code: StatusCode, mut stream: StreamWriter, len: u64) -> Self {
+        stream.set_framing(Framing::Length(len));
+        Response {
+            status_code,
+            headers: {
+                let mut headermap = HeaderMap::new();
+                headermap.insert("Content-Length".to_string(), len.to_string());
+                Some(headermap)
+            },
+            body: None,
+            stream: Some(stream),
+            upgrade: None,
+        }
+    }
+
+    /// Limits how fast this response's stream is sent, in bytes per second, for example to give
+    /// a download route less bandwidth than the rest of the server. The lower of this and
+    /// [`Server::bandwidth_limit`](crate::Server::bandwidth_limit) applies.
+    /// Has no effect on responses that aren't streamed.
+    pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        if let Some(stream) = &mut self.stream {
+            stream.limit_rate(bytes_per_sec);
+        }
+        self
+    }
+
+    /// Sends trailer fields after the body of a chunked streamed response, for values only known
+    /// once the body has been written, such as a checksum. `names` are advertised in the
+    /// `Trailer` header, the fields themselves are sent through `values` before the stream ends.
+    /// If the sender is dropped instead, the response ends without trailers.
+    /// Has no effect on responses that aren't chunked.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{tokio, HeaderMap, Request, Response, StatusCode, StreamWriter};
+    ///
+    /// async fn download(_req: Request) -> Response {
+    ///     let data = b"hello".to_vec();
+    ///     let (tx, rx) = tokio::sync::oneshot::channel();
+    ///     let checksum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    ///     let _ = tx.send(HeaderMap::from([("X-Checksum".to_string(), checksum.to_string())]));
+    ///
+    ///     Response::stream(StatusCode::Ok, StreamWriter::new(std::io::Cursor::new(data)))
+    ///         .trailers(&["X-Checksum"], rx)
+    /// }
+    /// ```
+    pub fn trailers(mut self, names: &[&str], values: oneshot::Receiver<HeaderMap>) -> Self {
+        match &mut self.stream {
+            Some(stream) if stream.is_chunked() => stream.set_trailers(values),
+            _ => return self,
+        }
+        self.header("Trailer", &names.join(", "))
+    }
+
+    /// Returns a 200 OK response streaming each value received from `values` as one line of
+    /// newline-delimited JSON (`application/x-ndjson`) over chunked encoding, ending when every
+    /// sender is dropped. A value is only taken from the channel once the previous one has been
+    /// written, so with a bounded channel producers wait for slow clients.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{serde_json::json, tokio, Request, Response};
+    ///
+    /// async fn export(_req: Request) -> Response {
+    ///     let (tx, rx) = tokio::sync::mpsc::channel(16);
+    ///     tokio::spawn(async move {
+    ///         for id in 0..1000 {
+    ///             let row = json!({"id": id});
+    ///             if tx.send(row).await.is_err() {
+    ///                 break;
+    ///             }
+    ///         }
+    ///     });
+    ///     Response::ndjson(rx)
+    /// }
+    /// ```
+    pub fn ndjson(values: Receiver<serde_json::Value>) -> Self {
+        Response::stream(StatusCode::Ok, StreamWriter::new(NdjsonReader::new(values)))
+            .header("Content-Type", "application/x-ndjson")
+    }
+
+    /// Takes over the connection once this response's head has been sent.
+    /// The callback receives the raw connection as an [`Upgraded`], and the connection is closed
+    /// when the callback's future completes. Typically used with a 101 Switching Protocols response
+    /// to implement WebSockets or other protocols tunneled over HTTP.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Request, Response, StatusCode};
+    /// use zep::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    ///
+    /// async fn echo(_req: Request) -> Response {
+    ///     Response::new(StatusCode::SwitchingProtocols)
+    ///         .header("Connection", "upgrade")
+    ///         .header("Upgrade", "echo")
+    ///         .upgrade(|mut io| async move {
+    ///             let mut buf = [0u8; 1024];
+    ///             while let Ok(n) = io.read(&mut buf).await {
+    ///                 if n == 0 || io.write_all(&buf[..n]).await.is_err() {
+    ///                     break;
+    ///                 }
+    ///             }
+    ///         })
+    /// }
+    /// ```
+    pub fn upgrade<F, Fut>(mut self, f: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(move |io| Box::pin(f(io))));
+        self
+    }
+
+    /// Splits the response into its head and its body, so proxies and middleware can rework
+    /// one without touching the other. A response with both a body and a stream keeps the stream.
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{Response, ResponseBody};
+    ///
+    /// let (mut head, body) = Response::ok("hi").into_parts();
+    /// head.headers.insert("X-Proxy".into(), "zep".into());
+    /// assert!(matches!(body, ResponseBody::Bytes(ref b) if b == b"hi"));
+    /// let resp = Response::from_parts(head, body);
+    /// ```
+    pub fn into_parts(self) -> (ResponseHead, ResponseBody) {
+        let head = ResponseHead {
+            status_code: self.status_code,
+            headers: self.headers.unwrap_or_default(),
+            upgrade: self.upgrade,
+        };
+        let body = match (self.stream, self.body) {
+            (Some(stream), _) => ResponseBody::Stream(stream),
+            (None, Some(body)) => ResponseBody::Bytes(body),
+            (None, None) => ResponseBody::Empty,
+        };
+        (head, body)
+    }
+
+    /// Builds a response from a head and a body, the reverse of [`Response::into_parts`].
+    pub fn from_parts(head: ResponseHead, body: ResponseBody) -> Response {
+        let (body, stream) = match body {
+            ResponseBody::Empty => (None, None),
+            ResponseBody::Bytes(body) => (Some(body), None),
+            ResponseBody::Stream(stream) => (None, Some(stream)),
+        };
+        Response {
+            status_code: head.status_code,
+            headers: if head.headers.is_empty() { None } else { Some(head.headers) },
+            body,
+            stream,
+            upgrade: head.upgrade,
+        }
+    }
+
+    /// Writes the response to `writer` as HTTP/1.1, including a streamed body, like the Server
+    /// would. See [`proto::serialize_response`](crate::proto::serialize_response).
+    ///
+    /// # Example:
+    /// ```
+    /// use zep::{tokio, Response};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut out = Vec::new();
+    ///     Response::ok("hi").write_to(&mut out).await.unwrap();
+    ///     assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+    /// }
+    /// ```
+    pub async fn write_to<W>(mut self, mut writer: W) -> std::io::Result<()>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        crate::proto::serialize_response(&mut self, &mut writer).await
+    }
 }
 
 impl Default for Request {
@@ -301,10 +1085,50 @@ impl Default for Request {
             path: "".into(),
             version: Version::Http10,
             headers: HeaderMap::new(),
-            body: None,
-            remote_addr: "".into(),
+            body: Body::empty(),
+            remote_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
             params: ParamMap::new(),
             stream: None,
+            extensions: Extensions::new(),
+            id: 0,
+            received: Instant::now(),
+        }
+    }
+}
+
+/// Reads values from a channel as newline-delimited JSON, ending when every sender is dropped.
+/// Backs [`Response::ndjson`](crate::Response::ndjson).
+pub(crate) struct NdjsonReader {
+    values: Receiver<serde_json::Value>,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl NdjsonReader {
+    pub(crate) fn new(values: Receiver<serde_json::Value>) -> Self {
+        NdjsonReader { values, line: Vec::new(), pos: 0 }
+    }
+}
+
+impl AsyncRead for NdjsonReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.pos == self.line.len() {
+            match self.values.poll_recv(cx) {
+                Poll::Ready(Some(value)) => {
+                    self.line = format!("{}\n", value).into_bytes();
+                    self.pos = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
         }
+        let n = buf.remaining().min(self.line.len() - self.pos);
+        buf.put_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(()))
     }
 }
@@ -0,0 +1,63 @@
+//! Connection upgrades, used to take over the raw connection after a response head was sent.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::server::{ConnReader, ConnWrite};
+
+/// Callback stored in a Response that takes over the connection once the response head is written.
+pub(crate) type OnUpgrade =
+    Box<dyn FnOnce(Upgraded) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// The raw connection handed to an upgrade callback.
+/// Implements `AsyncRead` and `AsyncWrite`, bytes the client sent right after the request head
+/// are returned first by reads.
+/// Use `tokio::io::split` or [`Upgraded::into_parts`] to get separate read and write halves.
+pub struct Upgraded {
+    read: ConnReader,
+    write: ConnWrite,
+}
+
+impl Upgraded {
+    pub(crate) fn new(read: ConnReader, write: ConnWrite) -> Self {
+        Upgraded { read, write }
+    }
+
+    /// Splits the connection into bytes already read from the client, and the read and write halves.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(
+        self,
+    ) -> (Vec<u8>, Box<dyn AsyncRead + Unpin + Send + Sync>, Box<dyn AsyncWrite + Unpin + Send + Sync>) {
+        let leftover = self.read.buffer().to_vec();
+        (leftover, self.read.into_inner(), self.write)
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,111 @@
+//! Experimental io_uring backend for Linux, see [`Server::run_io_uring`](crate::Server::run_io_uring).
+//! Sockets are accepted, read and written through io_uring by tokio-uring. The connection handling
+//! needs `Send` streams, which tokio-uring's aren't, so each connection is bridged to it over an
+//! in-memory pipe. The router, handlers and request and response types work unchanged, at the cost
+//! of a copy each way.
+
+use crate::server::{Server, accept_backoff};
+use std::future::Future;
+use std::net::Shutdown;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinSet;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+/// Bytes buffered in the pipe between a socket and its connection handler, each way.
+const PIPE_BUFFER: usize = 64 * 1024;
+/// Most bytes moved by a single io_uring read or write.
+const IO_BUFFER: usize = 16 * 1024;
+
+/// Accepts and handles connections until `shutdown` completes, then waits for open connections to
+/// finish. Must run on the tokio-uring runtime.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    server: &Server,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), crate::Error> {
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    let mut result = Ok(());
+    // Dropping an accept in flight doesn't withdraw it from the ring, and the connection it takes
+    // would be lost, so the same one is polled until it completes.
+    let mut accept = Box::pin(listener.accept());
+
+    loop {
+        tokio::select! {
+            accepted = &mut accept => {
+                accept = Box::pin(listener.accept());
+                let (stream, remote_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => match accept_backoff(&e) {
+                        Some(backoff) => {
+                            eprintln!("error, accept failed, retrying in {:?}: {:?}", backoff, e);
+                            tokio::select! {
+                                _ = tokio::time::sleep(backoff) => continue,
+                                _ = &mut shutdown => break,
+                            }
+                        }
+                        None => {
+                            result = Err(crate::Error::Accept(e));
+                            break;
+                        }
+                    },
+                };
+                let (client, conn) = tokio::io::duplex(PIPE_BUFFER);
+                let served = server.serve_connection(conn, remote_addr);
+                // io_uring operations aren't Send, so the bridge stays on this thread.
+                connections.spawn_local(async move {
+                    let (served, ()) = tokio::join!(served, bridge(stream, client));
+                    if let Err(e) = served {
+                        eprintln!("error, conn: {}, err: {:?}", remote_addr, e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(accept);
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    result
+}
+
+/// Copies bytes between `stream` and the connection handler's end of the pipe, `client`, until
+/// the handler is done with the connection. Once the peer stops sending, the rest of the response
+/// is still written.
+async fn bridge(stream: TcpStream, client: DuplexStream) {
+    let (mut from_handler, mut to_handler) = tokio::io::split(client);
+    let inbound = async {
+        let mut buf = Vec::with_capacity(IO_BUFFER);
+        loop {
+            buf.clear();
+            let (read, returned) = stream.read(buf).await;
+            buf = returned;
+            if !matches!(read, Ok(n) if n > 0) || to_handler.write_all(&buf).await.is_err() {
+                break;
+            }
+        }
+        let _ = to_handler.shutdown().await;
+    };
+    let outbound = async {
+        let mut buf = Vec::with_capacity(IO_BUFFER);
+        loop {
+            buf.clear();
+            if !matches!(from_handler.read_buf(&mut buf).await, Ok(n) if n > 0) {
+                break;
+            }
+            let (written, returned) = stream.write_all(buf).await;
+            buf = returned;
+            if written.is_err() {
+                break;
+            }
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+    };
+    tokio::pin!(inbound, outbound);
+    tokio::select! {
+        _ = &mut outbound => {}
+        _ = &mut inbound => outbound.await,
+    }
+}
@@ -0,0 +1,102 @@
+//! Reporting invalid input field by field, as 422 Unprocessable Content.
+
+use crate::{Response, StatusCode};
+use std::fmt;
+
+/// Field-level messages about invalid input, answered with a 422 Unprocessable Content response
+/// whose JSON body has the usual `error` and `status` plus the messages of each field:
+/// `{"error":"Unprocessable Content","status":422,"fields":{"email":["is required"]}}`.
+///
+/// # Example:
+/// ```
+/// use zep::{serde_json::{self, Value}, Request, Response, ValidationError};
+///
+/// async fn create_user(mut req: Request) -> Response {
+///     let Ok(Ok(body)) = req.bytes(64 * 1024).await.map(|body| serde_json::from_slice::<Value>(&body)) else {
+///         return Response::bad_request();
+///     };
+///     let name = body.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+///     let age = body.get("age").and_then(|v| v.as_f64());
+///
+///     let mut errors = ValidationError::new();
+///     errors.check(!name.is_empty(), "name", "is required");
+///     errors.check(age.is_some_and(|age| age >= 18.0), "age", "must be at least 18");
+///     if let Err(errors) = errors.into_result() {
+///         return errors.into();
+///     }
+///     Response::ok(format!("created {}", name))
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationError {
+    fields: Vec<(String, Vec<String>)>,
+}
+
+impl ValidationError {
+    /// Returns an empty ValidationError.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `message` to the messages of `field`.
+    pub fn add(&mut self, field: &str, message: &str) {
+        match self.fields.iter_mut().find(|(name, _)| name == field) {
+            Some((_, messages)) => messages.push(message.to_string()),
+            None => self.fields.push((field.to_string(), vec![message.to_string()])),
+        }
+    }
+
+    /// Adds `message` to the messages of `field` unless `valid` is true.
+    pub fn check(&mut self, valid: bool, field: &str, message: &str) {
+        if !valid {
+            self.add(field, message);
+        }
+    }
+
+    /// Returns true if no messages were added.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the messages of `field`.
+    pub fn messages(&self, field: &str) -> &[String] {
+        self.fields.iter().find(|(name, _)| name == field).map_or(&[], |(_, messages)| messages)
+    }
+
+    /// Returns `Ok` if no messages were added, `Err(self)` otherwise.
+    pub fn into_result(self) -> Result<(), ValidationError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        Err(self)
+    }
+}
+
+impl From<ValidationError> for Response {
+    fn from(errors: ValidationError) -> Self {
+        let fields: serde_json::Map<String, serde_json::Value> =
+            errors.fields.into_iter().map(|(field, messages)| (field, messages.into())).collect();
+        let body = serde_json::json!({
+            "error": "Unprocessable Content",
+            "status": 422,
+            "fields": fields,
+        });
+        let mut resp = Response::new(StatusCode::UnprocessableEntity).header("Content-Type", "application/json");
+        resp.body(body.to_string());
+        resp
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (field, messages)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{} {}", field, messages.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}